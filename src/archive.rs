@@ -0,0 +1,534 @@
+use std::{
+    cmp::min,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use flate2::write::DeflateDecoder;
+
+pub const ZIP_MAGIC: [u8; 4] = [b'P', b'K', 0x03, 0x04];
+
+const LOCAL_HEADER: u32 = 0x04034b50;
+const DATA_DESCRIPTOR: u32 = 0x08074b50;
+const CENTRAL_HEADER: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+const ZIP64_LOCATOR: u32 = 0x07064b50;
+const ZIP64_EXTRA: u16 = 0x0001;
+
+const LOCAL_HEADER_SIZE: usize = 30;
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+const HAS_DATA_DESCRIPTOR: u16 = 1 << 3;
+
+/// Whether a member looks like a disk image.
+pub fn is_image(name: &str) -> bool {
+    let name = name.to_lowercase();
+
+    [".img", ".iso", ".raw"]
+        .iter()
+        .any(|ext| name.ends_with(ext))
+}
+
+/// Streams one disk image out of a zip archive into `W` without extracting it
+/// to disk. Without a member name the archive has to hold exactly one image.
+pub struct ZipExtractor<W: Write> {
+    member: Option<String>,
+    header: Vec<u8>,
+    entry: Entry,
+    output: DeflateDecoder<W>,
+    extracted: bool,
+}
+
+enum Entry {
+    Header,
+    Data {
+        remaining: Option<u64>,
+        method: u16,
+        target: Target,
+        descriptor: Option<bool>,
+    },
+    Descriptor {
+        zip64: bool,
+    },
+    End,
+}
+
+enum Target {
+    Skip(Option<DeflateDecoder<io::Sink>>),
+    Output,
+}
+
+impl<W: Write> ZipExtractor<W> {
+    pub fn new(inner: W, member: Option<String>) -> Self {
+        Self {
+            member,
+            header: Vec::with_capacity(LOCAL_HEADER_SIZE),
+            entry: Entry::Header,
+            output: DeflateDecoder::new(inner),
+            extracted: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.output.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        self.output.get_mut()
+    }
+
+    /// Returns the inner writer. Fails if the archive ended early or held no
+    /// matching image.
+    pub fn finish(self) -> io::Result<W> {
+        if !matches!(self.entry, Entry::Header | Entry::End) {
+            return Err(invalid("zip archive is truncated"));
+        }
+
+        if !self.extracted {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                match self.member {
+                    Some(m) => format!("{m} is not in the zip archive"),
+                    None => "the zip archive holds no disk image".into(),
+                },
+            ));
+        }
+
+        self.output.finish()
+    }
+
+    /// Collects `data` into the header buffer until it holds `len` bytes.
+    /// Returns the unused rest of `data`, or `None` if more is needed.
+    fn fill<'a>(&mut self, data: &'a [u8], len: usize) -> Option<&'a [u8]> {
+        let take = min(len.saturating_sub(self.header.len()), data.len());
+        self.header.extend_from_slice(&data[..take]);
+
+        match self.header.len() >= len {
+            true => Some(&data[take..]),
+            false => None,
+        }
+    }
+
+    fn header<'a>(&mut self, data: &'a [u8]) -> io::Result<&'a [u8]> {
+        let rest = match self.fill(data, 4) {
+            Some(rest) => rest,
+            None => return Ok(&[]),
+        };
+
+        match u32_at(&self.header, 0) {
+            LOCAL_HEADER => {}
+            CENTRAL_HEADER | END_OF_CENTRAL_DIRECTORY => {
+                self.entry = Entry::End;
+                return Ok(&[]);
+            }
+            _ => return Err(invalid("not a zip archive")),
+        }
+
+        let rest = match self.fill(rest, LOCAL_HEADER_SIZE) {
+            Some(rest) => rest,
+            None => return Ok(&[]),
+        };
+
+        let name_len = u16_at(&self.header, 26) as usize;
+        let extra_len = u16_at(&self.header, 28) as usize;
+        let rest = match self.fill(rest, LOCAL_HEADER_SIZE + name_len + extra_len) {
+            Some(rest) => rest,
+            None => return Ok(&[]),
+        };
+
+        let flags = u16_at(&self.header, 6);
+        let method = u16_at(&self.header, 8);
+        let name = &self.header[LOCAL_HEADER_SIZE..LOCAL_HEADER_SIZE + name_len];
+        let name = String::from_utf8_lossy(name).into_owned();
+        let extra = &self.header[LOCAL_HEADER_SIZE + name_len..];
+
+        let zip64 = zip64_extra(extra);
+        let mut size = u32_at(&self.header, 18) as u64;
+        if size == u32::MAX as u64 {
+            // The zip64 extra field holds the uncompressed size first, then
+            // the compressed one.
+            size = zip64.map(|e| u64_at(e, 8)).unwrap_or(0);
+        }
+        let zip64 = zip64.is_some();
+
+        let descriptor = flags & HAS_DATA_DESCRIPTOR != 0;
+        let remaining = match descriptor && size == 0 {
+            true => None,
+            false => Some(size),
+        };
+
+        let wanted = match &self.member {
+            Some(member) => name == *member,
+            None => is_image(&name),
+        };
+
+        if wanted && self.extracted && self.member.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the zip archive holds several disk images, name the member to use",
+            ));
+        }
+
+        let target = match wanted && !self.extracted {
+            true => {
+                if method != STORED && method != DEFLATED {
+                    return Err(unsupported("compression method of the zip member"));
+                }
+                self.extracted = true;
+                Target::Output
+            }
+            false => Target::Skip(None),
+        };
+
+        if remaining.is_none() && method != DEFLATED {
+            return Err(unsupported("zip member without a recorded size"));
+        }
+
+        let target = match (target, remaining) {
+            (Target::Skip(_), None) => Target::Skip(Some(DeflateDecoder::new(io::sink()))),
+            (target, _) => target,
+        };
+
+        self.header.clear();
+        self.entry = Entry::Data {
+            remaining,
+            method,
+            target,
+            descriptor: descriptor.then_some(zip64),
+        };
+
+        Ok(rest)
+    }
+
+    fn data<'a>(&mut self, data: &'a [u8]) -> io::Result<&'a [u8]> {
+        let (remaining, method, target, descriptor) = match &mut self.entry {
+            Entry::Data {
+                remaining,
+                method,
+                target,
+                descriptor,
+            } => (remaining, *method, target, *descriptor),
+            _ => unreachable!(),
+        };
+
+        let (rest, done) = match remaining {
+            Some(n) => {
+                let take = min(*n, data.len() as u64) as usize;
+
+                if let Target::Output = target {
+                    match method {
+                        DEFLATED => self.output.write_all(&data[..take])?,
+                        _ => self.output.get_mut().write_all(&data[..take])?,
+                    }
+                }
+
+                *n -= take as u64;
+                (&data[take..], *n == 0)
+            }
+            // Without a recorded size the end of the deflate stream marks the
+            // end of the member.
+            None => {
+                let used = match target {
+                    Target::Output => self.output.write(data)?,
+                    Target::Skip(Some(d)) => d.write(data)?,
+                    Target::Skip(None) => unreachable!(),
+                };
+
+                (&data[used..], used == 0)
+            }
+        };
+
+        if done {
+            if matches!(target, Target::Output) && method == DEFLATED {
+                self.output.try_finish()?;
+            }
+
+            self.entry = match descriptor {
+                Some(zip64) => Entry::Descriptor { zip64 },
+                None => Entry::Header,
+            };
+        }
+
+        Ok(rest)
+    }
+
+    fn descriptor<'a>(&mut self, data: &'a [u8], zip64: bool) -> io::Result<&'a [u8]> {
+        let rest = match self.fill(data, 4) {
+            Some(rest) => rest,
+            None => return Ok(&[]),
+        };
+
+        // CRC-32 and both sizes, optionally preceded by a signature.
+        let mut len = if zip64 { 20 } else { 12 };
+        if u32_at(&self.header, 0) == DATA_DESCRIPTOR {
+            len += 4;
+        }
+
+        let rest = match self.fill(rest, len) {
+            Some(rest) => rest,
+            None => return Ok(&[]),
+        };
+
+        self.header.clear();
+        self.entry = Entry::Header;
+
+        Ok(rest)
+    }
+}
+
+impl<W: Write> Write for ZipExtractor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut data = buf;
+
+        while !data.is_empty() {
+            data = match self.entry {
+                Entry::Header => self.header(data)?,
+                Entry::Data { .. } => self.data(data)?,
+                Entry::Descriptor { zip64 } => self.descriptor(data, zip64)?,
+                Entry::End => &[],
+            };
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Lists the disk images in a seekable zip archive from its central
+/// directory.
+pub fn images<R: Read + Seek>(reader: &mut R) -> io::Result<Vec<String>> {
+    let end = reader.seek(SeekFrom::End(0))?;
+
+    // The end of central directory record is followed by at most 64 KiB of
+    // comment.
+    let tail_len = min(end, 22 + u16::MAX as u64);
+    let mut tail = vec![0; tail_len as usize];
+    reader.seek(SeekFrom::Start(end - tail_len))?;
+    reader.read_exact(&mut tail)?;
+
+    if tail.len() < 22 {
+        return Err(invalid("zip archive is truncated"));
+    }
+
+    let eocd = (0..=tail.len() - 22)
+        .rev()
+        .find(|&i| u32_at(&tail, i) == END_OF_CENTRAL_DIRECTORY)
+        .ok_or_else(|| invalid("zip archive has no central directory"))?;
+
+    let mut entries = u16_at(&tail, eocd + 10) as u64;
+    let mut offset = u32_at(&tail, eocd + 16) as u64;
+
+    if offset == u32::MAX as u64 && eocd >= 20 && u32_at(&tail, eocd - 20) == ZIP64_LOCATOR {
+        let record = u64_at(&tail, eocd - 20 + 8);
+        let mut zip64 = [0; 56];
+        reader.seek(SeekFrom::Start(record))?;
+        reader.read_exact(&mut zip64)?;
+
+        entries = u64_at(&zip64, 32);
+        offset = u64_at(&zip64, 48);
+    }
+
+    reader.seek(SeekFrom::Start(offset))?;
+
+    let mut images = vec![];
+    for _ in 0..entries {
+        let mut header = [0; 46];
+        reader.read_exact(&mut header)?;
+
+        if u32_at(&header, 0) != CENTRAL_HEADER {
+            return Err(invalid("corrupt zip central directory"));
+        }
+
+        let name_len = u16_at(&header, 28) as usize;
+        let skip = u16_at(&header, 30) as i64 + u16_at(&header, 32) as i64;
+
+        let mut name = vec![0; name_len];
+        reader.read_exact(&mut name)?;
+        reader.seek(SeekFrom::Current(skip))?;
+
+        let name = String::from_utf8_lossy(&name).into_owned();
+        if is_image(&name) {
+            images.push(name);
+        }
+    }
+
+    Ok(images)
+}
+
+fn zip64_extra(mut extra: &[u8]) -> Option<&[u8]> {
+    while extra.len() >= 4 {
+        let id = u16_at(extra, 0);
+        let len = u16_at(extra, 2) as usize;
+        let field = extra.get(4..4 + len)?;
+
+        if id == ZIP64_EXTRA && field.len() >= 16 {
+            return Some(field);
+        }
+        extra = &extra[4 + len..];
+    }
+
+    None
+}
+
+fn u16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, format!("unsupported {what}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use super::*;
+
+    const ZIP64_RECORD: u32 = 0x06064b50;
+
+    /// Builds a zip archive of stored `members`, with zip64 sizes and
+    /// records if `zip64`.
+    fn zip(members: &[(&str, &[u8])], zip64: bool) -> Vec<u8> {
+        let mut out = vec![];
+        let mut central = vec![];
+
+        for (name, data) in members {
+            let offset = out.len() as u32;
+            let size = match zip64 {
+                true => u32::MAX,
+                false => data.len() as u32,
+            };
+            let mut extra = vec![];
+            if zip64 {
+                extra.extend(ZIP64_EXTRA.to_le_bytes());
+                extra.extend(16u16.to_le_bytes());
+                extra.extend((data.len() as u64).to_le_bytes());
+                extra.extend((data.len() as u64).to_le_bytes());
+            }
+
+            out.extend(LOCAL_HEADER.to_le_bytes());
+            out.extend([20, 0, 0, 0]);
+            out.extend(STORED.to_le_bytes());
+            out.extend([0; 8]);
+            out.extend(size.to_le_bytes());
+            out.extend(size.to_le_bytes());
+            out.extend((name.len() as u16).to_le_bytes());
+            out.extend((extra.len() as u16).to_le_bytes());
+            out.extend(name.as_bytes());
+            out.extend(&extra);
+            out.extend(*data);
+
+            central.extend(CENTRAL_HEADER.to_le_bytes());
+            central.extend([20, 0, 20, 0, 0, 0]);
+            central.extend(STORED.to_le_bytes());
+            central.extend([0; 8]);
+            central.extend(size.to_le_bytes());
+            central.extend(size.to_le_bytes());
+            central.extend((name.len() as u16).to_le_bytes());
+            central.extend([0; 12]);
+            central.extend(offset.to_le_bytes());
+            central.extend(name.as_bytes());
+        }
+
+        let offset = out.len() as u64;
+        let entries = members.len() as u64;
+        out.extend(&central);
+
+        if zip64 {
+            let record = out.len() as u64;
+            out.extend(ZIP64_RECORD.to_le_bytes());
+            out.extend(44u64.to_le_bytes());
+            out.extend([45, 0, 45, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            out.extend(entries.to_le_bytes());
+            out.extend(entries.to_le_bytes());
+            out.extend((central.len() as u64).to_le_bytes());
+            out.extend(offset.to_le_bytes());
+
+            out.extend(ZIP64_LOCATOR.to_le_bytes());
+            out.extend([0; 4]);
+            out.extend(record.to_le_bytes());
+            out.extend(1u32.to_le_bytes());
+        }
+
+        out.extend(END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        out.extend([0; 4]);
+        let (entries, size, offset) = match zip64 {
+            true => (u16::MAX, u32::MAX, u32::MAX),
+            false => (entries as u16, central.len() as u32, offset as u32),
+        };
+        out.extend(entries.to_le_bytes());
+        out.extend(entries.to_le_bytes());
+        out.extend(size.to_le_bytes());
+        out.extend(offset.to_le_bytes());
+        out.extend([0; 2]);
+
+        out
+    }
+
+    /// Streams `archive` through a `ZipExtractor` in small pieces.
+    fn extract(archive: &[u8], member: Option<&str>) -> io::Result<Vec<u8>> {
+        let mut extractor = ZipExtractor::new(vec![], member.map(str::to_string));
+        for piece in archive.chunks(7) {
+            extractor.write_all(piece)?;
+        }
+        extractor.finish()
+    }
+
+    fn images_of(archive: &[u8]) -> io::Result<Vec<String>> {
+        images(&mut Cursor::new(archive))
+    }
+
+    #[test]
+    fn reads_a_single_image() {
+        for zip64 in [false, true] {
+            let archive = zip(&[("README", b"read me"), ("disk.img", b"the image")], zip64);
+
+            assert_eq!(images_of(&archive).unwrap(), ["disk.img"]);
+            assert_eq!(extract(&archive, None).unwrap(), b"the image");
+        }
+    }
+
+    #[test]
+    fn needs_a_name_for_one_of_several_images() {
+        for zip64 in [false, true] {
+            let archive = zip(&[("a.img", b"first"), ("b.ISO", b"second")], zip64);
+
+            assert_eq!(images_of(&archive).unwrap(), ["a.img", "b.ISO"]);
+            assert!(extract(&archive, None).is_err());
+            assert_eq!(extract(&archive, Some("b.ISO")).unwrap(), b"second");
+            assert!(extract(&archive, Some("c.img")).is_err());
+        }
+    }
+
+    #[test]
+    fn refuses_truncated_archives() {
+        let archive = zip(&[("disk.img", b"the image")], false);
+        let mut eocd = END_OF_CENTRAL_DIRECTORY.to_le_bytes().to_vec();
+        eocd.extend([0; 6]);
+
+        for short in [
+            &b""[..],
+            b"PK",
+            b"PK\x05",
+            &eocd,
+            &archive[..archive.len() - 1],
+        ] {
+            assert!(images_of(short).is_err());
+        }
+        assert!(extract(&archive[..40], None).is_err());
+    }
+}
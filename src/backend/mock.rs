@@ -0,0 +1,388 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use dbus_udisks2::{Block, DiskDevice, Drive};
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::{is_removable, Backend, Error, Mount, Partition};
+
+/// How much of a device formatting it as "empty" wipes.
+const WIPE: u64 = 1 << 20;
+
+/// Devices kept in memory, each backed by a file, to try out the device
+/// handling without sticks or UDisks. They are keyed by the path of their
+/// drive, as with UDisks. Only built for tests or with the "mock" feature.
+#[derive(Debug, Default)]
+pub struct Mock {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// The plugged in devices and the files holding what is on them.
+    devices: HashMap<String, (DiskDevice, PathBuf)>,
+    /// The files attached as loop devices, by block path.
+    loops: HashMap<String, PathBuf>,
+    /// The number of loop devices set up so far.
+    loop_count: usize,
+    mounts: Vec<Mount>,
+    /// Why devices hold the running system, by block path.
+    protected: HashMap<String, String>,
+    watchers: Vec<UnboundedSender<()>>,
+    /// Block paths of the devices ejected so far.
+    ejected: Vec<String>,
+    /// How much counterfeit devices really hold, by block path.
+    capacities: HashMap<String, u64>,
+    /// What calls fail with, by the name of the method or "*" for all.
+    failures: HashMap<String, Error>,
+}
+
+impl Mock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Plugs in `dev`, with what is on it kept in the file at `backing`.
+    pub fn plug(&self, dev: DiskDevice, backing: impl Into<PathBuf>) {
+        let mut state = self.lock();
+        state
+            .devices
+            .insert(dev.drive.path.clone(), (dev, backing.into()));
+        state.notify();
+    }
+
+    /// Pulls the device with the drive path `id`, which unmounts it too.
+    pub fn unplug(&self, id: &str) {
+        let mut state = self.lock();
+        if let Some((dev, _)) = state.devices.remove(id) {
+            state.mounts.retain(|m| !belongs(&m.path, &dev));
+            state.notify();
+        }
+    }
+
+    /// Mounts a filesystem of a device. `mount.path` is the block path of the
+    /// device or of one of its partitions.
+    pub fn mount(&self, mount: Mount) {
+        self.lock().mounts.push(mount);
+    }
+
+    /// Has `dev` hold the running system for `reason`.
+    pub fn protect(&self, dev: &DiskDevice, reason: &str) {
+        self.lock()
+            .protected
+            .insert(dev.parent.path.clone(), reason.into());
+    }
+
+    /// Makes `dev` a counterfeit stick that only holds `capacity` bytes. It
+    /// takes writes past that, but they read back as zeros once it is opened
+    /// again.
+    pub fn fake(&self, dev: &DiskDevice, capacity: u64) {
+        self.lock()
+            .capacities
+            .insert(dev.parent.path.clone(), capacity);
+    }
+
+    /// Makes the calls of the `Backend` method `call`, or of all of them with
+    /// "*", fail with `message`, or work again with `None`.
+    pub fn fail(&self, call: &str, message: Option<&str>) {
+        let mut state = self.lock();
+        match message {
+            Some(message) => state.failures.insert(call.into(), Error::new(message)),
+            None => state.failures.remove(call),
+        };
+    }
+
+    /// The block paths of the devices ejected so far.
+    pub fn ejected(&self) -> Vec<String> {
+        self.lock().ejected.clone()
+    }
+
+    /// A thread that panicked leaves the state usable.
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The state, unless `call` is to fail.
+    fn state(&self, call: &str) -> Result<MutexGuard<'_, State>, Error> {
+        let state = self.lock();
+        match state.failures.get(call).or(state.failures.get("*")) {
+            Some(e) => Err(e.clone()),
+            None => Ok(state),
+        }
+    }
+}
+
+impl State {
+    fn notify(&mut self) {
+        self.watchers.retain(|w| w.unbounded_send(()).is_ok());
+    }
+
+    /// The file holding what is on `dev`.
+    fn backing(&self, dev: &DiskDevice) -> Result<PathBuf, Error> {
+        self.devices
+            .values()
+            .find(|(d, _)| d.parent.path == dev.parent.path)
+            .map(|(_, path)| path)
+            .or_else(|| self.loops.get(&dev.parent.path))
+            .cloned()
+            .ok_or_else(|| Error::new(format!("{} is gone", dev.parent.device.display())))
+    }
+}
+
+impl Backend for Mock {
+    fn list_devices(&self) -> Result<HashMap<String, DiskDevice>, Error> {
+        Ok(self
+            .state("list_devices")?
+            .devices
+            .iter()
+            .filter(|(_, (dev, _))| is_removable(dev))
+            .map(|(id, (dev, _))| (id.clone(), dev.clone()))
+            .collect())
+    }
+
+    fn watch_devices(&self) -> Result<UnboundedReceiver<()>, Error> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.state("watch_devices")?.watchers.push(sender);
+
+        Ok(receiver)
+    }
+
+    fn is_unchanged(&self, dev: &DiskDevice) -> Result<bool, Error> {
+        Ok(self
+            .state("is_unchanged")?
+            .devices
+            .get(&dev.drive.path)
+            .is_some_and(|(now, _)| {
+                now.parent.path == dev.parent.path
+                    && now.parent.device == dev.parent.device
+                    && now.parent.size == dev.parent.size
+                    && now.drive.id == dev.drive.id
+                    && now.drive.serial == dev.drive.serial
+            }))
+    }
+
+    fn partitions(&self, dev: &DiskDevice) -> Result<Vec<Partition>, Error> {
+        let state = self.state("partitions")?;
+
+        let blocks: Vec<&Block> = match dev.partitions.is_empty() {
+            true => Some(&dev.parent)
+                .filter(|b| !b.id_type.is_empty())
+                .into_iter()
+                .collect(),
+            false => dev.partitions.iter().collect(),
+        };
+
+        Ok(blocks
+            .into_iter()
+            .map(|b| Partition {
+                device: b.device.clone(),
+                label: b.id_label.clone(),
+                kind: b.id_type.clone(),
+                size: b.size,
+                used: None,
+                mount_points: state
+                    .mounts
+                    .iter()
+                    .filter(|m| m.path == b.path)
+                    .flat_map(|m| m.mount_points.clone())
+                    .collect(),
+            })
+            .collect())
+    }
+
+    fn mounts(&self, dev: &DiskDevice) -> Result<Vec<Mount>, Error> {
+        Ok(self
+            .state("mounts")?
+            .mounts
+            .iter()
+            .filter(|m| belongs(&m.path, dev))
+            .cloned()
+            .collect())
+    }
+
+    fn protection(&self, dev: &DiskDevice) -> Result<Option<String>, Error> {
+        Ok(self
+            .state("protection")?
+            .protected
+            .get(&dev.parent.path)
+            .cloned())
+    }
+
+    fn open(&self, dev: &DiskDevice, writable: bool) -> Result<File, Error> {
+        let (backing, capacity) = {
+            let state = self.state("open")?;
+            let capacity = state.capacities.get(&dev.parent.path).copied();
+            (state.backing(dev)?, capacity)
+        };
+
+        if let Some(capacity) = capacity {
+            let file = OpenOptions::new().write(true).open(&backing)?;
+            let size = file.metadata()?.len();
+            if size > capacity {
+                file.set_len(capacity)?;
+                file.set_len(size)?;
+            }
+        }
+
+        Ok(OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .open(backing)?)
+    }
+
+    fn unmount(&self, mount: &Mount) -> Result<(), Error> {
+        let mut state = self.state("unmount")?;
+
+        let mounted = state.mounts.len();
+        state.mounts.retain(|m| m.path != mount.path);
+        match state.mounts.len() < mounted {
+            true => Ok(()),
+            false => Err(Error::new(format!(
+                "{} is not mounted",
+                mount.device.display()
+            ))),
+        }
+    }
+
+    fn eject(&self, dev: &DiskDevice) -> Result<bool, Error> {
+        for mount in self.mounts(dev)? {
+            self.unmount(&mount)?;
+        }
+
+        let mut state = self.state("eject")?;
+        let removable = dev.drive.ejectable || dev.drive.can_power_off;
+        if removable {
+            state.ejected.push(dev.parent.path.clone());
+            state.devices.remove(&dev.drive.path);
+            state.notify();
+        }
+
+        Ok(removable)
+    }
+
+    fn rescan(&self, dev: &DiskDevice) -> Result<(), Error> {
+        self.state("rescan")?.backing(dev).map(|_| ())
+    }
+
+    fn format(&self, dev: &DiskDevice, kind: &str) -> Result<(), Error> {
+        if kind != "empty" {
+            return Err(Error::new(format!("{kind} filesystems are not supported")));
+        }
+
+        let backing = self.state("format")?.backing(dev)?;
+        let mut file = OpenOptions::new().write(true).open(backing)?;
+        let wiped = file.metadata()?.len().min(WIPE);
+        file.write_all(&vec![0; wiped as usize])?;
+
+        Ok(())
+    }
+
+    fn loop_setup(&self, path: &Path) -> Result<DiskDevice, Error> {
+        let mut state = self.state("loop_setup")?;
+
+        let parent = Block {
+            path: format!("/mock/loop{}", state.loop_count),
+            device: path.to_path_buf(),
+            size: fs::metadata(path)?.len(),
+            ..Default::default()
+        };
+        state.loop_count += 1;
+        state.loops.insert(parent.path.clone(), path.to_path_buf());
+
+        Ok(DiskDevice {
+            drive: Drive::default(),
+            parent,
+            partitions: vec![],
+        })
+    }
+
+    fn loop_delete(&self, dev: &DiskDevice) -> Result<(), Error> {
+        match self.state("loop_delete")?.loops.remove(&dev.parent.path) {
+            Some(_) => Ok(()),
+            None => Err(Error::new(format!("{} is not set up", dev.parent.path))),
+        }
+    }
+}
+
+/// Whether the block at `path` is `dev` itself or one of its partitions.
+fn belongs(path: &str, dev: &DiskDevice) -> bool {
+    path == dev.parent.path || dev.partitions.iter().any(|p| p.path == path)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{FutureExt, StreamExt};
+    use tempfile::TempDir;
+
+    use super::Mock;
+    use crate::backend::Backend;
+    use crate::testing::stick;
+
+    #[test]
+    fn lists_only_removable_devices() {
+        let dir = TempDir::new().unwrap();
+        let mock = Mock::new();
+
+        let (usb, backing) = stick(dir.path(), "sdb", 1 << 20);
+        mock.plug(usb.clone(), backing);
+
+        let (mut sata, backing) = stick(dir.path(), "sda", 1 << 20);
+        sata.drive.connection_bus = "sata".into();
+        mock.plug(sata, backing);
+
+        // A card reader without a card in it.
+        let (mut empty, backing) = stick(dir.path(), "sdc", 0);
+        empty.parent.size = 0;
+        mock.plug(empty, backing);
+
+        let (mut card, backing) = stick(dir.path(), "mmcblk0", 1 << 20);
+        card.drive.connection_bus = "sdio".into();
+        mock.plug(card.clone(), backing);
+
+        let mut listed: Vec<String> = mock.list_devices().unwrap().into_keys().collect();
+        listed.sort();
+        assert_eq!(listed, [card.drive.path, usb.drive.path]);
+    }
+
+    #[test]
+    fn tells_watchers_about_hotplug() {
+        let dir = TempDir::new().unwrap();
+        let mock = Mock::new();
+        let mut watch = mock.watch_devices().unwrap();
+        assert_eq!(watch.next().now_or_never(), None);
+
+        let (dev, backing) = stick(dir.path(), "sdb", 1 << 20);
+        mock.plug(dev.clone(), backing);
+        assert_eq!(watch.next().now_or_never(), Some(Some(())));
+        assert_eq!(watch.next().now_or_never(), None);
+
+        mock.unplug(&dev.drive.path);
+        assert_eq!(watch.next().now_or_never(), Some(Some(())));
+        assert!(!mock.is_unchanged(&dev).unwrap());
+
+        // Watchers that went away are forgotten.
+        drop(watch);
+        mock.plug(dev, dir.path().join("sdb.img"));
+    }
+
+    #[test]
+    fn fails_calls_as_told() {
+        let mock = Mock::new();
+
+        mock.fail("list_devices", Some("UDisks is not running"));
+        let e = mock.list_devices().unwrap_err();
+        assert_eq!(e.to_string(), "UDisks is not running");
+        assert!(mock.watch_devices().is_ok());
+
+        mock.fail("list_devices", None);
+        mock.fail("*", Some("no bus"));
+        assert!(mock.list_devices().is_err());
+        assert!(mock.watch_devices().is_err());
+
+        mock.fail("*", None);
+        assert!(mock.list_devices().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,393 @@
+#[cfg(any(test, feature = "mock"))]
+mod mock;
+mod sysfs;
+#[cfg(target_os = "linux")]
+mod udisks;
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use dbus_udisks2::DiskDevice;
+use futures::channel::mpsc::UnboundedReceiver;
+
+use crate::transfer;
+
+#[cfg(any(test, feature = "mock"))]
+pub use mock::Mock;
+pub use sysfs::Sysfs;
+#[cfg(target_os = "linux")]
+pub use udisks::UDisks;
+
+/// Mount points of the running system. A device holding one of them is
+/// never written.
+const SYSTEM_PATHS: [&str; 7] = ["/", "/boot", "/boot/efi", "/efi", "/usr", "/var", "/home"];
+
+/// Finds the devices and does what writing to them takes besides the writing
+/// itself. Devices are identified by the `DiskDevice` they were listed as.
+pub trait Backend: fmt::Debug + Send + Sync {
+    /// The removable devices, keyed by an id that stays the same when a
+    /// device is plugged in again and tells identical devices apart.
+    fn list_devices(&self) -> Result<HashMap<String, DiskDevice>, Error>;
+
+    /// Yields whenever a device is added or removed, until the receiver is
+    /// dropped.
+    fn watch_devices(&self) -> Result<UnboundedReceiver<()>, Error>;
+
+    /// Looks `dev` up again and checks that it is still the device that was
+    /// listed, so a stick swapped since it was chosen is left alone.
+    fn is_unchanged(&self, dev: &DiskDevice) -> Result<bool, Error>;
+
+    /// What is on `dev` right now: its partitions, or the device itself if it
+    /// holds a filesystem directly.
+    fn partitions(&self, dev: &DiskDevice) -> Result<Vec<Partition>, Error>;
+
+    /// The filesystems mounted from `dev` or its partitions.
+    fn mounts(&self, dev: &DiskDevice) -> Result<Vec<Mount>, Error>;
+
+    /// Why `dev` must not be written, if it hosts the running system.
+    fn protection(&self, dev: &DiskDevice) -> Result<Option<String>, Error>;
+
+    /// Opens `dev` for reading, and for writing as well if `writable`.
+    fn open(&self, dev: &DiskDevice, writable: bool) -> Result<File, Error>;
+
+    fn unmount(&self, mount: &Mount) -> Result<(), Error>;
+
+    /// Unmounts what was mounted from a freshly written `dev`, then ejects
+    /// and powers it off as far as it supports either. Returns whether it is
+    /// safe to remove.
+    fn eject(&self, dev: &DiskDevice) -> Result<bool, Error>;
+
+    /// Has the partition table of `dev` read again, so the partitions of a
+    /// freshly written image show up.
+    fn rescan(&self, dev: &DiskDevice) -> Result<(), Error>;
+
+    /// Creates a filesystem of `kind`, such as "vfat", on the whole of `dev`.
+    /// "empty" wipes what is there instead.
+    fn format(&self, dev: &DiskDevice, kind: &str) -> Result<(), Error>;
+
+    /// Attaches the file at `path` as a loop device, which can then be used
+    /// like any other device.
+    fn loop_setup(&self, path: &Path) -> Result<DiskDevice, Error>;
+
+    /// Detaches a loop device set up by `loop_setup`.
+    fn loop_delete(&self, dev: &DiskDevice) -> Result<(), Error>;
+}
+
+/// UDisks if it is running, otherwise sysfs so the devices can still be
+/// written on systems without it.
+#[cfg(target_os = "linux")]
+pub fn detect() -> Arc<dyn Backend> {
+    match UDisks::is_available() {
+        true => Arc::new(UDisks),
+        false => Arc::new(Sysfs::default()),
+    }
+}
+
+/// Why the backend could not do something, e.g. because UDisks is not running
+/// or polkit denied it.
+#[derive(Debug, Clone)]
+pub struct Error {
+    message: String,
+}
+
+impl Error {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<dbus::Error> for Error {
+    fn from(e: dbus::Error) -> Self {
+        match e.message() {
+            Some(message) => Self::new(message),
+            None => Self::new(e.name().unwrap_or("D-Bus call failed")),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::new(e.to_string())
+    }
+}
+
+/// A mounted filesystem on a device.
+#[derive(Debug, Clone)]
+pub struct Mount {
+    /// The backend's path of the block device holding the filesystem.
+    pub path: String,
+    /// Its node, such as /dev/sdb1.
+    pub device: PathBuf,
+    pub mount_points: Vec<PathBuf>,
+}
+
+/// A partition, or the filesystem of a device without partitions, as it is
+/// before the device is written.
+#[derive(Debug, Clone)]
+pub struct Partition {
+    pub device: PathBuf,
+    pub label: String,
+    /// The filesystem or other content, such as "vfat" or "crypto_LUKS".
+    pub kind: String,
+    pub size: u64,
+    /// The space in use, if the filesystem is mounted.
+    pub used: Option<u64>,
+    pub mount_points: Vec<PathBuf>,
+}
+
+impl fmt::Display for Partition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.device.display())?;
+        if !self.label.is_empty() {
+            write!(f, " \"{}\"", self.label)?;
+        }
+        match self.kind.is_empty() {
+            true => write!(f, ", {}", transfer::bytes(self.size))?,
+            false => write!(f, ", {} {}", transfer::bytes(self.size), self.kind)?,
+        }
+        if let Some(used) = self.used {
+            write!(f, ", {} used", transfer::bytes(used))?;
+        }
+        if let Some(point) = self.mount_points.first() {
+            write!(f, ", mounted at {}", point.display())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether `dev` is a stick or card worth offering: attached by USB or SDIO
+/// and with a medium in it.
+pub fn is_removable(dev: &DiskDevice) -> bool {
+    (dev.drive.connection_bus == "usb" || dev.drive.connection_bus == "sdio")
+        && dev.parent.size != 0
+}
+
+/// Names a device for people: vendor, model, size, the end of the serial and
+/// the device node, e.g. "SanDisk Cruzer 14.3 GB …4F2A /dev/sdb".
+pub fn device_label(dev: &DiskDevice) -> String {
+    let mut label = match dev.drive.vendor.is_empty() {
+        true => dev.drive.model.to_string(),
+        false => format!("{} {}", dev.drive.vendor, dev.drive.model),
+    };
+
+    label = format!("{} {}", label.trim(), transfer::bytes(dev.parent.size));
+
+    let serial: Vec<char> = dev.drive.serial.trim().chars().collect();
+    if !serial.is_empty() {
+        let suffix: String = serial[serial.len().saturating_sub(4)..].iter().collect();
+        label = format!("{label} …{suffix}");
+    }
+
+    format!("{label} {}", dev.parent.device.display())
+}
+
+/// Why the blocks at `nodes`, a device and its partitions, must not be
+/// written: a system filesystem is mounted from one of them or from what is
+/// stacked on top of it, such as an unlocked encrypted partition and the LVM
+/// volumes in it, or one of them is in use as swap.
+fn system_protection(nodes: &[PathBuf]) -> Result<Option<String>, Error> {
+    let table = mount_table()?;
+    let stack = stacked(nodes, holders);
+
+    let describe = |node: &Path, base: &Path| match node == canonical(base) {
+        true => base.display().to_string(),
+        false => format!("{} on {}", node.display(), base.display()),
+    };
+
+    for (node, base) in &stack {
+        let system = table
+            .iter()
+            .filter(|(device, _)| device == node)
+            .find(|(_, point)| SYSTEM_PATHS.iter().any(|path| Path::new(path) == point));
+
+        if let Some((_, point)) = system {
+            return Ok(Some(format!(
+                "{} is mounted at {}",
+                describe(node, base),
+                point.display()
+            )));
+        }
+    }
+
+    let swaps = fs::read_to_string("/proc/swaps").unwrap_or_default();
+    for swap in swaps
+        .lines()
+        .skip(1)
+        .filter_map(|l| l.split_whitespace().next())
+    {
+        let swap = canonical(Path::new(&unescape(swap)));
+        if let Some((node, base)) = stack.iter().find(|(node, _)| *node == swap) {
+            return Ok(Some(format!("{} is in use as swap", describe(node, base))));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Every block of `nodes` and every block stacked on top of them, however
+/// deep, each with the block of `nodes` it stands on. `holders` gives the
+/// blocks directly on top of one.
+fn stacked(nodes: &[PathBuf], holders: impl Fn(&Path) -> Vec<PathBuf>) -> Vec<(PathBuf, &Path)> {
+    let mut stack: Vec<(PathBuf, &Path)> = nodes
+        .iter()
+        .map(|node| (canonical(node), node.as_path()))
+        .collect();
+
+    let mut i = 0;
+    while let Some((node, base)) = stack.get(i).cloned() {
+        for holder in holders(&node) {
+            if !stack.iter().any(|(n, _)| *n == holder) {
+                stack.push((holder, base));
+            }
+        }
+        i += 1;
+    }
+
+    stack
+}
+
+/// The blocks stacked directly on top of the block at `node`, such as device
+/// mapper or RAID devices.
+fn holders(node: &Path) -> Vec<PathBuf> {
+    let name = match node.file_name() {
+        Some(name) => name,
+        None => return vec![],
+    };
+
+    fs::read_dir(Path::new("/sys/class/block").join(name).join("holders"))
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| Path::new("/dev").join(entry.file_name()))
+        .collect()
+}
+
+/// The device of each mounted filesystem and where it is mounted.
+fn mount_table() -> Result<Vec<(PathBuf, PathBuf)>, Error> {
+    let mounts = fs::read_to_string("/proc/self/mounts")?;
+
+    Ok(mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let device = fields.next()?;
+            let point = fields.next()?;
+
+            device.starts_with('/').then(|| {
+                (
+                    canonical(Path::new(&unescape(device))),
+                    PathBuf::from(unescape(point)),
+                )
+            })
+        })
+        .collect())
+}
+
+/// Undoes the octal escapes of spaces and the like in /proc/self/mounts.
+fn unescape(field: &str) -> String {
+    let mut out = String::new();
+    let mut rest = field;
+
+    while let Some(i) = rest.find('\\') {
+        out.push_str(&rest[..i]);
+        let code = rest
+            .get(i + 1..i + 4)
+            .and_then(|c| u8::from_str_radix(c, 8).ok());
+        match code {
+            Some(code) => {
+                out.push(code as char);
+                rest = &rest[i + 4..];
+            }
+            None => {
+                out.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+
+    out
+}
+
+/// `path` with symlinks such as /dev/mapper/name resolved, or as it is if
+/// that fails.
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// The bytes in use on the filesystem mounted at `point`.
+fn used(point: &Path) -> Option<u64> {
+    let path = CString::new(point.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    match unsafe { libc::statvfs(path.as_ptr(), &mut stat) } {
+        0 => Some((stat.f_blocks - stat.f_bfree) as u64 * stat.f_frsize as u64),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{stacked, unescape};
+
+    #[test]
+    fn follows_holders_all_the_way_up() {
+        // An encrypted partition holding an LVM group with two volumes, one
+        // of them mirrored with another disk.
+        let holders = |node: &Path| -> Vec<PathBuf> {
+            let on: &[&str] = match node.to_str().unwrap() {
+                "/dev/sdx2" => &["/dev/dm-0"],
+                "/dev/dm-0" => &["/dev/dm-1", "/dev/dm-2"],
+                "/dev/dm-2" => &["/dev/md0"],
+                "/dev/sdy1" => &["/dev/md0"],
+                _ => &[],
+            };
+            on.iter().map(PathBuf::from).collect()
+        };
+
+        let nodes = [PathBuf::from("/dev/sdx1"), PathBuf::from("/dev/sdx2")];
+        let stack = stacked(&nodes, holders);
+        let found: Vec<(&str, &str)> = stack
+            .iter()
+            .map(|(node, base)| (node.to_str().unwrap(), base.to_str().unwrap()))
+            .collect();
+
+        assert_eq!(
+            found,
+            [
+                ("/dev/sdx1", "/dev/sdx1"),
+                ("/dev/sdx2", "/dev/sdx2"),
+                ("/dev/dm-0", "/dev/sdx2"),
+                ("/dev/dm-1", "/dev/sdx2"),
+                ("/dev/dm-2", "/dev/sdx2"),
+                ("/dev/md0", "/dev/sdx2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unescapes_mount_fields() {
+        assert_eq!(unescape(r"/media/a\040b"), "/media/a b");
+        assert_eq!(unescape(r"/media/tab\011end\134"), "/media/tab\tend\\");
+        assert_eq!(unescape(r"/plain"), "/plain");
+    }
+}
@@ -0,0 +1,584 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use dbus_udisks2::{Block, DiskDevice, Drive};
+use futures::channel::mpsc::{self, UnboundedReceiver};
+
+use super::{
+    canonical, is_removable, mount_table, system_protection, used, Backend, Error, Mount, Partition,
+};
+
+const SYS_BLOCK: &str = "sys/block";
+
+/// Stable names of the disks, kept up by udev where it runs.
+const BY_ID: &str = "dev/disk/by-id";
+
+/// How often the disks are looked at for devices plugged in or out.
+const POLL: Duration = Duration::from_secs(2);
+
+/// How much of a device formatting it as "empty" wipes.
+const WIPE: usize = 1 << 20;
+
+/// The SCSI peripheral type of CD and DVD drives.
+const SCSI_ROM: &str = "5";
+
+/// `BLKRRPART` from linux/fs.h, rereads the partition table.
+const BLKRRPART: u64 = 0x125f;
+
+/// Devices as the kernel lists them in sysfs, for systems without UDisks such
+/// as servers and containers. They are opened directly, which takes root or
+/// write permission on the device node, and there is no polkit to ask.
+#[derive(Debug, Clone)]
+pub struct Sysfs {
+    /// Where sysfs and /dev are found, "/" but in tests.
+    root: PathBuf,
+}
+
+impl Default for Sysfs {
+    fn default() -> Self {
+        Self { root: "/".into() }
+    }
+}
+
+impl Backend for Sysfs {
+    /// Devices are keyed by their name in /dev/disk/by-id, which is made of
+    /// the bus, model and serial, or by their sysfs path without udev.
+    fn list_devices(&self) -> Result<HashMap<String, DiskDevice>, Error> {
+        let mut map = HashMap::new();
+
+        for name in disks(&self.root)? {
+            if let Some(dev) = device(&self.root, &name).filter(offered) {
+                map.insert(dev.drive.path.clone(), dev);
+            }
+        }
+
+        Ok(map)
+    }
+
+    /// Without udev events the disks are looked at every few seconds, on a
+    /// thread of their own.
+    fn watch_devices(&self) -> Result<UnboundedReceiver<()>, Error> {
+        let (sender, receiver) = mpsc::unbounded();
+        let mut known = disks(&self.root)?;
+        let root = self.root.clone();
+
+        thread::spawn(move || {
+            while !sender.is_closed() {
+                thread::sleep(POLL);
+
+                let now = disks(&root).unwrap_or_default();
+                if now != known && sender.unbounded_send(()).is_err() {
+                    break;
+                }
+                known = now;
+            }
+        });
+
+        Ok(receiver)
+    }
+
+    /// The disk must still be there under the same name, size and serial.
+    fn is_unchanged(&self, dev: &DiskDevice) -> Result<bool, Error> {
+        let name = sys_name(dev);
+
+        Ok(device(&self.root, name).is_some_and(|now| {
+            now.drive.path == dev.drive.path
+                && now.parent.device == dev.parent.device
+                && now.parent.size == dev.parent.size
+                && now.drive.serial == dev.drive.serial
+        }))
+    }
+
+    /// The kind and label of filesystems are not known without udev.
+    fn partitions(&self, dev: &DiskDevice) -> Result<Vec<Partition>, Error> {
+        let table = mount_table()?;
+
+        // A disk without partitions shows up if a filesystem on it is mounted.
+        let mut blocks = blocks(dev);
+        if blocks.len() > 1 {
+            blocks.remove(0);
+        }
+
+        let mut partitions: Vec<Partition> = blocks
+            .into_iter()
+            .map(|b| {
+                let mount_points = mount_points(&table, &b.device);
+                Partition {
+                    used: mount_points.first().and_then(|point| used(point)),
+                    device: b.device,
+                    label: String::new(),
+                    kind: String::new(),
+                    size: b.size,
+                    mount_points,
+                }
+            })
+            .filter(|p| p.device != dev.parent.device || !p.mount_points.is_empty())
+            .collect();
+        partitions.sort_by(|a, b| a.device.cmp(&b.device));
+
+        Ok(partitions)
+    }
+
+    fn mounts(&self, dev: &DiskDevice) -> Result<Vec<Mount>, Error> {
+        let table = mount_table()?;
+
+        Ok(blocks(dev)
+            .into_iter()
+            .map(|b| Mount {
+                mount_points: mount_points(&table, &b.device),
+                path: b.path,
+                device: b.device,
+            })
+            .filter(|m| !m.mount_points.is_empty())
+            .collect())
+    }
+
+    fn protection(&self, dev: &DiskDevice) -> Result<Option<String>, Error> {
+        let nodes: Vec<PathBuf> = blocks(dev).into_iter().map(|b| b.device).collect();
+
+        system_protection(&nodes)
+    }
+
+    fn open(&self, dev: &DiskDevice, writable: bool) -> Result<File, Error> {
+        OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .custom_flags(if writable { libc::O_SYNC } else { 0 })
+            .open(&dev.parent.device)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::PermissionDenied => Error::new(format!(
+                    "{}: {e}, opening it takes root or {} permission on it",
+                    dev.parent.device.display(),
+                    if writable { "write" } else { "read" }
+                )),
+                _ => Error::new(format!("{}: {e}", dev.parent.device.display())),
+            })
+    }
+
+    fn unmount(&self, mount: &Mount) -> Result<(), Error> {
+        // The last mount is on top, so unmount in reverse.
+        for point in mount.mount_points.iter().rev() {
+            let path = CString::new(point.as_os_str().as_bytes())
+                .map_err(|e| Error::new(e.to_string()))?;
+
+            if unsafe { libc::umount2(path.as_ptr(), 0) } != 0 {
+                let e = io::Error::last_os_error();
+                return Err(Error::new(format!("{}: {e}", point.display())));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Has the kernel drop a USB disk, which makes it safe to pull. Cards in a
+    /// built-in reader stay.
+    fn eject(&self, dev: &DiskDevice) -> Result<bool, Error> {
+        for mount in self.mounts(dev)? {
+            self.unmount(&mount)?;
+        }
+
+        if !dev.drive.ejectable {
+            return Ok(false);
+        }
+
+        fs::write(Path::new(&dev.parent.path).join("device/delete"), "1")?;
+        Ok(true)
+    }
+
+    fn rescan(&self, dev: &DiskDevice) -> Result<(), Error> {
+        let file = File::open(&dev.parent.device)?;
+
+        match unsafe { libc::ioctl(file.as_raw_fd(), BLKRRPART as _) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error().into()),
+        }
+    }
+
+    fn format(&self, dev: &DiskDevice, kind: &str) -> Result<(), Error> {
+        if kind != "empty" {
+            return Err(Error::new(format!(
+                "creating {kind} filesystems takes UDisks"
+            )));
+        }
+
+        let mut file = self.open(dev, true)?;
+        let wiped = dev.parent.size.min(WIPE as u64) as usize;
+        file.write_all(&vec![0; wiped])?;
+        file.sync_all()?;
+
+        Ok(())
+    }
+
+    fn loop_setup(&self, _path: &Path) -> Result<DiskDevice, Error> {
+        Err(Error::new("loop devices take UDisks"))
+    }
+
+    fn loop_delete(&self, _dev: &DiskDevice) -> Result<(), Error> {
+        Err(Error::new("loop devices take UDisks"))
+    }
+}
+
+/// Whether to offer `dev`: the kernel marks it removable, or it is a stick
+/// or card reader that doesn't say so, as USB disks and readers often don't.
+fn offered(dev: &DiskDevice) -> bool {
+    (dev.drive.removable && dev.parent.size != 0) || is_removable(dev)
+}
+
+/// The names of the disks, such as "sdb", sorted.
+fn disks(root: &Path) -> Result<Vec<String>, Error> {
+    let mut names = vec![];
+    for entry in fs::read_dir(root.join(SYS_BLOCK))? {
+        names.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+    names.sort();
+
+    Ok(names)
+}
+
+/// Reads the disk `name` from sysfs. Virtual disks such as loop and device
+/// mapper devices have no hardware behind them and are left out, and so are
+/// optical drives, which the kernel marks removable but can't be written.
+fn device(root: &Path, name: &str) -> Option<DiskDevice> {
+    let sys = root.join(SYS_BLOCK).join(name);
+    let hardware = fs::canonicalize(sys.join("device")).ok()?;
+    if hardware.starts_with(root.join("sys/devices/virtual"))
+        || read(&sys.join("device/type")) == SCSI_ROM
+    {
+        return None;
+    }
+
+    let connection_bus = match hardware.to_string_lossy().contains("/usb") {
+        true => "usb",
+        false if name.starts_with("mmcblk") => "sdio",
+        false => "",
+    };
+    let removable = read(&sys.join("removable")) == "1";
+    let size = sectors(&sys);
+    let model = match read(&sys.join("device/model")).as_str() {
+        "" => read(&sys.join("device/name")),
+        model => model.to_string(),
+    };
+    let serial = hardware
+        .ancestors()
+        .take(8)
+        .map(|dir| read(&dir.join("serial")))
+        .find(|serial| !serial.is_empty())
+        .unwrap_or_default();
+    let node = Path::new("/dev").join(name);
+    let path = stable_name(root, name).unwrap_or_else(|| sys.display().to_string());
+
+    let drive = Drive {
+        id: path.rsplit('/').next().unwrap_or_default().to_string(),
+        path: path.clone(),
+        connection_bus: connection_bus.to_string(),
+        vendor: read(&sys.join("device/vendor")),
+        model,
+        serial,
+        removable,
+        media_removable: removable,
+        // Only SCSI disks, which USB ones are, can be deleted.
+        ejectable: connection_bus == "usb",
+        size,
+        ..Default::default()
+    };
+
+    let parent = Block {
+        path: sys.display().to_string(),
+        device: node.clone(),
+        preferred_device: node,
+        drive: path.clone(),
+        size,
+        read_only: read(&sys.join("ro")) == "1",
+        ..Default::default()
+    };
+
+    let mut dev = DiskDevice {
+        drive,
+        parent,
+        partitions: vec![],
+    };
+    dev.partitions = blocks(&dev).into_iter().skip(1).collect();
+
+    Some(dev)
+}
+
+/// The disk itself followed by its partitions, as they are right now.
+fn blocks(dev: &DiskDevice) -> Vec<Block> {
+    let sys = Path::new(&dev.parent.path);
+    let mut blocks = vec![dev.parent.clone()];
+
+    let mut names: Vec<String> = fs::read_dir(sys)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join("partition").exists())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+
+    for name in names {
+        let node = Path::new("/dev").join(&name);
+        blocks.push(Block {
+            path: sys.join(&name).display().to_string(),
+            device: node.clone(),
+            preferred_device: node,
+            drive: dev.drive.path.clone(),
+            size: sectors(&sys.join(&name)),
+            ..Default::default()
+        });
+    }
+
+    blocks
+}
+
+/// The name of the disk `name` in /dev/disk/by-id, other than its WWN which
+/// some readers give all their cards alike.
+fn stable_name(root: &Path, name: &str) -> Option<String> {
+    let node = root.join("dev").join(name);
+    let mut names: Vec<PathBuf> = fs::read_dir(root.join(BY_ID))
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|link| canonical(link) == node)
+        .filter(|link| !link.to_string_lossy().contains("/wwn-"))
+        .collect();
+    names.sort();
+
+    names.first().map(|name| name.display().to_string())
+}
+
+fn mount_points(table: &[(PathBuf, PathBuf)], node: &Path) -> Vec<PathBuf> {
+    table
+        .iter()
+        .filter(|(device, _)| device == node)
+        .map(|(_, point)| point.clone())
+        .collect()
+}
+
+/// The name of `dev` under /sys/block.
+fn sys_name(dev: &DiskDevice) -> &str {
+    dev.parent.path.rsplit('/').next().unwrap_or_default()
+}
+
+/// The size of the disk or partition at `sys` in bytes. sysfs counts 512
+/// byte sectors whatever the disk uses.
+fn sectors(sys: &Path) -> u64 {
+    read(&sys.join("size")).parse::<u64>().unwrap_or(0) * 512
+}
+
+/// The trimmed content of a sysfs attribute, empty if it can't be read.
+fn read(path: &Path) -> String {
+    fs::read_to_string(path)
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::path::{Path, PathBuf};
+
+    use tempfile::TempDir;
+
+    use super::Sysfs;
+    use crate::backend::Backend;
+
+    const USB: &str = "sys/devices/pci0000:00/0000:00:14.0/usb1/1-2";
+    const STICK: &str = "1-2:1.0/host6/target6:0:0/6:0:0:0";
+
+    fn write(root: &Path, path: &str, content: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    /// Adds the disk `name` of `sectors` with its hardware at `device` and
+    /// the given attributes, such as "removable".
+    fn disk(
+        root: &Path,
+        name: &str,
+        device: Option<&str>,
+        sectors: u64,
+        attributes: &[(&str, &str)],
+    ) {
+        write(
+            root,
+            &format!("sys/block/{name}/size"),
+            &format!("{sectors}\n"),
+        );
+        write(root, &format!("sys/block/{name}/ro"), "0\n");
+        for (attribute, value) in attributes {
+            write(
+                root,
+                &format!("sys/block/{name}/{attribute}"),
+                &format!("{value}\n"),
+            );
+        }
+        if let Some(device) = device {
+            fs::create_dir_all(root.join(device)).unwrap();
+            symlink(
+                root.join(device),
+                root.join(format!("sys/block/{name}/device")),
+            )
+            .unwrap();
+        }
+        write(root, &format!("dev/{name}"), "");
+    }
+
+    /// A fake root with a USB stick, an internal disk, a hot-swap disk marked
+    /// removable, an SD card, a DVD drive, an empty card reader and virtual
+    /// disks.
+    fn tree() -> (TempDir, PathBuf) {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+
+        write(&root, &format!("{USB}/serial"), "4C530001\n");
+        write(&root, &format!("{USB}/{STICK}/vendor"), "SanDisk \n");
+        write(&root, &format!("{USB}/{STICK}/model"), "Cruzer\n");
+        write(&root, &format!("{USB}/{STICK}/type"), "0\n");
+        disk(
+            &root,
+            "sdb",
+            Some(&format!("{USB}/{STICK}")),
+            30_031_872,
+            &[("removable", "1")],
+        );
+        write(&root, "sys/block/sdb/sdb1/partition", "1\n");
+        write(&root, "sys/block/sdb/sdb1/size", "2048\n");
+        write(&root, "sys/block/sdb/sdb2/partition", "2\n");
+        write(&root, "sys/block/sdb/sdb2/size", "4096\n");
+        fs::create_dir_all(root.join("sys/block/sdb/holders")).unwrap();
+        fs::create_dir_all(root.join("sys/block/sdb/queue")).unwrap();
+
+        fs::create_dir_all(root.join("dev/disk/by-id")).unwrap();
+        for link in ["usb-SanDisk_Cruzer_4C530001-0:0", "wwn-0x5000000000000001"] {
+            symlink("../../sdb", root.join("dev/disk/by-id").join(link)).unwrap();
+        }
+
+        let ata = "sys/devices/pci0000:00/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0";
+        disk(&root, "sda", Some(ata), 1 << 30, &[("removable", "0")]);
+        let bay = "sys/devices/pci0000:00/0000:00:17.0/ata2/host1/target1:0:0/1:0:0:0";
+        disk(&root, "sdc", Some(bay), 1 << 20, &[("removable", "1")]);
+        let dvd = "sys/devices/pci0000:00/0000:00:17.0/ata3/host2/target2:0:0/2:0:0:0";
+        write(&root, &format!("{dvd}/type"), "5\n");
+        disk(&root, "sr0", Some(dvd), 1 << 20, &[("removable", "1")]);
+
+        let mmc = "sys/devices/platform/fe320000.mmc/mmc_host/mmc0/mmc0:0001";
+        write(&root, &format!("{mmc}/name"), "SD32G\n");
+        write(&root, &format!("{mmc}/serial"), "0x1234abcd\n");
+        disk(
+            &root,
+            "mmcblk0",
+            Some(mmc),
+            62_333_952,
+            &[("removable", "0")],
+        );
+
+        let reader =
+            "sys/devices/pci0000:00/0000:00:14.0/usb1/1-3/1-3:1.0/host7/target7:0:0/7:0:0:0";
+        disk(&root, "sdd", Some(reader), 0, &[("removable", "1")]);
+        disk(
+            &root,
+            "zram0",
+            Some("sys/devices/virtual/block/zram0"),
+            1 << 20,
+            &[("removable", "1")],
+        );
+        disk(&root, "loop0", None, 1 << 20, &[("removable", "0")]);
+
+        (dir, root)
+    }
+
+    #[test]
+    fn lists_the_removable_disks() {
+        let (_dir, root) = tree();
+        let sysfs = Sysfs { root: root.clone() };
+
+        let devices = sysfs.list_devices().unwrap();
+        let mut paths: Vec<&str> = devices.keys().map(String::as_str).collect();
+        paths.sort();
+        let stick = root.join("dev/disk/by-id/usb-SanDisk_Cruzer_4C530001-0:0");
+        let sys = |name: &str| root.join("sys/block").join(name).display().to_string();
+        assert_eq!(
+            paths,
+            [stick.to_str().unwrap(), &sys("mmcblk0"), &sys("sdc")]
+        );
+
+        let dev = &devices[stick.to_str().unwrap()];
+        assert_eq!(dev.drive.id, "usb-SanDisk_Cruzer_4C530001-0:0");
+        assert_eq!(dev.drive.connection_bus, "usb");
+        assert_eq!(
+            (dev.drive.vendor.as_str(), dev.drive.model.as_str()),
+            ("SanDisk", "Cruzer")
+        );
+        assert_eq!(dev.drive.serial, "4C530001");
+        assert!(dev.drive.removable && dev.drive.ejectable);
+        assert_eq!(dev.parent.size, 30_031_872 * 512);
+        assert_eq!(dev.parent.device, Path::new("/dev/sdb"));
+        assert_eq!(dev.parent.path, sys("sdb"));
+        let partitions: Vec<_> = dev
+            .partitions
+            .iter()
+            .map(|b| (b.device.clone(), b.size))
+            .collect();
+        assert_eq!(
+            partitions,
+            [
+                (PathBuf::from("/dev/sdb1"), 2048 * 512),
+                (PathBuf::from("/dev/sdb2"), 4096 * 512)
+            ]
+        );
+
+        let card = &devices[&sys("mmcblk0")];
+        assert_eq!(card.drive.connection_bus, "sdio");
+        assert_eq!(card.drive.model, "SD32G");
+        assert_eq!(card.drive.serial, "0x1234abcd");
+        assert!(!card.drive.removable && !card.drive.ejectable);
+        assert_eq!(card.parent.size, 62_333_952 * 512);
+
+        let bay = &devices[&sys("sdc")];
+        assert_eq!(bay.drive.connection_bus, "");
+        assert!(bay.partitions.is_empty());
+    }
+
+    #[test]
+    fn reads_partitions_and_changes() {
+        let (_dir, root) = tree();
+        let sysfs = Sysfs { root: root.clone() };
+        let stick = root.join("dev/disk/by-id/usb-SanDisk_Cruzer_4C530001-0:0");
+        let dev = sysfs
+            .list_devices()
+            .unwrap()
+            .remove(stick.to_str().unwrap())
+            .unwrap();
+
+        let partitions: Vec<_> = sysfs
+            .partitions(&dev)
+            .unwrap()
+            .into_iter()
+            .map(|p| (p.device, p.size, p.mount_points.is_empty()))
+            .collect();
+        assert_eq!(
+            partitions,
+            [
+                (PathBuf::from("/dev/sdb1"), 2048 * 512, true),
+                (PathBuf::from("/dev/sdb2"), 4096 * 512, true),
+            ]
+        );
+
+        assert!(sysfs.is_unchanged(&dev).unwrap());
+        write(&root, "sys/block/sdb/size", "15015936\n");
+        assert!(!sysfs.is_unchanged(&dev).unwrap());
+        fs::remove_dir_all(root.join("sys/block/sdb")).unwrap();
+        assert!(!sysfs.is_unchanged(&dev).unwrap());
+    }
+}
@@ -0,0 +1,334 @@
+use dbus::arg::{AppendAll, OwnedFd, ReadAll, RefArg, Variant};
+use dbus::blocking::stdintf::org_freedesktop_dbus::{
+    ObjectManagerInterfacesAdded, ObjectManagerInterfacesRemoved,
+};
+use dbus::blocking::{Connection, Proxy};
+use dbus::Message;
+use dbus_udisks2::{Block, DiskDevice, Disks, Drive, UDisks2};
+
+use super::{is_removable, system_protection, used, Backend, Error, Mount, Partition};
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// The interfaces of UDisks objects that make up a device.
+const DEVICE_INTERFACES: [&str; 2] = [
+    "org.freedesktop.UDisks2.Drive",
+    "org.freedesktop.UDisks2.Block",
+];
+
+/// How long UDisks may take to unmount or rescan. Unmounting flushes the
+/// filesystem first, which can take a while on slow sticks.
+const CALL_TIMEOUT: Duration = Duration::from_secs(120);
+
+type UDisksOptions = HashMap<String, Variant<Box<dyn RefArg>>>;
+
+/// Devices as UDisks sees them, over the system bus.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UDisks;
+
+impl UDisks {
+    /// Whether UDisks answers on the system bus.
+    pub fn is_available() -> bool {
+        UDisks2::new().is_ok()
+    }
+}
+
+impl Backend for UDisks {
+    /// Devices are keyed by the UDisks path of their drive. UDisks builds it
+    /// from the vendor, model and serial and keeps it unique, so two
+    /// identical sticks don't collide.
+    fn list_devices(&self) -> Result<HashMap<String, DiskDevice>, Error> {
+        let udisks = UDisks2::new()?;
+        let devices = Disks::new(&udisks).devices;
+
+        let mut map = HashMap::new();
+
+        devices.into_iter().filter(is_removable).for_each(|d| {
+            map.insert(d.drive.path.clone(), d);
+        });
+
+        Ok(map)
+    }
+
+    /// UDisks signals added and removed drives and block devices, they are
+    /// handled on a thread of their own.
+    fn watch_devices(&self) -> Result<UnboundedReceiver<()>, Error> {
+        let connection = Connection::new_system()?;
+        let (sender, receiver) = mpsc::unbounded();
+
+        let proxy = connection.with_proxy(
+            "org.freedesktop.UDisks2",
+            "/org/freedesktop/UDisks2",
+            Duration::new(25, 0),
+        );
+
+        let added = sender.clone();
+        proxy.match_signal(
+            move |s: ObjectManagerInterfacesAdded, _: &Connection, _: &Message| {
+                notify(&added, s.interfaces.keys())
+            },
+        )?;
+
+        let removed = sender.clone();
+        proxy.match_signal(
+            move |s: ObjectManagerInterfacesRemoved, _: &Connection, _: &Message| {
+                notify(&removed, s.interfaces.iter())
+            },
+        )?;
+
+        thread::spawn(move || {
+            while !sender.is_closed() {
+                if connection.process(Duration::from_secs(1)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(receiver)
+    }
+
+    /// The drive must still be the one behind the same block device.
+    fn is_unchanged(&self, dev: &DiskDevice) -> Result<bool, Error> {
+        let udisks = UDisks2::new()?;
+
+        let (block, drive) = match (
+            udisks.get_block(&dev.parent.path),
+            udisks.get_drive(&dev.drive.path),
+        ) {
+            (Some(block), Some(drive)) => (block, drive),
+            _ => return Ok(false),
+        };
+
+        Ok(block.drive == dev.drive.path
+            && block.device == dev.parent.device
+            && block.size == dev.parent.size
+            && drive.id == dev.drive.id
+            && drive.serial == dev.drive.serial
+            && drive.wwn == dev.drive.wwn)
+    }
+
+    fn partitions(&self, dev: &DiskDevice) -> Result<Vec<Partition>, Error> {
+        let udisks = UDisks2::new()?;
+
+        let blocks: Vec<Block> = udisks
+            .get_blocks()
+            .filter(|b| belongs(b, dev))
+            .filter(|b| b.path != dev.parent.path || !b.id_type.is_empty())
+            .collect();
+
+        let mut partitions: Vec<Partition> = blocks
+            .into_iter()
+            .map(|b| Partition {
+                used: b.mount_points.first().and_then(|point| used(point)),
+                device: b.device,
+                label: b.id_label,
+                kind: b.id_type,
+                size: b.size,
+                mount_points: b.mount_points,
+            })
+            .collect();
+        partitions.sort_by(|a, b| a.device.cmp(&b.device));
+
+        Ok(partitions)
+    }
+
+    fn mounts(&self, dev: &DiskDevice) -> Result<Vec<Mount>, Error> {
+        let udisks = UDisks2::new()?;
+
+        let mounts = udisks
+            .get_blocks()
+            .filter(|b| belongs(b, dev))
+            .filter(|b| !b.mount_points.is_empty())
+            .map(|b| Mount {
+                path: b.path,
+                device: b.device,
+                mount_points: b.mount_points,
+            })
+            .collect();
+
+        Ok(mounts)
+    }
+
+    /// The blocks of the device are looked up through UDisks, and what is
+    /// stacked on top of them through sysfs, which UDisks doesn't follow past
+    /// an unlocked encrypted partition.
+    fn protection(&self, dev: &DiskDevice) -> Result<Option<String>, Error> {
+        let udisks = UDisks2::new()?;
+        let nodes: Vec<PathBuf> = udisks
+            .get_blocks()
+            .filter(|b| belongs(b, dev))
+            .map(|b| b.device)
+            .collect();
+
+        system_protection(&nodes)
+    }
+
+    fn open(&self, dev: &DiskDevice, writable: bool) -> Result<File, Error> {
+        udisks_open(&dev.parent.path, writable)
+    }
+
+    fn unmount(&self, mount: &Mount) -> Result<(), Error> {
+        call(
+            &mount.path,
+            "org.freedesktop.UDisks2.Filesystem",
+            "Unmount",
+            (UDisksOptions::new(),),
+        )
+    }
+
+    fn eject(&self, dev: &DiskDevice) -> Result<bool, Error> {
+        for mount in self.mounts(dev)? {
+            self.unmount(&mount)?;
+        }
+
+        let mut removable = false;
+        if dev.drive.ejectable {
+            call::<_, ()>(
+                &dev.drive.path,
+                "org.freedesktop.UDisks2.Drive",
+                "Eject",
+                (UDisksOptions::new(),),
+            )?;
+            removable = true;
+        }
+        if dev.drive.can_power_off {
+            call::<_, ()>(
+                &dev.drive.path,
+                "org.freedesktop.UDisks2.Drive",
+                "PowerOff",
+                (UDisksOptions::new(),),
+            )?;
+            removable = true;
+        }
+
+        Ok(removable)
+    }
+
+    fn rescan(&self, dev: &DiskDevice) -> Result<(), Error> {
+        call(
+            &dev.parent.path,
+            "org.freedesktop.UDisks2.Block",
+            "Rescan",
+            (UDisksOptions::new(),),
+        )
+    }
+
+    fn format(&self, dev: &DiskDevice, kind: &str) -> Result<(), Error> {
+        call(
+            &dev.parent.path,
+            "org.freedesktop.UDisks2.Block",
+            "Format",
+            (kind, UDisksOptions::new()),
+        )
+    }
+
+    /// The loop device has no drive, only its block device.
+    fn loop_setup(&self, path: &Path) -> Result<DiskDevice, Error> {
+        let file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        let fd = unsafe { OwnedFd::new(file.into_raw_fd()) };
+
+        let (block,): (dbus::Path<'static>,) = call(
+            "/org/freedesktop/UDisks2/Manager",
+            "org.freedesktop.UDisks2.Manager",
+            "LoopSetup",
+            (fd, UDisksOptions::new()),
+        )?;
+
+        let parent = UDisks2::new()?
+            .get_block(&block)
+            .ok_or_else(|| Error::new(format!("{block} is gone")))?;
+
+        Ok(DiskDevice {
+            drive: Drive::default(),
+            parent,
+            partitions: vec![],
+        })
+    }
+
+    fn loop_delete(&self, dev: &DiskDevice) -> Result<(), Error> {
+        call(
+            &dev.parent.path,
+            "org.freedesktop.UDisks2.Loop",
+            "Delete",
+            (UDisksOptions::new(),),
+        )
+    }
+}
+
+/// Passes on a change of `interfaces` if it concerns devices. Returns whether
+/// to keep listening.
+fn notify<'a>(
+    sender: &UnboundedSender<()>,
+    mut interfaces: impl Iterator<Item = &'a String>,
+) -> bool {
+    if interfaces.any(|i| DEVICE_INTERFACES.contains(&i.as_str())) {
+        return sender.unbounded_send(()).is_ok();
+    }
+
+    !sender.is_closed()
+}
+
+/// Whether `block` is `dev` itself or one of its partitions.
+fn belongs(block: &Block, dev: &DiskDevice) -> bool {
+    block.path == dev.parent.path || (!dev.drive.path.is_empty() && block.drive == dev.drive.path)
+}
+
+fn call<A: AppendAll, R: ReadAll + 'static>(
+    dbus_path: &str,
+    interface: &str,
+    method: &str,
+    args: A,
+) -> Result<R, Error> {
+    let connection = Connection::new_system()?;
+
+    let dbus_path = match dbus::strings::Path::new(dbus_path) {
+        Ok(p) => p,
+        Err(e) => return Err(Error::new(e)),
+    };
+
+    let proxy = Proxy::new(
+        "org.freedesktop.UDisks2",
+        &dbus_path,
+        CALL_TIMEOUT,
+        &connection,
+    );
+    Ok(proxy.method_call(interface, method, args)?)
+}
+
+fn udisks_open(dbus_path: &str, writable: bool) -> Result<File, Error> {
+    let connection = Connection::new_system()?;
+
+    let dbus_path = match dbus::strings::Path::new(dbus_path) {
+        Ok(p) => p,
+        Err(e) => return Err(Error::new(e)),
+    };
+
+    let proxy = Proxy::new(
+        "org.freedesktop.UDisks2",
+        &dbus_path,
+        Duration::new(25, 0),
+        &connection,
+    );
+
+    // Reading only takes a lesser polkit permission.
+    let mut options = UDisksOptions::new();
+    let mode = match writable {
+        true => {
+            options.insert("flags".into(), Variant(Box::new(libc::O_SYNC)));
+            "rw"
+        }
+        false => "r",
+    };
+    let res: (OwnedFd,) = proxy.method_call(
+        "org.freedesktop.UDisks2.Block",
+        "OpenDevice",
+        (mode, options),
+    )?;
+
+    Ok(unsafe { File::from_raw_fd(res.0.into_fd()) })
+}
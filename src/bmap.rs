@@ -0,0 +1,437 @@
+use std::{
+    cmp::min,
+    fs,
+    io::{self, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use reqwest::Client;
+
+use crate::checksum::{fetch, Checksum, Hasher};
+use crate::Source;
+
+/// A bmaptool block map: the parts of an image that hold data.
+#[derive(Debug, Clone)]
+pub struct Bmap {
+    image_size: u64,
+    ranges: Vec<Range>,
+}
+
+/// A mapped byte range of the image, end exclusive.
+#[derive(Debug, Clone)]
+struct Range {
+    start: u64,
+    end: u64,
+    checksum: Option<Checksum>,
+}
+
+impl Bmap {
+    /// The size of the whole image, mapped or not.
+    pub fn image_size(&self) -> u64 {
+        self.image_size
+    }
+
+    /// Parses the XML format written by `bmaptool create`. Only SHA-256 range
+    /// checksums can be checked, so a bmap with others, like the SHA-1 ones of
+    /// format 1.x, is refused rather than written unchecked.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let doc = roxmltree::Document::parse(text).map_err(invalid)?;
+        let root = doc.root_element();
+
+        let value = |tag: &str| -> io::Result<u64> {
+            root.children()
+                .find(|n| n.has_tag_name(tag))
+                .and_then(|n| n.text())
+                .and_then(|t| t.trim().parse().ok())
+                .ok_or_else(|| invalid(format!("bmap has no valid {tag}")))
+        };
+
+        let image_size = value("ImageSize")?;
+        let block_size = value("BlockSize")?;
+
+        let map = root
+            .children()
+            .find(|n| n.has_tag_name("BlockMap"))
+            .ok_or_else(|| invalid("bmap has no BlockMap"))?;
+
+        let checksum_type = root
+            .children()
+            .find(|n| n.has_tag_name("ChecksumType"))
+            .and_then(|n| n.text())
+            .map(|t| t.trim().to_ascii_lowercase());
+
+        let mut ranges = vec![];
+        for node in map.children().filter(|n| n.has_tag_name("Range")) {
+            let text = node.text().unwrap_or("").trim();
+            let (first, last) = text.split_once('-').unwrap_or((text, text));
+
+            let (first, last): (u64, u64) = match (first.trim().parse(), last.trim().parse()) {
+                (Ok(first), Ok(last)) if first <= last => (first, last),
+                _ => return Err(invalid(format!("bad bmap range {text}"))),
+            };
+
+            let start = first.checked_mul(block_size);
+            let end = last.checked_add(1).and_then(|l| l.checked_mul(block_size));
+            let (start, end) = match (start, end) {
+                (Some(start), Some(end)) if start < image_size => (start, min(end, image_size)),
+                _ => return Err(invalid(format!("bmap range {text} is outside the image"))),
+            };
+
+            let checksum = match node.attribute("chksum") {
+                None => None,
+                Some(_) if checksum_type.as_deref().is_some_and(|t| t != "sha256") => {
+                    return Err(invalid(format!(
+                        "bmap range checksums are {}, only sha256 ones can be checked",
+                        checksum_type.as_deref().unwrap_or_default()
+                    )))
+                }
+                Some(hex) => Some(Checksum::sha256(hex).ok_or_else(|| {
+                    invalid(format!("bmap range {text} has no SHA-256 checksum"))
+                })?),
+            };
+
+            ranges.push(Range {
+                start,
+                end,
+                checksum,
+            });
+        }
+
+        ranges.sort_by_key(|r| r.start);
+
+        if ranges.windows(2).any(|r| r[1].start < r[0].end) {
+            return Err(invalid("bmap ranges overlap"));
+        }
+
+        Ok(Self { image_size, ranges })
+    }
+
+    /// Loads the bmap from `source`.
+    pub async fn load(source: &Source, client: &Client) -> io::Result<Self> {
+        let text = match source {
+            Source::Url(url) => {
+                let bytes = fetch(client, url).await.map_err(io::Error::other)?;
+                String::from_utf8_lossy(&bytes).into_owned()
+            }
+            Source::File(path) => fs::read_to_string(path)?,
+            Source::Mirrors(urls) => {
+                let mut fetched = Err(invalid("no mirror for the bmap"));
+                for url in urls {
+                    fetched = fetch(client, url).await.map_err(io::Error::other);
+                    if fetched.is_ok() {
+                        break;
+                    }
+                }
+                String::from_utf8_lossy(&fetched?).into_owned()
+            }
+            Source::Metalink(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "a bmap can't be loaded from a metalink",
+                ))
+            }
+        };
+
+        Self::parse(&text)
+    }
+}
+
+/// Looks for a bmap next to a local image, as bmaptool does: `<image>.bmap`
+/// or the image name with its extensions replaced.
+pub fn beside(image: &str) -> Option<Source> {
+    let path = Path::new(image);
+    let mut candidates = vec![format!("{image}.bmap")];
+
+    let mut stem = path.file_name()?.to_str()?;
+    while let Some((rest, _)) = stem.rsplit_once('.').filter(|(r, _)| !r.is_empty()) {
+        let candidate = path.with_file_name(format!("{rest}.bmap"));
+        candidates.push(candidate.to_string_lossy().into_owned());
+        stem = rest;
+    }
+
+    candidates
+        .into_iter()
+        .find(|c| Path::new(c).is_file())
+        .map(Source::File)
+}
+
+/// Writes only the mapped ranges of the image to `W`, seeking over the holes
+/// and checking each range against its checksum. Without a bmap everything is
+/// passed through.
+pub struct BmapWriter<W: Write + Seek> {
+    inner: W,
+    bmap: Option<Bmap>,
+    range: usize,
+    hasher: Option<Hasher>,
+    position: u64,
+    inner_position: u64,
+}
+
+impl<W: Write + Seek> BmapWriter<W> {
+    pub fn new(inner: W, bmap: Option<Bmap>) -> Self {
+        Self {
+            inner,
+            bmap,
+            range: 0,
+            hasher: None,
+            position: 0,
+            inner_position: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Bytes of the image seen so far, written or not.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Returns the inner writer. Fails if the image did not have the size the
+    /// bmap describes.
+    pub fn finish(self) -> io::Result<W> {
+        if let Some(bmap) = &self.bmap {
+            if self.position != bmap.image_size {
+                return Err(invalid(format!(
+                    "image is {} bytes but the bmap describes {}",
+                    self.position, bmap.image_size
+                )));
+            }
+        }
+
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write + Seek> Write for BmapWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bmap = match &self.bmap {
+            None => {
+                let size = self.inner.write(buf)?;
+                self.position += size as u64;
+                return Ok(size);
+            }
+            Some(bmap) => bmap,
+        };
+
+        let mut data = buf;
+        while !data.is_empty() {
+            let range = match bmap.ranges.get(self.range) {
+                Some(range) => range,
+                None => {
+                    self.position += data.len() as u64;
+                    break;
+                }
+            };
+
+            if self.position < range.start {
+                let skip = min(range.start - self.position, data.len() as u64) as usize;
+                self.position += skip as u64;
+                data = &data[skip..];
+                continue;
+            }
+
+            if self.inner_position != self.position {
+                self.inner_position = self.inner.seek(SeekFrom::Start(self.position))?;
+            }
+
+            if self.position == range.start {
+                self.hasher = range.checksum.as_ref().map(Checksum::hasher);
+            }
+
+            let take = min(range.end - self.position, data.len() as u64) as usize;
+            self.inner.write_all(&data[..take])?;
+
+            if let Some(hasher) = &mut self.hasher {
+                hasher.update(&data[..take]);
+            }
+
+            self.position += take as u64;
+            self.inner_position = self.position;
+            data = &data[take..];
+
+            if self.position == range.end {
+                if let (Some(checksum), Some(hasher)) = (&range.checksum, self.hasher.take()) {
+                    if !checksum.matches(hasher) {
+                        return Err(invalid(format!(
+                            "bytes {}-{} do not match the bmap checksum",
+                            range.start, range.end
+                        )));
+                    }
+                }
+
+                self.range += 1;
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn invalid<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Cursor, Write};
+
+    use sha2::{Digest, Sha256};
+
+    use super::{beside, Bmap, BmapWriter};
+    use crate::Source;
+
+    const BLOCK: usize = 4096;
+
+    fn image() -> Vec<u8> {
+        (0..BLOCK * 4 + 100).map(|i| (i % 251) as u8 + 1).collect()
+    }
+
+    /// A bmap of `image` mapping `ranges` of blocks, listed as given.
+    fn bmap(image: &[u8], ranges: &[(usize, usize)]) -> String {
+        let ranges: String = ranges
+            .iter()
+            .map(|&(first, last)| {
+                let end = ((last + 1) * BLOCK).min(image.len());
+                let digest = Sha256::digest(&image[first * BLOCK..end]);
+                let hex: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+                format!("<Range chksum=\"{hex}\"> {first}-{last} </Range>\n")
+            })
+            .collect();
+
+        format!(
+            "<?xml version=\"1.0\" ?>\n<bmap version=\"2.0\">\n\
+             <ImageSize> {} </ImageSize>\n<BlockSize> {BLOCK} </BlockSize>\n\
+             <ChecksumType> sha256 </ChecksumType>\n<BlockMap>\n{ranges}</BlockMap>\n</bmap>\n",
+            image.len()
+        )
+    }
+
+    fn write(bmap: Option<Bmap>, image: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut writer = BmapWriter::new(Cursor::new(vec![0; image.len()]), bmap);
+        for chunk in image.chunks(1000) {
+            writer.write_all(chunk)?;
+        }
+        Ok(writer.finish()?.into_inner())
+    }
+
+    #[test]
+    fn parses_the_ranges_in_order() {
+        let image = image();
+        let bmap = Bmap::parse(&bmap(&image, &[(4, 4), (0, 1)])).unwrap();
+
+        assert_eq!(bmap.image_size(), image.len() as u64);
+        let ranges: Vec<_> = bmap.ranges.iter().map(|r| (r.start, r.end)).collect();
+        assert_eq!(
+            ranges,
+            [
+                (0, 2 * BLOCK as u64),
+                (4 * BLOCK as u64, image.len() as u64)
+            ]
+        );
+        assert!(bmap.ranges.iter().all(|r| r.checksum.is_some()));
+    }
+
+    #[test]
+    fn refuses_checksums_it_cannot_check_and_bad_ranges() {
+        let sha1 = "da39a3ee5e6b4b0d3255bfef95601890afd80709";
+        let v1 = format!(
+            "<bmap version=\"1.4\"><ImageSize>8192</ImageSize><BlockSize>4096</BlockSize>\
+             <BlockMap><Range sha1=\"{sha1}\" chksum=\"{sha1}\">0</Range></BlockMap></bmap>"
+        );
+        let typed = bmap(&image(), &[(0, 0)]).replace("sha256", "sha1");
+
+        let range = |range: &str| {
+            format!(
+                "<bmap version=\"2.0\"><ImageSize>8192</ImageSize><BlockSize>4096</BlockSize>\
+                 <BlockMap>{range}</BlockMap></bmap>"
+            )
+        };
+
+        for text in [
+            v1,
+            typed,
+            range("<Range>18446744073709551615</Range>"),
+            range("<Range>4503599627370496</Range>"),
+            range("<Range>2</Range>"),
+            range("<Range>1-0</Range>"),
+            range("<Range>0-1</Range><Range>1</Range>"),
+            range("<Range>x</Range>"),
+            "<bmap><ImageSize>8192</ImageSize><BlockMap/></bmap>".into(),
+        ] {
+            let e = Bmap::parse(&text).unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidData, "{text}");
+        }
+    }
+
+    #[test]
+    fn writes_the_mapped_ranges_and_skips_the_holes() {
+        let image = image();
+        let bmap = Bmap::parse(&bmap(&image, &[(1, 1), (3, 4)])).unwrap();
+
+        let written = write(Some(bmap), &image).unwrap();
+
+        for (block, data) in written.chunks(BLOCK).enumerate() {
+            let mapped = matches!(block, 1 | 3 | 4);
+            let expected = &image[block * BLOCK..block * BLOCK + data.len()];
+            assert_eq!(data == expected, mapped, "block {block}");
+            assert_eq!(data.iter().all(|&b| b == 0), !mapped, "block {block}");
+        }
+
+        assert_eq!(write(None, &image).unwrap(), image);
+    }
+
+    #[test]
+    fn fails_on_a_range_that_does_not_match_its_checksum() {
+        let mut image = image();
+        let bmap = Bmap::parse(&bmap(&image, &[(0, 0), (2, 4)])).unwrap();
+        image[3 * BLOCK] ^= 1;
+
+        let e = write(Some(bmap.clone()), &image).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains(&format!("{}-", 2 * BLOCK)), "{e}");
+
+        // A change in a hole is not written, so it goes unnoticed.
+        image[3 * BLOCK] ^= 1;
+        image[BLOCK] ^= 1;
+        write(Some(bmap.clone()), &image).unwrap();
+
+        let e = write(Some(bmap), &image[..image.len() - 1]).unwrap_err();
+        assert!(e.to_string().contains("bmap describes"), "{e}");
+    }
+
+    #[test]
+    fn finds_a_bmap_beside_the_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("disk.img.xz");
+        let image = image.to_str().unwrap();
+        let found = |bmap: &str| match beside(image) {
+            Some(Source::File(path)) => path == dir.path().join(bmap).to_str().unwrap(),
+            _ => false,
+        };
+
+        assert!(beside(image).is_none());
+
+        fs::write(dir.path().join("disk.bmap"), "").unwrap();
+        assert!(found("disk.bmap"));
+
+        fs::write(dir.path().join("disk.img.bmap"), "").unwrap();
+        assert!(found("disk.img.bmap"));
+
+        fs::write(dir.path().join("disk.img.xz.bmap"), "").unwrap();
+        assert!(found("disk.img.xz.bmap"));
+
+        fs::write(dir.path().join(".bmap"), "").unwrap();
+        assert!(beside(dir.path().join(".img").to_str().unwrap()).is_none());
+    }
+}
@@ -0,0 +1,385 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::checksum::{self, Checksum};
+
+const PARTIAL: &str = ".part";
+
+/// Next to a partial download, the URL it came from and its ETag or
+/// Last-Modified date, to pick it up again only if the image is unchanged.
+const VALIDATOR: &str = ".part.validator";
+
+/// Hex digits of the key in front of the name of every cached image.
+const KEY_LEN: usize = 16;
+
+/// Downloaded images kept on disk, keyed by URL and checksum. The least
+/// recently used ones are evicted once the cache grows past its limit. Only
+/// files named like a cached image are listed or removed, so other files in
+/// the directory are left alone.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    dir: PathBuf,
+    limit: u64,
+}
+
+/// An image in the cache.
+#[derive(Debug, Clone)]
+pub struct Cached {
+    pub name: String,
+    pub path: PathBuf,
+    pub size: u64,
+    pub used: SystemTime,
+}
+
+impl Cache {
+    /// A `limit` of 0 disables caching.
+    pub fn new(dir: PathBuf, limit: u64) -> Self {
+        Self { dir, limit }
+    }
+
+    /// `$XDG_CACHE_HOME/linux_creation_tool`, or the same under `~/.cache`.
+    pub fn default_dir() -> PathBuf {
+        let base = match env::var_os("XDG_CACHE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME").unwrap_or_else(|| "/tmp".into())).join(".cache"),
+        };
+
+        base.join("linux_creation_tool")
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.limit > 0
+    }
+
+    /// Returns the cached copy of `url`, marking it as used.
+    pub fn get(&self, url: &str, checksum: Option<&Checksum>) -> Option<PathBuf> {
+        if !self.enabled() {
+            return None;
+        }
+
+        let path = self.path(url, checksum);
+        let file = File::options().write(true).open(&path).ok()?;
+        let _ = file.set_modified(SystemTime::now());
+
+        Some(path)
+    }
+
+    /// Starts caching a download of `url`, or carries on with one an earlier
+    /// run left unfinished. It only shows up in the cache once committed.
+    pub fn entry(&self, url: &str, checksum: Option<&Checksum>) -> io::Result<Entry> {
+        fs::create_dir_all(&self.dir)?;
+
+        let path = self.path(url, checksum);
+        let partial = with_suffix(&path, PARTIAL);
+        let validator = with_suffix(&path, VALIDATOR);
+
+        Ok(Entry {
+            file: Some(File::options().create(true).append(true).open(&partial)?),
+            partial,
+            validator,
+            path,
+            cache: self.clone(),
+        })
+    }
+
+    /// The cached images, most recently used first. Anything not named
+    /// `<key>-<name>` isn't one.
+    pub fn list(&self) -> io::Result<Vec<Cached>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+
+        let mut images = vec![];
+        for entry in entries {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let name = match image_name(&file_name) {
+                Some(name) if !file_name.ends_with(PARTIAL) && !file_name.ends_with(VALIDATOR) => {
+                    name.to_string()
+                }
+                _ => continue,
+            };
+
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+
+            images.push(Cached {
+                name,
+                path: entry.path(),
+                size: metadata.len(),
+                used: metadata.modified()?,
+            });
+        }
+
+        images.sort_by_key(|image| std::cmp::Reverse(image.used));
+
+        Ok(images)
+    }
+
+    pub fn remove(&self, image: &Cached) -> io::Result<()> {
+        fs::remove_file(&image.path)
+    }
+
+    /// Removes the cached images and unfinished downloads.
+    pub fn clear(&self) -> io::Result<()> {
+        for image in self.list()? {
+            self.remove(&image)?;
+        }
+
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let file_name = entry?.file_name().to_string_lossy().into_owned();
+            let partial = file_name.ends_with(PARTIAL) || file_name.ends_with(VALIDATOR);
+            if partial && image_name(&file_name).is_some() {
+                fs::remove_file(self.dir.join(file_name))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the least recently used images until the cache fits its limit.
+    fn evict(&self) -> io::Result<()> {
+        let mut images = self.list()?;
+        let mut size: u64 = images.iter().map(|i| i.size).sum();
+
+        while size > self.limit {
+            let oldest = match images.pop() {
+                Some(image) => image,
+                None => break,
+            };
+
+            self.remove(&oldest)?;
+            size -= oldest.size;
+        }
+
+        Ok(())
+    }
+
+    fn path(&self, url: &str, checksum: Option<&Checksum>) -> PathBuf {
+        let mut hasher = Sha256::new();
+        hasher.update(url);
+        if let Some(checksum) = checksum {
+            hasher.update(checksum.to_hex());
+        }
+
+        let key: String = hasher.finalize()[..KEY_LEN / 2]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+
+        self.dir.join(format!("{key}-{}", checksum::file_name(url)))
+    }
+}
+
+/// The name of the image in `file_name` of a cached image, `None` if it is
+/// something else.
+fn image_name(file_name: &str) -> Option<&str> {
+    let (key, name) = file_name.split_once('-')?;
+    let keyed = key.len() == KEY_LEN
+        && key
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+
+    Some(name).filter(|name| keyed && !name.is_empty())
+}
+
+/// `path` with `suffix` appended to its file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// A download being written into the cache. Dropping it without committing
+/// keeps the partial file if it can be resumed, which takes a validator, and
+/// throws it away otherwise.
+pub struct Entry {
+    file: Option<File>,
+    partial: PathBuf,
+    validator: PathBuf,
+    path: PathBuf,
+    cache: Cache,
+}
+
+impl Entry {
+    /// How much an earlier run downloaded, and the URL and validator it
+    /// downloaded it with.
+    pub fn resumable(&self) -> Option<(u64, String, String)> {
+        let file = self.file.as_ref()?;
+        let len = file.metadata().ok()?.len();
+        let saved = fs::read_to_string(&self.validator).ok()?;
+        let (url, validator) = saved.split_once('\n')?;
+
+        match len > 0 && !validator.is_empty() {
+            true => Some((len, url.to_string(), validator.to_string())),
+            false => None,
+        }
+    }
+
+    /// Reads back what an earlier run downloaded.
+    pub fn replay(&self) -> io::Result<File> {
+        File::open(&self.partial)
+    }
+
+    /// Starts over with a download of `url`, which can be resumed later on if
+    /// it has a `validator`.
+    pub fn restart(&mut self, url: &str, validator: Option<&str>) {
+        let _ = fs::remove_file(&self.validator);
+
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+        if file.set_len(0).is_err() {
+            self.file = None;
+            return;
+        }
+
+        if let Some(validator) = validator {
+            let _ = fs::write(&self.validator, format!("{url}\n{validator}"));
+        }
+    }
+
+    /// Throws the download away, e.g. because it doesn't match its checksum.
+    pub fn discard(mut self) {
+        self.file = None;
+        let _ = fs::remove_file(&self.validator);
+    }
+
+    /// Appends `data`. A failure, e.g. a full disk, only gives up on caching
+    /// and never fails the write itself.
+    pub fn write(&mut self, data: &[u8]) {
+        if let Some(file) = &mut self.file {
+            if file.write_all(data).is_err() {
+                self.file = None;
+            }
+        }
+    }
+
+    /// Moves the finished download into the cache and evicts older images.
+    pub fn commit(mut self) -> io::Result<()> {
+        let file = match self.file.take() {
+            Some(file) => file,
+            None => return Err(io::Error::other("caching the download failed")),
+        };
+
+        file.sync_all()?;
+        drop(file);
+
+        let _ = fs::remove_file(&self.validator);
+        fs::rename(&self.partial, &self.path)?;
+        self.cache.evict()
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        if self.file.is_none() || !self.validator.exists() {
+            let _ = fs::remove_file(&self.partial);
+            let _ = fs::remove_file(&self.validator);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::time::{Duration, SystemTime};
+
+    use tempfile::TempDir;
+
+    use super::Cache;
+    use crate::checksum::Checksum;
+
+    fn add(cache: &Cache, url: &str, size: usize) {
+        let mut entry = cache.entry(url, None).unwrap();
+        entry.write(&vec![1; size]);
+        entry.commit().unwrap();
+    }
+
+    #[test]
+    fn keys_images_by_url_and_checksum() {
+        let dir = TempDir::new().unwrap();
+        let cache = Cache::new(dir.path().into(), 1 << 20);
+        let checksum = Checksum::sha256(&"ab".repeat(32));
+
+        assert!(cache.get("http://a/image.iso", None).is_none());
+        let mut entry = cache
+            .entry("http://a/image.iso", checksum.as_ref())
+            .unwrap();
+        entry.write(b"image");
+        entry.commit().unwrap();
+
+        assert!(cache.get("http://a/image.iso", None).is_none());
+        assert!(cache.get("http://b/image.iso", checksum.as_ref()).is_none());
+        let path = cache.get("http://a/image.iso", checksum.as_ref()).unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"image");
+
+        let listed = cache.list().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].name, "image.iso");
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let dir = TempDir::new().unwrap();
+        let cache = Cache::new(dir.path().into(), 250);
+
+        add(&cache, "http://a/old.iso", 100);
+        add(&cache, "http://a/used.iso", 100);
+        let old = SystemTime::now() - Duration::from_secs(60);
+        for image in cache.list().unwrap() {
+            File::options()
+                .write(true)
+                .open(&image.path)
+                .unwrap()
+                .set_modified(old)
+                .unwrap();
+        }
+        cache.get("http://a/used.iso", None).unwrap();
+
+        add(&cache, "http://a/new.iso", 100);
+
+        let mut names: Vec<String> = cache.list().unwrap().into_iter().map(|i| i.name).collect();
+        names.sort();
+        assert_eq!(names, ["new.iso", "used.iso"]);
+    }
+
+    #[test]
+    fn leaves_other_files_alone() {
+        let dir = TempDir::new().unwrap();
+        let cache = Cache::new(dir.path().into(), 150);
+        for name in [
+            "notes.txt",
+            "holiday-photos.tar",
+            "0123456789abcdef-",
+            "0123456789ABCDEF-x.iso",
+        ] {
+            fs::write(dir.path().join(name), vec![0; 1000]).unwrap();
+        }
+
+        add(&cache, "http://a/one.iso", 100);
+        add(&cache, "http://a/two.iso", 100);
+        assert_eq!(cache.list().unwrap().len(), 1);
+
+        cache.clear().unwrap();
+        assert!(cache.list().unwrap().is_empty());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 4);
+    }
+}
@@ -0,0 +1,314 @@
+use std::io;
+
+use reqwest::Client;
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::signature::Signature;
+
+/// A digest the image is expected to hash to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Checksum {
+    Sha256(Vec<u8>),
+    Sha512(Vec<u8>),
+}
+
+impl Checksum {
+    /// Parses a hex SHA-256 digest, refusing one of another length.
+    pub fn sha256(hex: &str) -> Option<Self> {
+        Some(Checksum::Sha256(decode_hex(hex.trim())?)).filter(|c| c.len() == 32)
+    }
+
+    /// Parses a hex SHA-512 digest, refusing one of another length.
+    pub fn sha512(hex: &str) -> Option<Self> {
+        Some(Checksum::Sha512(decode_hex(hex.trim())?)).filter(|c| c.len() == 64)
+    }
+
+    /// Parses a hex digest where nothing names the algorithm, picking it
+    /// from the length.
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        Self::sha256(hex).or_else(|| Self::sha512(hex))
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Checksum::Sha256(d) | Checksum::Sha512(d) => d.len(),
+        }
+    }
+
+    pub fn to_hex(&self) -> String {
+        match self {
+            Checksum::Sha256(d) | Checksum::Sha512(d) => {
+                d.iter().map(|b| format!("{b:02x}")).collect()
+            }
+        }
+    }
+
+    /// A checksum configuration that expects exactly this digest.
+    pub fn expected(&self) -> Expected {
+        match self {
+            Checksum::Sha256(_) => Expected {
+                sha256: Some(self.to_hex()),
+                ..Default::default()
+            },
+            Checksum::Sha512(_) => Expected {
+                sha512: Some(self.to_hex()),
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            Checksum::Sha256(_) => Hasher::Sha256(Box::default()),
+            Checksum::Sha512(_) => Hasher::Sha512(Box::default()),
+        }
+    }
+
+    pub fn matches(&self, hasher: Hasher) -> bool {
+        let expected = match self {
+            Checksum::Sha256(d) | Checksum::Sha512(d) => d,
+        };
+
+        hasher.finalize() == *expected
+    }
+}
+
+pub enum Hasher {
+    Sha256(Box<Sha256>),
+    Sha512(Box<Sha512>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha512(h) => h.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+            Hasher::Sha512(h) => h.finalize().to_vec(),
+        }
+    }
+}
+
+/// The checksum configuration of a catalog entry.
+#[derive(Debug, Clone, Default)]
+pub struct Expected {
+    pub sha256: Option<String>,
+    pub sha512: Option<String>,
+    pub url: Option<String>,
+    pub signature: Option<Signature>,
+}
+
+impl Expected {
+    /// Whether a checksum is configured at all, or a signature that needs
+    /// one.
+    pub fn is_set(&self) -> bool {
+        self.sha256.is_some()
+            || self.sha512.is_some()
+            || self.url.is_some()
+            || self.signature.is_some()
+    }
+
+    /// Returns the digest the image called `name` has to match, fetching the
+    /// checksum file and checking its signature if necessary. `None` means no
+    /// checksum is configured.
+    pub async fn resolve(&self, client: &Client, name: &str) -> io::Result<Option<Checksum>> {
+        // A signature is made over a checksum file, without one there is
+        // nothing it could vouch for.
+        if self.signature.is_some() && self.url.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a signature_url needs a checksum_url it signs",
+            ));
+        }
+
+        let configured = match (&self.sha512, &self.sha256) {
+            (Some(hex), _) => Some(("sha512", Checksum::sha512(hex))),
+            (None, Some(hex)) => Some(("sha256", Checksum::sha256(hex))),
+            (None, None) => None,
+        };
+        let configured = match configured {
+            Some((_, Some(checksum))) => Some(checksum),
+            Some((field, None)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("the {field} checksum is not a hex digest of the right length"),
+                ))
+            }
+            None => None,
+        };
+
+        // A configured digest is taken as is, unless a signature is to be
+        // checked. Then it has to be the one the signed file lists.
+        let url = match (&self.url, &self.signature, &configured) {
+            (Some(url), Some(_), _) | (Some(url), None, None) => url,
+            _ => return Ok(configured),
+        };
+
+        let content = fetch(client, url).await.map_err(io::Error::other)?;
+
+        if let Some(signature) = &self.signature {
+            signature.verify(client, &content).await?;
+        }
+
+        let content = String::from_utf8_lossy(&content);
+
+        match (find_in_sums(&content, name), configured) {
+            (Some(listed), Some(configured)) if listed != configured => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{url} lists another checksum for {name} than the configured one"),
+            )),
+            (Some(listed), _) => Ok(Some(listed)),
+            (None, _) => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{name} is not listed in {url}"),
+            )),
+        }
+    }
+}
+
+pub(crate) async fn fetch(client: &Client, url: &str) -> reqwest::Result<Vec<u8>> {
+    let response = client.get(url).send().await?.error_for_status()?;
+
+    Ok(response.bytes().await?.to_vec())
+}
+
+/// Looks up `name` in a SHA256SUMS-style file. Both the GNU
+/// (`<hex>  <name>`) and the BSD (`SHA256 (<name>) = <hex>`) formats are
+/// understood, other lines and other algorithms are ignored. The GNU format
+/// doesn't name the algorithm, so there it is told by the length.
+pub fn find_in_sums(content: &str, name: &str) -> Option<Checksum> {
+    for line in content.lines() {
+        let line = line.trim();
+
+        if let Some((head, hex)) = line.split_once(") = ") {
+            let checksum = match head.split_once(" (") {
+                Some(("SHA256", file)) if file == name => Checksum::sha256(hex),
+                Some(("SHA512", file)) if file == name => Checksum::sha512(hex),
+                _ => None,
+            };
+            match checksum {
+                Some(checksum) => return Some(checksum),
+                None => continue,
+            }
+        }
+
+        if let Some((hex, file)) = line.split_once(char::is_whitespace) {
+            if file.trim_start().trim_start_matches('*') == name {
+                if let Some(checksum) = Checksum::from_hex(hex) {
+                    return Some(checksum);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// The file name a checksum file would list for `source`.
+pub fn file_name(source: &str) -> &str {
+    let source = source.split(['?', '#']).next().unwrap_or(source);
+
+    source.rsplit('/').next().unwrap_or(source)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => Some((hex_value(*high)? << 4) | hex_value(*low)?),
+            _ => None,
+        })
+        .collect()
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|v| v as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{file_name, find_in_sums, Checksum, Expected};
+
+    const SHA256: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    fn sha512() -> String {
+        "ee26b0dd4af7e749aa1a8ee3c10ae9923f618980772e473f8819a5d4940e0db2".repeat(2)
+    }
+
+    #[test]
+    fn parses_digests_of_the_right_length_only() {
+        let checksum = Checksum::sha256(&format!(" {} ", SHA256.to_uppercase())).unwrap();
+        assert_eq!(checksum.to_hex(), SHA256);
+        assert!(matches!(
+            Checksum::sha512(&sha512()),
+            Some(Checksum::Sha512(_))
+        ));
+
+        assert!(Checksum::sha256(&sha512()).is_none());
+        assert!(Checksum::sha512(SHA256).is_none());
+        assert!(Checksum::sha256(&SHA256[1..]).is_none());
+        assert!(Checksum::sha256(&SHA256.replace('f', "g")).is_none());
+        assert!(Checksum::sha256("").is_none());
+    }
+
+    #[tokio::test]
+    async fn takes_the_algorithm_from_the_field() {
+        let client = reqwest::Client::new();
+        let resolve = |sha256: Option<String>, sha512: Option<String>| {
+            let expected = Expected {
+                sha256,
+                sha512,
+                ..Default::default()
+            };
+            let client = client.clone();
+            async move { expected.resolve(&client, "image.iso").await }
+        };
+
+        let checksum = resolve(Some(SHA256.into()), None).await.unwrap();
+        assert!(matches!(checksum, Some(Checksum::Sha256(_))));
+        let checksum = resolve(None, Some(sha512())).await.unwrap();
+        assert!(matches!(checksum, Some(Checksum::Sha512(_))));
+
+        assert!(resolve(Some(sha512()), None).await.is_err());
+        assert!(resolve(None, Some(SHA256.into())).await.is_err());
+        assert!(resolve(None, None).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn finds_images_in_checksum_files() {
+        let gnu = format!(
+            "# a comment\n{}  other.iso\n{SHA256} *image.iso\n{}  big.iso\n",
+            "0".repeat(64),
+            sha512()
+        );
+        assert_eq!(find_in_sums(&gnu, "image.iso").unwrap().to_hex(), SHA256);
+        assert!(matches!(
+            find_in_sums(&gnu, "big.iso"),
+            Some(Checksum::Sha512(_))
+        ));
+        assert!(find_in_sums(&gnu, "missing.iso").is_none());
+
+        let bsd = format!(
+            "MD5 (image.iso) = {}\nSHA512 (image.iso) = {SHA256}\nSHA256 (image.iso) = {SHA256}\n",
+            "0".repeat(32)
+        );
+        assert_eq!(find_in_sums(&bsd, "image.iso").unwrap().to_hex(), SHA256);
+        assert!(find_in_sums(&bsd, "image").is_none());
+    }
+
+    #[test]
+    fn names_files_like_checksum_files_do() {
+        assert_eq!(file_name("https://example.org/a/image.iso"), "image.iso");
+        assert_eq!(
+            file_name("https://example.org/image.iso?mirror=1#x"),
+            "image.iso"
+        );
+        assert_eq!(file_name("/srv/images/image.img.xz"), "image.img.xz");
+        assert_eq!(file_name("image.iso"), "image.iso");
+    }
+}
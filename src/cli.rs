@@ -0,0 +1,722 @@
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use dbus_udisks2::DiskDevice;
+use futures::StreamExt;
+use linux_creation_tool::backend::{self, Sysfs, UDisks};
+use linux_creation_tool::checksum::Checksum;
+use linux_creation_tool::target::{release, Target};
+use linux_creation_tool::transfer;
+use linux_creation_tool::writer::Job;
+use linux_creation_tool::*;
+use reqwest::Client;
+use serde_json::json;
+
+use crate::CONFIG;
+
+/// Writes operating system images to USB sticks. Without a subcommand the
+/// graphical interface is started.
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Print results and progress as JSON lines
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Catalog of operating systems
+    #[arg(long, global = true, default_value = CONFIG)]
+    pub config: String,
+
+    /// How devices are found and opened. auto uses UDisks if it is running
+    /// and sysfs otherwise
+    #[arg(long, global = true, value_enum, default_value_t = BackendKind::Auto)]
+    backend: BackendKind,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Cli {
+    pub fn backend(&self) -> Arc<dyn Backend> {
+        match self.backend {
+            BackendKind::Auto => backend::detect(),
+            BackendKind::Udisks => Arc::new(UDisks),
+            BackendKind::Sysfs => Arc::new(Sysfs::default()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum BackendKind {
+    Auto,
+    /// The UDisks daemon, which asks polkit for permission
+    Udisks,
+    /// The kernel's list of disks, needs root or write permission on them
+    Sysfs,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// List the devices that can be written to
+    ListDevices,
+    /// List the operating systems in the catalog
+    Catalog,
+    /// Write an image to one or more devices
+    Write(WriteArgs),
+    /// Compare devices with an image without writing to them
+    Verify(WriteArgs),
+    /// Erase a device and create an empty filesystem on it, to use a written
+    /// stick for files again
+    Format(FormatArgs),
+    /// List the downloaded images in the cache
+    Cache {
+        /// Remove all cached images
+        #[arg(long)]
+        clear: bool,
+    },
+}
+
+#[derive(Args)]
+pub struct WriteArgs {
+    /// Name of a catalog entry, or the path or URL of an image or metalink
+    source: String,
+
+    /// Device ids as shown by list-devices, or nodes such as /dev/sdb. Paths
+    /// of files with --file or --loop
+    #[arg(required = true)]
+    devices: Vec<String>,
+
+    /// Write to regular files instead of devices, creating them as needed
+    #[arg(long, conflicts_with = "loop_device")]
+    file: bool,
+
+    /// Attach existing files as loop devices and write to those, so the image
+    /// has to fit in them
+    #[arg(long = "loop")]
+    loop_device: bool,
+
+    /// Skip reading the devices back after writing
+    #[arg(long)]
+    no_verify: bool,
+
+    /// Expected SHA-256 or SHA-512 of the image, in hex
+    #[arg(long)]
+    checksum: Option<String>,
+
+    /// Image to write from a zip archive holding several
+    #[arg(long)]
+    member: Option<String>,
+
+    /// Path or URL of a bmaptool block map for the image
+    #[arg(long)]
+    bmap: Option<String>,
+
+    /// Unmount filesystems mounted from the devices instead of refusing to
+    /// write to them
+    #[arg(long)]
+    unmount: bool,
+
+    /// Leave the devices attached instead of ejecting them after writing
+    #[arg(long)]
+    no_eject: bool,
+
+    /// Write to devices larger than the large_device_gb of the catalog too,
+    /// understanding that everything on them is destroyed
+    #[arg(long)]
+    allow_large: bool,
+
+    /// Write without showing what is on the devices and asking first
+    #[arg(long, short)]
+    yes: bool,
+}
+
+#[derive(Args)]
+pub struct FormatArgs {
+    /// Device id as shown by list-devices, or a node such as /dev/sdb
+    device: String,
+
+    /// Filesystem to create, such as vfat, exfat or ext4. "empty" only wipes
+    /// what is there
+    #[arg(long, default_value = "vfat")]
+    kind: String,
+
+    /// Unmount filesystems mounted from the device instead of refusing to
+    /// format it
+    #[arg(long)]
+    unmount: bool,
+
+    /// Format without showing what is on the device and asking first
+    #[arg(long, short)]
+    yes: bool,
+}
+
+pub fn run(cli: Cli) -> Result<(), String> {
+    let json = cli.json;
+    let backend = cli.backend();
+
+    match cli.command {
+        None => Ok(()),
+        Some(Command::ListDevices) => devices(json, backend.as_ref()),
+        Some(Command::Catalog) => catalog(json, &cli.config),
+        Some(Command::Write(args)) => write(json, &cli.config, backend, args, false),
+        Some(Command::Verify(args)) => write(json, &cli.config, backend, args, true),
+        Some(Command::Format(args)) => format(backend.as_ref(), args),
+        Some(Command::Cache { clear }) => cache(json, &cli.config, clear),
+    }
+}
+
+fn devices(json: bool, backend: &dyn Backend) -> Result<(), String> {
+    let devices = backend.list_devices().map_err(|e| e.to_string())?;
+
+    let mut devices: Vec<(&String, &DiskDevice)> = devices.iter().collect();
+    devices.sort_by_key(|(_, dev)| &dev.parent.device);
+
+    for (id, dev) in devices {
+        let block = &dev.parent;
+        let label = device_label(dev);
+        let protected = backend.protection(dev).map_err(|e| e.to_string())?;
+
+        match (json, protected) {
+            (true, protected) => println!(
+                "{}",
+                json!({
+                    "id": id,
+                    "label": label,
+                    "device": block.device,
+                    "size": block.size,
+                    "protected": protected
+                })
+            ),
+            (false, Some(reason)) => println!("{id}\t{label}\t(protected, {reason})"),
+            (false, None) => println!("{id}\t{label}"),
+        }
+    }
+
+    Ok(())
+}
+
+fn catalog(json: bool, config: &str) -> Result<(), String> {
+    let list = load_config(config).map_err(|e| format!("{config}: {e}"))?;
+
+    for os in list.as_vec() {
+        match json {
+            true => println!("{}", serde_json::to_string(os).map_err(|e| e.to_string())?),
+            false => match os.source() {
+                Source::Url(s) | Source::File(s) | Source::Metalink(s) => {
+                    println!("{}\t{}", os.name(), s)
+                }
+                Source::Mirrors(urls) => println!("{}\t{}", os.name(), urls.join(" ")),
+            },
+        }
+    }
+
+    Ok(())
+}
+
+fn cache(json: bool, config: &str, clear: bool) -> Result<(), String> {
+    let cache = load_config(config)
+        .unwrap_or_else(|_| OperatingSystemList::empty())
+        .cache();
+
+    if clear {
+        return cache.clear().map_err(|e| e.to_string());
+    }
+
+    for image in cache.list().map_err(|e| e.to_string())? {
+        match json {
+            true => println!(
+                "{}",
+                json!({ "name": image.name, "path": image.path, "size": image.size })
+            ),
+            false => println!(
+                "{}\t{}\t{}",
+                transfer::bytes(image.size),
+                image.name,
+                image.path.display()
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+fn write(
+    json: bool,
+    config: &str,
+    backend: Arc<dyn Backend>,
+    args: WriteArgs,
+    compare_only: bool,
+) -> Result<(), String> {
+    let targets = args
+        .devices
+        .iter()
+        .map(|name| match (args.file, args.loop_device) {
+            (true, _) => Ok(Target::File(name.into())),
+            (_, true) => Ok(Target::Loop(name.into())),
+            _ => find_device(backend.as_ref(), name).map(|dev| Target::Device(Box::new(dev))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    for target in &targets {
+        target.check().map_err(|e| e.to_string())?;
+    }
+    let names: Vec<String> = targets.iter().map(Target::name).collect();
+    let devs: Vec<&DiskDevice> = targets
+        .iter()
+        .filter_map(|target| match target {
+            Target::Device(dev) => Some(&**dev),
+            _ => None,
+        })
+        .collect();
+    let files: Vec<&Path> = targets.iter().filter_map(Target::existing_file).collect();
+
+    let catalog = load_config(config).unwrap_or_else(|_| OperatingSystemList::empty());
+
+    if !compare_only && !args.allow_large {
+        if let Some(dev) = devs.iter().find(|dev| catalog.is_large(dev.parent.size)) {
+            return Err(format!(
+                "{} is {}, more than a USB stick usually holds; pass --allow-large to write to it anyway",
+                dev.parent.device.display(),
+                transfer::bytes(dev.parent.size)
+            ));
+        }
+    }
+
+    if !compare_only && !args.yes && (!devs.is_empty() || !files.is_empty()) {
+        confirm(backend.as_ref(), &devs, &files, "write", "Write to them?")?;
+    }
+
+    let (source, mut options) = source(&catalog, args.source);
+
+    if let Some(checksum) = args.checksum {
+        // Which of the two it is, is told by the length.
+        options.checksum = Checksum::from_hex(&checksum)
+            .ok_or("--checksum is not a SHA-256 or SHA-512 hex digest")?
+            .expected();
+    }
+    if args.member.is_some() {
+        options.member = args.member;
+    }
+    if let Some(bmap) = args.bmap {
+        options.bmap = Some(
+            match bmap.starts_with("http://") || bmap.starts_with("https://") {
+                true => Source::Url(bmap),
+                false => Source::File(bmap),
+            },
+        );
+    }
+    options.verify = !args.no_verify;
+    options.compare_only = compare_only;
+    options.unmount = args.unmount;
+    options.eject = !args.no_eject;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let job = Job::new(source, targets, Client::new(), options)
+        .with_backend(backend)
+        .with_cache(catalog.cache());
+
+    // Ctrl-C cancels the job so the device is flushed and closed cleanly. If
+    // that takes too long, a second one exits right away.
+    let cancel = job.cancel_handle();
+    runtime.spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            cancel.cancel();
+        }
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("Exiting without waiting for the devices.");
+            std::process::exit(130);
+        }
+    });
+
+    let several = names.len() > 1;
+    let results = runtime.block_on(async {
+        let mut reports = job.run();
+        let mut results: Vec<Option<Progress>> = vec![None; names.len()];
+
+        while let Some(report) = reports.next().await {
+            for (i, result) in results.iter_mut().enumerate() {
+                if !report.concerns(i) || result.is_some() {
+                    continue;
+                }
+
+                print(json, &names[i], several, &report.progress);
+                if report.progress.is_final() {
+                    *result = Some(report.progress.clone());
+                }
+            }
+        }
+
+        results
+    });
+
+    // Every device is reported on, so one bad stick doesn't hide the others.
+    let failures: Vec<String> = names
+        .iter()
+        .zip(results)
+        .filter_map(|(name, result)| match outcome(result, compare_only) {
+            Ok(()) => None,
+            Err(e) if several => Some(format!("{name}: {e}")),
+            Err(e) => Some(e),
+        })
+        .collect();
+
+    match failures.is_empty() {
+        true => Ok(()),
+        false => Err(failures.join("\n")),
+    }
+}
+
+/// What to write for `name`. A catalog entry brings its own checksum, bmap
+/// and member settings, anything else is taken as a path or URL.
+fn source(catalog: &OperatingSystemList, name: String) -> (Source, WriteOptions) {
+    match catalog.as_vec().iter().find(|os| *os.name() == name) {
+        Some(os) => (os.source().clone(), os.options(true)),
+        None if name.starts_with("http://") || name.starts_with("https://") => {
+            let source = match name.ends_with(".meta4") || name.ends_with(".metalink") {
+                true => Source::Metalink(name),
+                false => Source::Url(name),
+            };
+            (source, WriteOptions::default())
+        }
+        None => (Source::File(name), WriteOptions::default()),
+    }
+}
+
+/// Creates an empty filesystem on a device, with the same checks as before
+/// writing an image.
+fn format(backend: &dyn Backend, args: FormatArgs) -> Result<(), String> {
+    let dev = find_device(backend, &args.device)?;
+
+    if let Some(reason) = backend.protection(&dev).map_err(|e| e.to_string())? {
+        return Err(WriteError::Protected { reason }.to_string());
+    }
+
+    if !args.yes {
+        confirm(backend, &[&dev], &[], "format", "Format it?")?;
+    }
+
+    release(backend, &dev, args.unmount).map_err(|e| e.to_string())?;
+    backend
+        .format(&dev, &args.kind)
+        .map_err(|e| format!("Could not format {}: {e}", dev.parent.device.display()))?;
+
+    println!("Done.");
+    Ok(())
+}
+
+/// Shows what is on `devs` and which `files` are overwritten, and asks
+/// `question` about destroying it. `verb` names what is done to them.
+fn confirm(
+    backend: &dyn Backend,
+    devs: &[&DiskDevice],
+    files: &[&Path],
+    verb: &str,
+    question: &str,
+) -> Result<(), String> {
+    if !io::stdin().is_terminal() {
+        return Err(format!("pass --yes to {verb} without being asked"));
+    }
+
+    if !devs.is_empty() {
+        eprintln!("Everything on these devices will be destroyed:");
+    }
+    for dev in devs {
+        eprintln!("{}", device_label(dev));
+
+        let partitions = backend.partitions(dev).map_err(|e| e.to_string())?;
+        if partitions.is_empty() {
+            eprintln!("    No partitions");
+        }
+        for partition in partitions {
+            eprintln!("    {partition}");
+        }
+    }
+
+    if !files.is_empty() {
+        eprintln!("These files will be overwritten:");
+    }
+    for file in files {
+        let size = std::fs::metadata(file).map(|m| m.len()).unwrap_or_default();
+        eprintln!("{}, {}", file.display(), transfer::bytes(size));
+    }
+
+    eprint!("{question} [y/N] ");
+    let mut answer = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .map_err(|e| e.to_string())?;
+
+    match answer.trim() {
+        "y" | "Y" | "yes" => Ok(()),
+        _ => Err(format!("did not {verb}, nothing was changed")),
+    }
+}
+
+/// How the write to one device ended, from its final progress.
+fn outcome(result: Option<Progress>, compare_only: bool) -> Result<(), String> {
+    match result {
+        Some(Progress::Finished | Progress::Ejected) => Ok(()),
+        Some(Progress::Mismatch) if compare_only => {
+            Err("the device does not match the image".into())
+        }
+        Some(Progress::Mismatch) => Err("the device does not match the image after writing".into()),
+        Some(Progress::ChecksumMismatch) => Err("the image does not match its checksum".into()),
+        Some(Progress::Cancelled) if compare_only => {
+            Err("cancelled before the comparison ended".into())
+        }
+        Some(Progress::Cancelled) => {
+            Err("cancelled, the device is partly written and in an undefined state".into())
+        }
+        Some(Progress::Errored(e)) => Err(e.to_string()),
+        _ => Err("writing the image stopped unexpectedly".into()),
+    }
+}
+
+/// Prints the progress on `device`. Without JSON the device is only named if
+/// there are `several`.
+fn print(json: bool, device: &str, several: bool, progress: &Progress) {
+    if json {
+        println!("{}", json_line(device, progress));
+        return;
+    }
+
+    let prefix = match several {
+        true => format!("{device}: "),
+        false => String::new(),
+    };
+
+    let mut stdout = io::stdout();
+    let _ = match progress {
+        Progress::Started if several => Ok(()),
+        Progress::Started => write!(stdout, "Writing..."),
+        Progress::Advanced(t) => write!(
+            stdout,
+            "\r{:<80}",
+            format!("{prefix}Writing    {:5.1}%  {t}", t.percentage)
+        ),
+        Progress::Verifying(t) => write!(
+            stdout,
+            "\r{:<80}",
+            format!("{prefix}Verifying  {:5.1}%  {t}", t.percentage)
+        ),
+        Progress::Finished => writeln!(stdout, "\r{:<80}", format!("{prefix}Done.")),
+        Progress::Ejected => writeln!(stdout, "\r{:<80}", format!("{prefix}Done, safe to remove.")),
+        _ if several => writeln!(stdout, "\r{:<80}", format!("{prefix}Failed.")),
+        _ => writeln!(stdout),
+    };
+    let _ = stdout.flush();
+}
+
+/// The progress on `device` as a line of JSON, with the message of an error
+/// next to the error itself.
+fn json_line(device: &str, progress: &Progress) -> String {
+    match progress {
+        Progress::Errored(e) => json!({
+            "device": device,
+            "event": "errored",
+            "error": e,
+            "message": e.to_string()
+        })
+        .to_string(),
+        p => {
+            let mut line = json!(p);
+            line["device"] = json!(device);
+            line.to_string()
+        }
+    }
+}
+
+/// Looks a device up by its id or device node.
+fn find_device(backend: &dyn Backend, name: &str) -> Result<DiskDevice, String> {
+    let devices = backend.list_devices().map_err(|e| e.to_string())?;
+
+    devices
+        .get(name)
+        .or_else(|| {
+            devices
+                .values()
+                .find(|dev| dev.parent.device == Path::new(name))
+        })
+        .cloned()
+        .ok_or_else(|| format!("no device called {name}, see list-devices"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use clap::Parser;
+    use linux_creation_tool::transfer::Transfer;
+    use linux_creation_tool::{OperatingSystemList, Progress, Source, WriteError};
+    use serde_json::{json, Value};
+    use sha2::{Digest, Sha512};
+    use tempfile::TempDir;
+
+    use super::{json_line, run, source, Cli};
+
+    /// Runs the command line `args` with the sysfs backend and no catalog,
+    /// printing JSON.
+    fn cli(dir: &TempDir, args: &[&str]) -> Result<(), String> {
+        let config = dir.path().join("config.json");
+        let mut line = vec![
+            "linux_creation_tool",
+            "--json",
+            "--backend",
+            "sysfs",
+            "--config",
+        ];
+        line.push(config.to_str().unwrap());
+        line.extend(args);
+
+        run(Cli::try_parse_from(line).unwrap())
+    }
+
+    #[test]
+    fn prints_one_json_object_per_line() {
+        let line = json_line("/dev/sdb", &Progress::Finished);
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value, json!({ "device": "/dev/sdb", "event": "finished" }));
+
+        let transfer = Transfer {
+            done: 1024,
+            total: 4096,
+            ..Default::default()
+        };
+        let line = json_line("sdb.img", &Progress::Verifying(transfer));
+        assert!(!line.contains('\n'));
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["device"], "sdb.img");
+        assert_eq!(value["event"], "verifying");
+        assert_eq!(value["transfer"]["done"], 1024);
+        assert_eq!(value["transfer"]["total"], 4096);
+
+        let error = WriteError::Sync {
+            cause: "Input/output error".into(),
+        };
+        let line = json_line("/dev/sdb", &Progress::Errored(error.clone()));
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["event"], "errored");
+        assert_eq!(value["error"]["kind"], "sync");
+        assert_eq!(value["error"]["cause"], "Input/output error");
+        assert_eq!(value["message"], error.to_string());
+    }
+
+    #[test]
+    fn takes_a_source_from_the_catalog_or_as_a_path_or_url() {
+        let catalog: OperatingSystemList = serde_json::from_value(json!({
+            "os": [{
+                "name": "Fedora",
+                "source": { "Url": "https://example.org/fedora.iso" },
+                "pic": { "File": "pictures/fedora.png" },
+                "sha256": "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+                "member": "fedora.img"
+            }]
+        }))
+        .unwrap();
+        let name = |source: &Source| match source {
+            Source::Url(s) => format!("url {s}"),
+            Source::File(s) => format!("file {s}"),
+            Source::Metalink(s) => format!("metalink {s}"),
+            Source::Mirrors(s) => format!("mirrors {}", s.join(" ")),
+        };
+
+        let (fedora, options) = source(&catalog, "Fedora".into());
+        assert_eq!(name(&fedora), "url https://example.org/fedora.iso");
+        assert!(options.checksum.sha256.is_some());
+        assert_eq!(options.member.as_deref(), Some("fedora.img"));
+
+        for (arg, expected) in [
+            (
+                "https://example.org/os.img.xz",
+                "url https://example.org/os.img.xz",
+            ),
+            (
+                "http://example.org/os.meta4",
+                "metalink http://example.org/os.meta4",
+            ),
+            (
+                "https://example.org/os.metalink",
+                "metalink https://example.org/os.metalink",
+            ),
+            ("fedora", "file fedora"),
+            ("ftp://example.org/os.img", "file ftp://example.org/os.img"),
+            ("/tmp/os.img", "file /tmp/os.img"),
+        ] {
+            let (found, options) = source(&catalog, arg.into());
+            assert_eq!(name(&found), expected);
+            assert!(!options.checksum.is_set());
+        }
+    }
+
+    #[test]
+    fn fails_when_a_device_does_not_match() {
+        let dir = TempDir::new().unwrap();
+        let image = dir.path().join("os.img");
+        let target = dir.path().join("stick.img");
+        let (image, target) = (image.to_str().unwrap(), target.to_str().unwrap());
+        fs::write(image, vec![7; 1 << 20]).unwrap();
+
+        fs::write(target, vec![7; 1 << 20]).unwrap();
+        cli(&dir, &["verify", "--file", image, target]).unwrap();
+
+        fs::write(target, vec![8; 1 << 20]).unwrap();
+        let e = cli(&dir, &["verify", "--file", image, target]).unwrap_err();
+        assert_eq!(e, "the device does not match the image");
+
+        let e = cli(&dir, &["verify", "--file", image, target, image]).unwrap_err();
+        assert_eq!(e, format!("{target}: the device does not match the image"));
+    }
+
+    #[test]
+    fn fails_when_the_image_does_not_match_its_checksum() {
+        let dir = TempDir::new().unwrap();
+        let image = dir.path().join("os.img");
+        let target = dir.path().join("stick.img");
+        let (image, target) = (image.to_str().unwrap(), target.to_str().unwrap());
+        fs::write(image, vec![7; 1 << 20]).unwrap();
+
+        let other = format!("{:x}", Sha512::digest(vec![8; 1 << 20]));
+        let args = [
+            "write",
+            "--file",
+            "--yes",
+            "--checksum",
+            &other,
+            image,
+            target,
+        ];
+        let e = cli(&dir, &args).unwrap_err();
+        assert_eq!(e, "the image does not match its checksum");
+
+        let right = format!("{:x}", Sha512::digest(vec![7; 1 << 20]));
+        let args = [
+            "write",
+            "--file",
+            "--yes",
+            "--checksum",
+            &right,
+            image,
+            target,
+        ];
+        cli(&dir, &args).unwrap();
+        assert!(fs::read(target).unwrap() == vec![7; 1 << 20]);
+
+        let args = [
+            "write",
+            "--file",
+            "--yes",
+            "--checksum",
+            "abc",
+            image,
+            target,
+        ];
+        let e = cli(&dir, &args).unwrap_err();
+        assert!(e.starts_with("--checksum"), "{e}");
+    }
+}
@@ -0,0 +1,359 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use bzip2::write::BzDecoder;
+use flate2::write::GzDecoder;
+use xz2::write::XzDecoder;
+
+use crate::archive::{ZipExtractor, ZIP_MAGIC};
+
+const XZ_MAGIC: [u8; 6] = [0xFD, b'7', b'z', b'X', b'Z', 0x00];
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+const BZIP2_MAGIC: [u8; 3] = [b'B', b'Z', b'h'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Xz,
+    Gzip,
+    Zstd,
+    Bzip2,
+    Zip,
+}
+
+impl Compression {
+    /// Detects the container from the first bytes of the image, falling back
+    /// to the file extension if there are too few of them.
+    pub fn detect(head: &[u8], name: &str) -> Self {
+        if head.len() >= XZ_MAGIC.len() {
+            return match head {
+                h if h.starts_with(&XZ_MAGIC) => Compression::Xz,
+                h if h.starts_with(&GZIP_MAGIC) => Compression::Gzip,
+                h if h.starts_with(&ZSTD_MAGIC) => Compression::Zstd,
+                h if h.starts_with(&BZIP2_MAGIC) => Compression::Bzip2,
+                h if h.starts_with(&ZIP_MAGIC) => Compression::Zip,
+                _ => Compression::None,
+            };
+        }
+
+        match name.rsplit('.').next() {
+            Some("xz") => Compression::Xz,
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            Some("bz2") => Compression::Bzip2,
+            Some("zip") => Compression::Zip,
+            _ => Compression::None,
+        }
+    }
+}
+
+/// Decompresses everything written to it into `W`. Zip archives are unpacked
+/// to the image `member` they hold.
+pub enum Decoder<W: Write> {
+    Raw(W),
+    Xz(XzDecoder<W>),
+    Gzip(GzDecoder<W>),
+    Zstd(zstd::stream::write::Decoder<'static, W>),
+    Bzip2(BzDecoder<W>),
+    Zip(ZipExtractor<W>),
+}
+
+impl<W: Write> Decoder<W> {
+    pub fn new(compression: Compression, inner: W, member: Option<String>) -> io::Result<Self> {
+        Ok(match compression {
+            Compression::None => Decoder::Raw(inner),
+            Compression::Xz => Decoder::Xz(XzDecoder::new_multi_decoder(inner)),
+            Compression::Gzip => Decoder::Gzip(GzDecoder::new(inner)),
+            Compression::Zstd => Decoder::Zstd(zstd::stream::write::Decoder::new(inner)?),
+            Compression::Bzip2 => Decoder::Bzip2(BzDecoder::new(inner)),
+            Compression::Zip => Decoder::Zip(ZipExtractor::new(inner, member)),
+        })
+    }
+
+    pub fn get_ref(&self) -> &W {
+        match self {
+            Decoder::Raw(w) => w,
+            Decoder::Xz(d) => d.get_ref(),
+            Decoder::Gzip(d) => d.get_ref(),
+            Decoder::Zstd(d) => d.get_ref(),
+            Decoder::Bzip2(d) => d.get_ref(),
+            Decoder::Zip(d) => d.get_ref(),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        match self {
+            Decoder::Raw(w) => w,
+            Decoder::Xz(d) => d.get_mut(),
+            Decoder::Gzip(d) => d.get_mut(),
+            Decoder::Zstd(d) => d.get_mut(),
+            Decoder::Bzip2(d) => d.get_mut(),
+            Decoder::Zip(d) => d.get_mut(),
+        }
+    }
+
+    /// Flushes the rest of the decompressed data and returns the inner writer.
+    /// Truncated xz, gzip, bzip2 and zip streams are reported as errors.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Decoder::Raw(w) => Ok(w),
+            Decoder::Xz(mut d) => d.finish(),
+            Decoder::Gzip(d) => d.finish(),
+            Decoder::Zstd(mut d) => {
+                d.flush()?;
+                Ok(d.into_inner())
+            }
+            Decoder::Bzip2(mut d) => d.finish(),
+            Decoder::Zip(d) => d.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Decoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Decoder::Raw(w) => w.write(buf),
+            Decoder::Xz(d) => d.write(buf),
+            Decoder::Gzip(d) => d.write(buf),
+            Decoder::Zstd(d) => d.write(buf),
+            Decoder::Bzip2(d) => d.write(buf),
+            Decoder::Zip(d) => d.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Decoder::Raw(w) => w.flush(),
+            Decoder::Xz(d) => d.flush(),
+            Decoder::Gzip(d) => d.flush(),
+            Decoder::Zstd(d) => d.flush(),
+            Decoder::Bzip2(d) => d.flush(),
+            Decoder::Zip(d) => d.flush(),
+        }
+    }
+}
+
+/// Reads the uncompressed size an xz file records in the indexes of its
+/// streams. The reader is left at an unspecified position.
+pub fn xz_uncompressed_size<R: Read + Seek>(reader: &mut R) -> Option<u64> {
+    const FOOTER: u64 = 12;
+    const HEADER: u64 = 12;
+
+    let mut end = reader.seek(SeekFrom::End(0)).ok()?;
+    let mut total = 0u64;
+
+    // The streams are found from the last one back, each from its footer.
+    while end > 0 {
+        let mut footer = [0; FOOTER as usize];
+        reader
+            .seek(SeekFrom::Start(end.checked_sub(FOOTER)?))
+            .ok()?;
+        reader.read_exact(&mut footer).ok()?;
+
+        // Streams may be followed by padding of four null bytes at a time.
+        if footer[8..] == [0; 4] {
+            end -= 4;
+            continue;
+        }
+        if footer[10..] != *b"YZ" {
+            return None;
+        }
+
+        let backward_size = (u32::from_le_bytes(footer[4..8].try_into().ok()?) as u64 + 1) * 4;
+        let index_start = end.checked_sub(FOOTER + backward_size)?;
+        let mut index = vec![0; backward_size as usize];
+        reader.seek(SeekFrom::Start(index_start)).ok()?;
+        reader.read_exact(&mut index).ok()?;
+
+        let (blocks, size) = read_index(&index)?;
+        total = total.checked_add(size)?;
+
+        end = index_start.checked_sub(blocks)?.checked_sub(HEADER)?;
+        let mut magic = [0; XZ_MAGIC.len()];
+        reader.seek(SeekFrom::Start(end)).ok()?;
+        reader.read_exact(&mut magic).ok()?;
+        if magic != XZ_MAGIC {
+            return None;
+        }
+    }
+
+    Some(total)
+}
+
+/// The size the blocks of a stream take up and the size they decompress to,
+/// from the stream's index.
+fn read_index(index: &[u8]) -> Option<(u64, u64)> {
+    if index.first() != Some(&0) {
+        return None;
+    }
+
+    let mut pos = 1;
+    let records = read_varint(index, &mut pos)?;
+
+    let (mut blocks, mut size) = (0u64, 0u64);
+    for _ in 0..records {
+        // Blocks are padded to a multiple of four bytes.
+        let unpadded = read_varint(index, &mut pos)?;
+        blocks = blocks.checked_add(unpadded.checked_add(3)? & !3)?;
+        size = size.checked_add(read_varint(index, &mut pos)?)?;
+    }
+
+    Some((blocks, size))
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0;
+
+    for i in 0..9 {
+        let byte = *buf.get(*pos)?;
+        *pos += 1;
+
+        value |= ((byte & 0x7F) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use bzip2::write::BzEncoder;
+    use flate2::write::GzEncoder;
+    use xz2::stream::{Action, Check, Status, Stream};
+    use xz2::write::XzEncoder;
+
+    use super::{xz_uncompressed_size, Compression, Decoder};
+
+    fn data() -> Vec<u8> {
+        (0..1 << 20).map(|i: u32| (i * 7 % 253) as u8).collect()
+    }
+
+    fn xz(data: &[u8]) -> Vec<u8> {
+        let mut encoder = XzEncoder::new(vec![], 1);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// One xz stream with a block for each of `parts`.
+    fn xz_blocks(parts: &[&[u8]]) -> Vec<u8> {
+        let mut stream = Stream::new_easy_encoder(1, Check::Crc64).unwrap();
+        let mut out = vec![];
+
+        for (i, part) in parts.iter().enumerate() {
+            // A full flush ends the block, the next data starts another.
+            let action = match i + 1 == parts.len() {
+                true => Action::Finish,
+                false => Action::FullFlush,
+            };
+
+            let mut input = *part;
+            loop {
+                out.reserve(1 << 16);
+                let before = stream.total_in();
+                let status = stream.process_vec(input, &mut out, action).unwrap();
+                input = &input[(stream.total_in() - before) as usize..];
+                if status == Status::StreamEnd {
+                    break;
+                }
+            }
+        }
+
+        out
+    }
+
+    /// Writes `compressed` to a decoder in small pieces, as the source is
+    /// read, and returns what came out.
+    fn decode(compressed: &[u8], name: &str) -> std::io::Result<Vec<u8>> {
+        let compression = Compression::detect(&compressed[..6], name);
+        let mut decoder = Decoder::new(compression, vec![], None)?;
+        for chunk in compressed.chunks(4099) {
+            decoder.write_all(chunk)?;
+        }
+        decoder.finish()
+    }
+
+    #[test]
+    fn decompresses_all_formats() {
+        let data = data();
+
+        let mut gzip = GzEncoder::new(vec![], flate2::Compression::fast());
+        gzip.write_all(&data).unwrap();
+        let mut bzip2 = BzEncoder::new(vec![], bzip2::Compression::fast());
+        bzip2.write_all(&data).unwrap();
+
+        for (compressed, name) in [
+            (xz(&data), "image.img.xz"),
+            (gzip.finish().unwrap(), "image.img.gz"),
+            (zstd::encode_all(&data[..], 1).unwrap(), "image.img.zst"),
+            (bzip2.finish().unwrap(), "image.img.bz2"),
+            (data.clone(), "image.img"),
+        ] {
+            assert!(decode(&compressed, name).unwrap() == data, "{name}");
+        }
+    }
+
+    #[test]
+    fn refuses_truncated_streams() {
+        let data = data();
+        let compressed = xz(&data);
+
+        let truncated = &compressed[..compressed.len() / 2];
+        assert!(decode(truncated, "image.img.xz").is_err());
+    }
+
+    #[test]
+    fn detects_the_compression_by_magic_before_the_extension() {
+        let xz = xz(b"image");
+        assert_eq!(
+            Compression::detect(&xz[..6], "image.img.gz"),
+            Compression::Xz
+        );
+        assert_eq!(
+            Compression::detect(b"PK\x03\x04\x14\x00", "image.img"),
+            Compression::Zip
+        );
+        assert_eq!(
+            Compression::detect(b"\x00\x00\x00\x00\x00\x00", "image.img.xz"),
+            Compression::None
+        );
+
+        // Too short to tell, e.g. an empty first chunk of a download.
+        assert_eq!(Compression::detect(b"", "image.img.zst"), Compression::Zstd);
+        assert_eq!(
+            Compression::detect(b"\x1f\x8b", "image.img.bz2"),
+            Compression::Bzip2
+        );
+        assert_eq!(Compression::detect(b"", "image.iso"), Compression::None);
+    }
+
+    #[test]
+    fn reads_the_size_from_xz_indexes() {
+        let data = data();
+        let size = |file: &[u8]| xz_uncompressed_size(&mut Cursor::new(file));
+
+        assert_eq!(size(&xz(&data)), Some(data.len() as u64));
+
+        let blocks = xz_blocks(&[&data[..1000], &data[1000..300_000], &data[300_000..]]);
+        assert_eq!(size(&blocks), Some(data.len() as u64));
+
+        // Streams one after another, with padding between and after them.
+        let mut streams = xz(&data);
+        streams.extend([0; 8]);
+        streams.extend(xz(&data[..12345]));
+        streams.extend([0; 4]);
+        assert_eq!(size(&streams), Some(data.len() as u64 + 12345));
+
+        // The index is cut short, or the file isn't xz at all.
+        let file = xz(&data);
+        let mut cut = file[..file.len() - 16].to_vec();
+        cut.extend(&file[file.len() - 12..]);
+        assert_eq!(size(&cut), None);
+        assert_eq!(size(&file[..file.len() - 1]), None);
+        assert_eq!(size(&data), None);
+        assert_eq!(size(b"YZ"), None);
+    }
+}
@@ -1,117 +1,1061 @@
-use dbus_udisks2::DiskDevice;
-use iced::subscription;
+use bytes::Bytes;
+use reqwest::header::{CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE};
 use reqwest::Client;
 use reqwest::Response;
+use reqwest::StatusCode;
+use tokio::time;
 
 use std::cmp::min;
-use std::fs;
+use std::collections::VecDeque;
 use std::fs::File;
-use std::hash::Hash;
-use std::io;
-use std::io::Seek;
-use std::io::Write;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-#[cfg(target_os = "linux")]
-use crate::linux::udisks_open;
+use crate::backend::Backend;
+use crate::bmap::{Bmap, BmapWriter};
+use crate::cache::Entry;
+use crate::checksum::{self, Checksum, Hasher};
+use crate::decompress::{Compression, Decoder};
+use crate::fanout::Fanout;
+use crate::target::Target;
+use crate::writer::{self, Cancel};
+use crate::{Progress, Report, WriteError, WriteOptions};
 
-use crate::Progress;
+/// Failures in a row, to connect or to go on reading, before a download is
+/// given up.
+const RETRIES: u32 = 5;
+/// Wait before the first retry, doubled for each further one.
+#[cfg(not(test))]
+const BACKOFF: Duration = Duration::from_secs(1);
+#[cfg(test)]
+const BACKOFF: Duration = Duration::from_millis(10);
+/// How long the server may stay silent before the connection counts as lost.
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often the rate of a mirror is checked.
+const PACE_WINDOW: Duration = Duration::from_secs(20);
+/// A mirror that gets this many times slower than the best rate so far is
+/// replaced by the next one.
+const SLOW_FACTOR: f64 = 4.0;
 
-pub fn file<I: 'static + Hash + Copy + Send + Sync, T: ToString>(
-    id: I,
-    url: T,
-    dev: DiskDevice,
-    client: Client,
-) -> iced::Subscription<(I, Progress)> {
-    subscription::unfold(
-        id,
-        State::Ready(url.to_string(), Box::new(dev), client),
-        move |state| download(id, state),
-    )
-}
+/// Advances the write by one step. Returns the next state, or `None` once
+/// the write has ended on every device or it was cancelled.
+pub async fn download(state: State, cancel: &Cancel) -> (Vec<Report>, Option<State>) {
+    match state {
+        State::Ready {
+            urls,
+            size,
+            targets,
+            backend,
+            client,
+            options,
+            mut cache,
+        } => {
+            let name = urls.first().map_or("", |url| checksum::file_name(url));
+            let mut checksum = match options.checksum.resolve(&client, name).await {
+                Ok(checksum) => checksum.map(|c| {
+                    let hasher = c.hasher();
+                    (c, hasher)
+                }),
+                Err(e) => {
+                    return failed(WriteError::Checksum {
+                        cause: e.to_string(),
+                    })
+                }
+            };
 
-#[derive(Debug, Hash, Clone)]
-pub struct Download<I> {
-    id: I,
-    url: String,
-}
+            let bmap = match &options.bmap {
+                Some(source) => match Bmap::load(source, &client).await {
+                    Ok(bmap) => Some(bmap),
+                    Err(e) => {
+                        return failed(WriteError::Bmap {
+                            cause: e.to_string(),
+                        })
+                    }
+                },
+                None => None,
+            };
 
-async fn download<I: Copy>(id: I, state: State) -> (Option<(I, Progress)>, State) {
-    match state {
-        State::Ready(url, dev, client) => {
-            let response = client.get(&url).send().await;
-
-            match response {
-                Ok(response) => {
-                    if let Some(total) = response.content_length() {
-                        let mut file = match udisks_open(&dev.parent.path) {
-                            Ok(f) => f,
-                            Err(_) => return (Some((id, Progress::Errored)), State::Finished),
-                        };
-
-                        let file_size = match fs::metadata(&dev.parent.device) {
-                            Ok(x) => x.len(),
-                            Err(_) => return (Some((id, Progress::Errored)), State::Finished),
-                        };
-
-                        if file.seek(io::SeekFrom::Start(file_size)).is_err() {
-                            return (Some((id, Progress::Errored)), State::Finished);
-                        };
-
-                        (
-                            Some((id, Progress::Started)),
-                            State::Downloading {
-                                response,
-                                file,
-                                total,
-                                downloaded: 0,
-                            },
-                        )
-                    } else {
-                        (Some((id, Progress::Errored)), State::Finished)
+            let mut mirrors = VecDeque::from(urls);
+
+            // Pick up what an earlier run downloaded into the cache, if the
+            // image is still the same. That is handed to the devices first.
+            let resumed = match &cache {
+                Some(entry) => resume(&client, entry, size).await,
+                None => None,
+            };
+
+            let (url, response, first, validator, replay, skip) = match resumed {
+                Some(resumed) => resumed,
+                None => {
+                    // Go down the mirrors until one of them serves the image.
+                    let mut started = Err(WriteError::Request {
+                        cause: "there is no mirror to download from".into(),
+                    });
+                    while let Some(url) = mirrors.pop_front() {
+                        started = start(&client, &url, size)
+                            .await
+                            .map(|(response, first)| (url, response, first));
+                        if started.is_ok() {
+                            break;
+                        }
                     }
+
+                    let (url, response, first) = match started {
+                        Ok(started) => started,
+                        Err(e) => return failed(e),
+                    };
+
+                    let validator = validator_of(&response);
+                    (url, response, first, validator, None, 0)
                 }
-                Err(_) => (Some((id, Progress::Errored)), State::Finished),
+            };
+
+            let total = match full_length(&response) {
+                Some(total) => total,
+                None => return failed(WriteError::NoContentLength),
+            };
+
+            // Only a checksum tells whether the bytes of two mirrors make up
+            // one image, without it the download stays with this one.
+            if checksum.is_none() {
+                mirrors.clear();
             }
+
+            // The first chunk tells whether the image is compressed.
+            let compression = Compression::detect(&first, checksum::file_name(&url));
+
+            let image = match compression {
+                Compression::None => Some(total),
+                _ => None,
+            }
+            .or(bmap.as_ref().map(Bmap::image_size))
+            .or(options.size);
+
+            let mut sink = match Decoder::new(
+                compression,
+                BmapWriter::new(Fanout::open(targets, &backend, &options, image), bmap),
+                options.member,
+            ) {
+                Ok(sink) => Box::new(sink),
+                Err(e) => return failed(WriteError::from_sink(e, 0)),
+            };
+
+            // What was read back from the cache is in it already.
+            let restart = replay.is_none().then(|| (url.clone(), validator.clone()));
+            let downloaded = first.len() as u64;
+            let fed = writer::blocking(move || {
+                let entry = match (&mut cache, &restart) {
+                    (Some(entry), Some((url, validator))) => {
+                        entry.restart(url, validator.as_deref());
+                        Some(entry)
+                    }
+                    _ => None,
+                };
+                let fed = feed(&mut sink, &mut checksum, entry, &first, 0);
+                (sink, checksum, cache, fed)
+            });
+            let (sink, checksum, cache) = match fed.await {
+                (sink, checksum, cache, Ok(())) => (sink, checksum, cache),
+                (.., Err(e)) => return failed(e),
+            };
+
+            (
+                vec![Report::all(Progress::Started)],
+                Some(State::Downloading {
+                    response,
+                    url,
+                    mirrors,
+                    client,
+                    validator,
+                    sink,
+                    checksum,
+                    cache,
+                    total,
+                    downloaded,
+                    replay,
+                    skip,
+                    retries: 0,
+                    pace: Pace::new(downloaded),
+                }),
+            )
         }
         State::Downloading {
             mut response,
-            mut file,
+            mut url,
+            mut mirrors,
+            client,
+            mut validator,
+            mut sink,
+            mut checksum,
+            mut cache,
             total,
             downloaded,
-        } => match response.chunk().await {
-            Ok(None) => (Some((id, Progress::Finished)), State::Finished),
-            Ok(Some(chunk)) => {
-                if file.write_all(&chunk).is_ok() {
-                    let new = min(downloaded + (chunk.len() as u64), total);
+            mut replay,
+            mut skip,
+            mut retries,
+            mut pace,
+        } => {
+            if let Some(mut file) = replay {
+                let fed = writer::blocking(move || {
+                    let fed = replay_chunk(&mut file, &mut sink, &mut checksum, downloaded);
+                    (file, sink, checksum, fed)
+                });
+                let size;
+                (replay, sink, checksum, size) = match fed.await {
+                    (file, sink, checksum, Ok(size)) => (Some(file), sink, checksum, size),
+                    (.., Err(e)) => return failed(e),
+                };
 
-                    let percentage = (new as f32 / total as f32) * 100.0;
+                // Once it is all handed out, the download carries on.
+                if size == 0 {
+                    replay = None;
+                } else {
+                    let new = downloaded + size as u64;
+                    let fanout = sink.get_mut().get_mut();
+                    if cancel.until(fanout.drain()).await.is_none() {
+                        return cancelled(fanout).await;
+                    }
+                    let reports = fanout.reports(new, total, new);
+                    if fanout.is_done() {
+                        return (reports, None);
+                    }
+                    pace.restart(new);
 
-                    (
-                        Some((id, Progress::Advanced(percentage))),
-                        State::Downloading {
+                    return (
+                        reports,
+                        Some(State::Downloading {
                             response,
+                            url,
+                            mirrors,
+                            client,
+                            validator,
                             total,
                             downloaded: new,
-                            file,
-                        },
-                    )
-                } else {
-                    (Some((id, Progress::Errored)), State::Finished)
+                            replay,
+                            skip,
+                            retries,
+                            sink,
+                            checksum,
+                            cache,
+                            pace,
+                        }),
+                    );
                 }
             }
-            Err(_) => (Some((id, Progress::Errored)), State::Finished),
-        },
-        State::Finished => iced::futures::future::pending().await,
+
+            let chunk = time::timeout(CHUNK_TIMEOUT, response.chunk());
+            let chunk = match cancel.until(chunk).await {
+                Some(Ok(Ok(None))) if downloaded < total => {
+                    Err("connection closed early".to_string())
+                }
+                Some(Ok(Ok(chunk))) => Ok(chunk),
+                Some(Ok(Err(e))) => Err(e.to_string()),
+                Some(Err(_)) => Err("the server stopped sending data".to_string()),
+                None => return cancelled(sink.get_mut().get_mut()).await,
+            };
+
+            let mut chunk = match chunk {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => {
+                    if let Some((checksum, source_hasher)) = checksum {
+                        if !checksum.matches(source_hasher) {
+                            if let Some(entry) = cache {
+                                entry.discard();
+                            }
+                            let fanout = sink.get_mut().get_mut();
+                            return (fanout.stop(Progress::ChecksumMismatch).await, None);
+                        }
+                    }
+
+                    // Caching is best effort, the write goes on without it.
+                    if let Some(entry) = cache {
+                        let _ = entry.commit();
+                    }
+
+                    let finished = writer::blocking(move || {
+                        sink.finish().and_then(|bmap_writer| bmap_writer.finish())
+                    });
+                    let mut fanout = match finished.await {
+                        Ok(fanout) => fanout,
+                        Err(e) => return failed(WriteError::from_sink(e, downloaded)),
+                    };
+
+                    fanout.finish().await;
+                    return (vec![], Some(State::Finishing(fanout)));
+                }
+                // Pick up where the connection broke off.
+                Err(cause) => {
+                    retries += 1;
+                    let resumed = match retries > RETRIES {
+                        true => Err(WriteError::Download {
+                            cause,
+                            offset: downloaded,
+                        }),
+                        false => {
+                            let validator = validator.as_deref();
+                            let total = Some(total);
+                            let resumed = async {
+                                time::sleep(backoff(retries)).await;
+                                connect(&client, &url, downloaded, validator, total, &mut retries)
+                                    .await
+                            };
+                            match cancel.until(resumed).await {
+                                Some(resumed) => resumed,
+                                None => return cancelled(sink.get_mut().get_mut()).await,
+                            }
+                        }
+                    };
+
+                    // Once a mirror gives up, the next one takes over.
+                    (response, skip) = match resumed {
+                        Ok(resumed) => resumed,
+                        Err(e) => {
+                            let next = failover(&client, &mut mirrors, downloaded, total);
+                            match cancel.until(next).await {
+                                Some(Some((next, resumed, next_skip))) => {
+                                    url = next;
+                                    validator = validator_of(&resumed);
+                                    retries = 0;
+                                    (resumed, next_skip)
+                                }
+                                Some(None) => return failed(e),
+                                None => return cancelled(sink.get_mut().get_mut()).await,
+                            }
+                        }
+                    };
+                    pace.restart(downloaded);
+
+                    Bytes::new()
+                }
+            };
+
+            // A server that ignored the range sends the image from the start
+            // again.
+            let skipped = min(skip, chunk.len() as u64);
+            skip -= skipped;
+            let chunk = chunk.split_off(skipped as usize);
+
+            if !chunk.is_empty() {
+                retries = 0;
+            }
+
+            let new = min(downloaded + (chunk.len() as u64), total);
+
+            let fed = writer::blocking(move || {
+                let fed = feed(&mut sink, &mut checksum, cache.as_mut(), &chunk, downloaded);
+                (sink, checksum, cache, fed)
+            });
+            (sink, checksum, cache) = match fed.await {
+                (sink, checksum, cache, Ok(())) => (sink, checksum, cache),
+                (.., Err(e)) => return failed(e),
+            };
+
+            let fanout = sink.get_mut().get_mut();
+            if cancel.until(fanout.drain()).await.is_none() {
+                return cancelled(fanout).await;
+            }
+            let reports = fanout.reports(new, total, new);
+            if fanout.is_done() {
+                return (reports, None);
+            }
+
+            // Leave a mirror that slowed to a crawl if another one is left to
+            // take over.
+            if !mirrors.is_empty() && pace.too_slow(new) {
+                let next = match cancel
+                    .until(failover(&client, &mut mirrors, new, total))
+                    .await
+                {
+                    Some(next) => next,
+                    None => return cancelled(sink.get_mut().get_mut()).await,
+                };
+                if let Some((next, resumed, next_skip)) = next {
+                    url = next;
+                    validator = validator_of(&resumed);
+                    (response, skip) = (resumed, next_skip);
+                    retries = 0;
+                }
+                pace.restart(new);
+            }
+
+            (
+                reports,
+                Some(State::Downloading {
+                    response,
+                    url,
+                    mirrors,
+                    client,
+                    validator,
+                    total,
+                    downloaded: new,
+                    replay,
+                    skip,
+                    retries,
+                    sink,
+                    checksum,
+                    cache,
+                    pace,
+                }),
+            )
+        }
+        State::Finishing(fanout) => finishing(fanout).await,
+    }
+}
+
+/// Stops the write, flushing what was written so far to the devices.
+pub async fn cancel(state: State) -> Vec<Report> {
+    match state {
+        State::Ready { .. } => vec![Report::all(Progress::Cancelled)],
+        State::Downloading { mut sink, .. } => {
+            sink.get_mut().get_mut().stop(Progress::Cancelled).await
+        }
+        State::Finishing(mut fanout) => fanout.stop(Progress::Cancelled).await,
+    }
+}
+
+/// Stops a write that was cancelled in the middle of a step.
+async fn cancelled(fanout: &mut Fanout) -> (Vec<Report>, Option<State>) {
+    (fanout.stop(Progress::Cancelled).await, None)
+}
+
+/// Stops writing to `device`, which was unplugged.
+pub fn remove(state: &mut State, device: usize) {
+    match state {
+        State::Ready { .. } => {}
+        State::Downloading { sink, .. } => sink.get_mut().get_mut().remove(device),
+        State::Finishing(fanout) => fanout.remove(device),
     }
 }
 
 pub enum State {
-    Ready(String, Box<DiskDevice>, Client),
+    Ready {
+        /// Mirrors of the image, best first.
+        urls: Vec<String>,
+        /// The size all mirrors have to agree on, if known up front.
+        size: Option<u64>,
+        targets: Vec<Target>,
+        backend: Arc<dyn Backend>,
+        client: Client,
+        options: WriteOptions,
+        cache: Option<Entry>,
+    },
     Downloading {
         response: Response,
-        file: File,
+        url: String,
+        /// Mirrors not tried yet, to fall back on.
+        mirrors: VecDeque<String>,
+        client: Client,
+        /// ETag or Last-Modified of the image, to resume only if it is
+        /// unchanged.
+        validator: Option<String>,
+        sink: Box<Decoder<BmapWriter<Fanout>>>,
+        checksum: Option<(Checksum, Hasher)>,
+        cache: Option<Entry>,
         total: u64,
         downloaded: u64,
+        /// What an earlier run left in the cache, read back before the
+        /// response, which goes on from there.
+        replay: Option<File>,
+        /// Bytes still to drop from a response that restarted from the top.
+        skip: u64,
+        /// Reconnects since data last arrived.
+        retries: u32,
+        pace: Pace,
     },
-    Finished,
+    /// The whole image is handed to the devices, which finish writing and
+    /// read it back.
+    Finishing(Fanout),
+}
+
+async fn finishing(mut fanout: Fanout) -> (Vec<Report>, Option<State>) {
+    let reports = fanout.wait().await;
+
+    match fanout.is_done() {
+        true => (reports, None),
+        false => (reports, Some(State::Finishing(fanout))),
+    }
+}
+
+fn failed(e: WriteError) -> (Vec<Report>, Option<State>) {
+    (vec![Report::all(Progress::Errored(e))], None)
+}
+
+/// Hands `chunk`, downloaded at `offset`, to the devices and adds it to the
+/// cache `entry`.
+fn feed(
+    sink: &mut Decoder<BmapWriter<Fanout>>,
+    checksum: &mut Option<(Checksum, Hasher)>,
+    entry: Option<&mut Entry>,
+    chunk: &[u8],
+    offset: u64,
+) -> Result<(), WriteError> {
+    if let Some((_, source_hasher)) = checksum {
+        source_hasher.update(chunk);
+    }
+
+    if let Some(entry) = entry {
+        entry.write(chunk);
+    }
+
+    sink.write_all(chunk)
+        .map_err(|e| WriteError::from_sink(e, offset))
+}
+
+/// Reads the next chunk of what an earlier run left in the cache, at
+/// `offset`, and hands it to the devices. Returns its size, 0 once it is all
+/// handed out.
+fn replay_chunk(
+    file: &mut File,
+    sink: &mut Decoder<BmapWriter<Fanout>>,
+    checksum: &mut Option<(Checksum, Hasher)>,
+    offset: u64,
+) -> Result<usize, WriteError> {
+    let mut chunk = vec![0; 1 << 20];
+    let size = file.read(&mut chunk).map_err(|e| WriteError::Read {
+        cause: e.to_string(),
+        offset,
+    })?;
+
+    feed(sink, checksum, None, &chunk[..size], offset)?;
+    Ok(size)
+}
+
+/// Watches the rate of the current mirror.
+pub struct Pace {
+    since: Instant,
+    from: u64,
+    /// The best rate over a window so far, in bytes per second.
+    best: f64,
+}
+
+impl Pace {
+    fn new(offset: u64) -> Self {
+        Self {
+            since: Instant::now(),
+            from: offset,
+            best: 0.0,
+        }
+    }
+
+    /// Starts a new window, e.g. after connecting again.
+    fn restart(&mut self, offset: u64) {
+        self.since = Instant::now();
+        self.from = offset;
+    }
+
+    /// Whether the window just over was much slower than the best one.
+    fn too_slow(&mut self, offset: u64) -> bool {
+        let elapsed = self.since.elapsed();
+        if elapsed < PACE_WINDOW {
+            return false;
+        }
+
+        let rate = offset.saturating_sub(self.from) as f64 / elapsed.as_secs_f64();
+        self.best = self.best.max(rate);
+        self.restart(offset);
+
+        rate * SLOW_FACTOR < self.best
+    }
+}
+
+/// Requests the rest of a download an earlier run left in the cache `entry`,
+/// if it is still the same image, and reads back the first chunk of what it
+/// left. Returns the URL, the response, the chunk, the validator, what else
+/// it left and how much of the response to skip.
+async fn resume(
+    client: &Client,
+    entry: &Entry,
+    size: Option<u64>,
+) -> Option<(String, Response, Bytes, Option<String>, Option<File>, u64)> {
+    let (have, url, validator) = entry.resumable()?;
+    let (response, skip) = connect(client, &url, have, Some(&validator), size, &mut 0)
+        .await
+        .ok()?;
+
+    let mut replay = entry.replay().ok()?;
+    let (replay, first) = writer::blocking(move || {
+        let mut first = vec![0; min(have, 1 << 20) as usize];
+        replay.read_exact(&mut first).map(|_| (replay, first))
+    })
+    .await
+    .ok()?;
+
+    Some((
+        url,
+        response,
+        first.into(),
+        Some(validator),
+        Some(replay),
+        skip,
+    ))
+}
+
+/// Requests the image and waits for its first chunk, retrying transient
+/// failures of either. `size` is the size the image has to have, if known.
+async fn start(
+    client: &Client,
+    url: &str,
+    size: Option<u64>,
+) -> Result<(Response, Bytes), WriteError> {
+    let mut retries = 0;
+
+    loop {
+        let (mut response, _) = connect(client, url, 0, None, size, &mut retries).await?;
+
+        let cause = match time::timeout(CHUNK_TIMEOUT, response.chunk()).await {
+            Ok(Ok(chunk)) => return Ok((response, chunk.unwrap_or_default())),
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => "the server stopped sending data".to_string(),
+        };
+
+        retries += 1;
+        if retries > RETRIES {
+            return Err(WriteError::Download { cause, offset: 0 });
+        }
+        time::sleep(backoff(retries)).await;
+    }
+}
+
+/// Moves on to the first of `mirrors` that serves the image from `offset` on,
+/// dropping the ones that fail.
+async fn failover(
+    client: &Client,
+    mirrors: &mut VecDeque<String>,
+    offset: u64,
+    total: u64,
+) -> Option<(String, Response, u64)> {
+    while let Some(url) = mirrors.pop_front() {
+        let connected = connect(client, &url, offset, None, Some(total), &mut 0).await;
+        if let Ok((response, skip)) = connected {
+            return Some((url, response, skip));
+        }
+    }
+
+    None
+}
+
+/// Requests the image from `offset` on, retrying transient failures with
+/// exponential backoff. Also returns how many bytes at the start of the
+/// response to skip, for servers that don't support ranges. `retries` are
+/// the failures in a row so far, which the caller's count towards as well.
+async fn connect(
+    client: &Client,
+    url: &str,
+    offset: u64,
+    validator: Option<&str>,
+    total: Option<u64>,
+    retries: &mut u32,
+) -> Result<(Response, u64), WriteError> {
+    loop {
+        let cause = match request(client, url, offset, validator, total).await {
+            Ok(response) => return Ok(response),
+            Err(Failure::Fatal(e)) => return Err(e),
+            Err(Failure::Transient(cause)) => cause,
+        };
+
+        *retries += 1;
+        if *retries > RETRIES {
+            return Err(match offset {
+                0 => WriteError::Request { cause },
+                offset => WriteError::Download { cause, offset },
+            });
+        }
+        time::sleep(backoff(*retries)).await;
+    }
+}
+
+enum Failure {
+    /// Worth trying again, like a dropped connection or a 503.
+    Transient(String),
+    Fatal(WriteError),
+}
+
+/// `validator` is only checked if there is one, so a new mirror can take over
+/// as long as it serves an image of the same `total` size.
+async fn request(
+    client: &Client,
+    url: &str,
+    offset: u64,
+    validator: Option<&str>,
+    total: Option<u64>,
+) -> Result<(Response, u64), Failure> {
+    let mut request = client.get(url);
+    if offset > 0 {
+        request = request.header(RANGE, format!("bytes={offset}-"));
+        if let Some(validator) = validator {
+            request = request.header(IF_RANGE, validator);
+        }
+    }
+
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(e) if e.is_builder() => {
+            return Err(Failure::Fatal(WriteError::Request {
+                cause: e.to_string(),
+            }))
+        }
+        Err(e) => return Err(Failure::Transient(e.to_string())),
+    };
+
+    let status = response.status();
+    if status.is_success() && total.is_some() && full_length(&response) != total {
+        return Err(Failure::Fatal(WriteError::Download {
+            cause: "the server has an image of a different size".into(),
+            offset,
+        }));
+    }
+
+    match status {
+        StatusCode::PARTIAL_CONTENT => match content_range_start(&response) {
+            // A server that ignores If-Range may send part of another image.
+            _ if validator.is_some_and(|validator| {
+                validator_of(&response).is_some_and(|now| now != validator)
+            }) =>
+            {
+                Err(Failure::Fatal(WriteError::Download {
+                    cause: "the image changed on the server".into(),
+                    offset,
+                }))
+            }
+            Some(start) if start == offset => Ok((response, 0)),
+            _ => Err(Failure::Fatal(WriteError::Download {
+                cause: "the server resumed at the wrong offset".into(),
+                offset,
+            })),
+        },
+        // Either ranges are not supported or the image changed, in which
+        // case If-Range makes the server send all of the new one.
+        s if s.is_success() => {
+            let changed = validator.is_some() && validator != validator_of(&response).as_deref();
+            match offset > 0 && changed {
+                true => Err(Failure::Fatal(WriteError::Download {
+                    cause: "the image changed on the server".into(),
+                    offset,
+                })),
+                false => Ok((response, offset)),
+            }
+        }
+        s if s.is_server_error()
+            || s == StatusCode::REQUEST_TIMEOUT
+            || s == StatusCode::TOO_MANY_REQUESTS =>
+        {
+            Err(Failure::Transient(format!("HTTP {s}")))
+        }
+        s => Err(Failure::Fatal(WriteError::Request {
+            cause: format!("HTTP {s}"),
+        })),
+    }
+}
+
+/// The ETag, or failing that the Last-Modified date, of a response. Weak
+/// ETags can't be used with If-Range.
+fn validator_of(response: &Response) -> Option<String> {
+    let headers = response.headers();
+
+    headers
+        .get(ETAG)
+        .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+        .or_else(|| headers.get(LAST_MODIFIED))
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+/// The first byte of a `Content-Range: bytes <first>-<last>/<total>` header.
+fn content_range_start(response: &Response) -> Option<u64> {
+    let range = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    let (first, _) = range.strip_prefix("bytes ")?.split_once('-')?;
+
+    first.trim().parse().ok()
+}
+
+/// The size of the whole image a response is part of. Unlike
+/// `Response::content_length`, this doesn't shrink as the body is read.
+fn full_length(response: &Response) -> Option<u64> {
+    let headers = response.headers();
+
+    let length = match response.status() {
+        StatusCode::PARTIAL_CONTENT => {
+            headers
+                .get(CONTENT_RANGE)?
+                .to_str()
+                .ok()?
+                .rsplit_once('/')?
+                .1
+        }
+        _ => headers.get(CONTENT_LENGTH)?.to_str().ok()?,
+    };
+
+    length.trim().parse().ok()
+}
+
+fn backoff(retries: u32) -> Duration {
+    BACKOFF * 2u32.pow(retries.saturating_sub(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use futures::StreamExt;
+    use reqwest::Client;
+    use sha2::{Digest, Sha256};
+    use tempfile::TempDir;
+
+    use super::RETRIES;
+    use crate::backend::{Backend, Mock};
+    use crate::cache::Cache;
+    use crate::checksum::Checksum;
+    use crate::target::Target;
+    use crate::testing::{image, run, stick, Served, Server};
+    use crate::writer::Job;
+    use crate::{Progress, Source, WriteError, WriteOptions};
+
+    /// A job downloading `/image.img` to a new stick, and the file backing
+    /// the stick.
+    fn job(server: &Server, cache: &Cache, dir: &Path, name: &str) -> (Job, std::path::PathBuf) {
+        let mock = Arc::new(Mock::new());
+        let (dev, backing) = stick(dir, name, 4 << 20);
+        mock.plug(dev.clone(), &backing);
+        let backend: Arc<dyn Backend> = mock;
+
+        let source = Source::Url(server.url("/image.img"));
+        let targets = vec![Target::Device(Box::new(dev))];
+        let job = Job::new(source, targets, Client::new(), WriteOptions::default())
+            .with_backend(backend)
+            .with_cache(cache.clone());
+        (job, backing)
+    }
+
+    /// Serves `data` of version `etag`, cutting every connection after 1 MiB,
+    /// and runs a job that is cancelled then. It leaves the download unfinished
+    /// in `cache`.
+    async fn interrupt(server: &Server, cache: &Cache, dir: &Path, data: &[u8]) {
+        server.serve(
+            "/image.img",
+            Served {
+                body: data.to_vec(),
+                etag: Some("\"v1\"".into()),
+                cut: Some(1 << 20),
+                stall: true,
+                ..Default::default()
+            },
+        );
+
+        let (job, _) = job(server, cache, dir, "sdb");
+        let cancel = job.cancel_handle();
+        let mut reports = job.run();
+        while let Some(report) = reports.next().await {
+            if let Progress::Advanced(transfer) = report.progress {
+                if transfer.done >= 1 << 20 {
+                    cancel.cancel();
+                }
+            }
+        }
+    }
+
+    fn partial_size(dir: &Path) -> Option<u64> {
+        fs::read_dir(dir)
+            .unwrap()
+            .flatten()
+            .find(|entry| entry.file_name().to_string_lossy().ends_with(".part"))
+            .map(|entry| entry.metadata().unwrap().len())
+    }
+
+    #[tokio::test]
+    async fn resumes_a_download_of_an_earlier_run() {
+        let dir = TempDir::new().unwrap();
+        let (_, data) = image(dir.path());
+        let server = Server::start();
+        let cache = Cache::new(dir.path().join("cache"), 1 << 30);
+
+        interrupt(&server, &cache, dir.path(), &data).await;
+        let left = partial_size(&dir.path().join("cache")).unwrap();
+        assert_eq!(left, 1 << 20);
+
+        server.serve(
+            "/image.img",
+            Served {
+                body: data.clone(),
+                etag: Some("\"v1\"".into()),
+                ..Default::default()
+            },
+        );
+        let (job, backing) = job(&server, &cache, dir.path(), "sdc");
+        assert!(matches!(run(job, 1).await[..], [Progress::Finished]));
+
+        assert!(fs::read(backing).unwrap()[..data.len()] == data);
+        assert_eq!(
+            server.requests().last().unwrap(),
+            &("/image.img".into(), left)
+        );
+        let cached = cache.get(&server.url("/image.img"), None).unwrap();
+        assert!(fs::read(cached).unwrap() == data);
+    }
+
+    #[tokio::test]
+    async fn starts_over_if_the_image_changed() {
+        let dir = TempDir::new().unwrap();
+        let (_, old) = image(dir.path());
+        let server = Server::start();
+        let cache = Cache::new(dir.path().join("cache"), 1 << 30);
+
+        interrupt(&server, &cache, dir.path(), &old).await;
+
+        let new: Vec<u8> = old.iter().map(|b| b.wrapping_add(1)).collect();
+        server.serve(
+            "/image.img",
+            Served {
+                body: new.clone(),
+                etag: Some("\"v2\"".into()),
+                ..Default::default()
+            },
+        );
+        let (job, backing) = job(&server, &cache, dir.path(), "sdc");
+        assert!(matches!(run(job, 1).await[..], [Progress::Finished]));
+
+        assert!(fs::read(backing).unwrap()[..new.len()] == new);
+        assert_eq!(server.requests().last().unwrap(), &("/image.img".into(), 0));
+        let cached = cache.get(&server.url("/image.img"), None).unwrap();
+        assert!(fs::read(cached).unwrap() == new);
+    }
+
+    /// Downloads an image from two mirrors, the first of which breaks off
+    /// after 1 MiB and is gone then, and returns how it ended, what the stick
+    /// holds and the requests to the second mirror. With `metalink` the
+    /// mirrors and the checksum come from a metalink the second one serves.
+    async fn fail_over(
+        checksum: bool,
+        metalink: bool,
+    ) -> (Progress, Vec<u8>, Vec<u8>, Vec<(String, u64)>) {
+        let dir = TempDir::new().unwrap();
+        let (_, data) = image(dir.path());
+        let first = Server::start();
+        first.serve(
+            "/image.img",
+            Served {
+                body: data.clone(),
+                cut: Some(1 << 20),
+                once: true,
+                ..Default::default()
+            },
+        );
+        let second = Server::start();
+        second.serve("/image.img", &data[..]);
+
+        let mock = Arc::new(Mock::new());
+        let (dev, backing) = stick(dir.path(), "sdb", 4 << 20);
+        mock.plug(dev.clone(), &backing);
+        let backend: Arc<dyn Backend> = mock;
+
+        let digest = Checksum::Sha256(Sha256::digest(&data).to_vec());
+        let mut options = WriteOptions::default();
+        let source = match metalink {
+            true => {
+                let hash = match checksum {
+                    true => format!("<hash type=\"sha-256\">{}</hash>", digest.to_hex()),
+                    false => String::new(),
+                };
+                let text = format!(
+                    "<metalink xmlns=\"urn:ietf:params:xml:ns:metalink\">\
+                     <file name=\"image.img\"><size>{}</size>{hash}\
+                     <url priority=\"3\">{}</url>\
+                     <url priority=\"1\">ftp://localhost/image.img</url>\
+                     <url priority=\"2\">{}</url>\
+                     </file></metalink>",
+                    data.len(),
+                    second.url("/image.img"),
+                    first.url("/image.img"),
+                );
+                second.serve("/image.meta4", text.as_bytes());
+                Source::Metalink(second.url("/image.meta4"))
+            }
+            false => {
+                if checksum {
+                    options.checksum = digest.expected();
+                }
+                Source::Mirrors(vec![first.url("/image.img"), second.url("/image.img")])
+            }
+        };
+        let targets = vec![Target::Device(Box::new(dev))];
+        let job = Job::new(source, targets, Client::new(), options).with_backend(backend);
+
+        let result = run(job, 1).await.remove(0);
+        let requests = second
+            .requests()
+            .into_iter()
+            .filter(|(path, _)| path != "/image.meta4")
+            .collect();
+        (result, data, fs::read(backing).unwrap(), requests)
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_the_next_mirror_if_a_checksum_checks_it() {
+        let (result, data, written, requests) = fail_over(true, false).await;
+
+        assert!(matches!(result, Progress::Finished));
+        assert!(written[..data.len()] == data);
+        assert_eq!(requests, [("/image.img".into(), 1 << 20)]);
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_the_next_mirror_of_a_metalink() {
+        let (result, data, written, requests) = fail_over(true, true).await;
+
+        assert!(matches!(result, Progress::Finished));
+        assert!(written[..data.len()] == data);
+        assert_eq!(requests, [("/image.img".into(), 1 << 20)]);
+    }
+
+    #[tokio::test]
+    async fn stays_with_one_mirror_without_a_checksum() {
+        let (result, _, _, requests) = fail_over(false, false).await;
+
+        assert!(matches!(
+            result,
+            Progress::Errored(WriteError::Download { .. } | WriteError::Request { .. })
+        ));
+        assert!(requests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_one_budget_of_retries() {
+        let dir = TempDir::new().unwrap();
+        let (_, data) = image(dir.path());
+        let server = Server::start();
+        let cache = Cache::new(dir.path().join("cache"), 1 << 30);
+        server.serve(
+            "/image.img",
+            Served {
+                body: data,
+                cut: Some(1 << 20),
+                ..Default::default()
+            },
+        );
+
+        // The connection breaks off after 1 MiB and reconnecting fails.
+        let (job, _) = job(&server, &cache, dir.path(), "sdb");
+        let mut reports = job.run();
+        let mut result = None;
+        while let Some(report) = reports.next().await {
+            if let Progress::Started = report.progress {
+                let busy = Served {
+                    status: Some(503),
+                    ..Default::default()
+                };
+                server.serve("/image.img", busy);
+            }
+            if report.progress.is_final() {
+                result = Some(report.progress);
+            }
+        }
+
+        assert!(matches!(
+            result,
+            Some(Progress::Errored(WriteError::Download { offset, .. })) if offset == 1 << 20
+        ));
+        assert_eq!(server.requests().len(), RETRIES as usize + 1);
+    }
 }
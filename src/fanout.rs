@@ -0,0 +1,800 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Seek, SeekFrom, Write},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use tokio::time;
+
+use crate::backend::Backend;
+use crate::target::{Attached, Target};
+use crate::transfer::{Meter, Transfer};
+use crate::verify::{Recorder, Step};
+use crate::{Progress, Report, WriteError, WriteOptions};
+
+/// Writes buffered per device before a slow one holds up the others.
+const QUEUE: usize = 16;
+
+/// Bytes waiting for a device beyond its queue before handing it more data
+/// waits for it to catch up. Only very compressible images get there.
+const BACKLOG: usize = 64 << 20;
+
+/// How often devices are looked at once the whole image is handed out.
+const POLL: Duration = Duration::from_millis(250);
+
+/// How often a device that is behind is looked at.
+const TICK: Duration = Duration::from_millis(10);
+
+/// How long a device may take no data and make no progress before it is
+/// given up on as hung, e.g. behind a dying USB controller.
+#[cfg(not(test))]
+const STALL: Duration = Duration::from_secs(120);
+#[cfg(test)]
+const STALL: Duration = Duration::from_secs(2);
+
+/// Writes one image to several devices at once. Every device is written and
+/// read back by a thread of its own, so a slow stick holds up the others only
+/// while it catches up, one that fails is dropped while the rest carry on and
+/// one that hangs is given up on. Waiting for the devices never blocks.
+pub struct Fanout {
+    devices: Vec<Device>,
+    position: u64,
+    /// The source progress last reported, in the arguments of `reports`.
+    last: (u64, u64, u64),
+}
+
+struct Device {
+    queue: Option<SyncSender<Command>>,
+    /// What didn't fit in the queue yet, handed on by `drain`.
+    backlog: VecDeque<Command>,
+    /// The bytes to write in `backlog`.
+    backlog_size: usize,
+    shared: Arc<Mutex<Shared>>,
+    meter: Meter,
+    /// The steps of the thread when it was first found busy, and when.
+    watch: Option<(u64, Instant)>,
+    /// Whether its final progress was handed out.
+    ended: bool,
+}
+
+enum Command {
+    Write(Arc<[u8]>),
+    Seek(u64),
+}
+
+/// What a device thread and the job know about each other.
+#[derive(Default)]
+struct Shared {
+    written: u64,
+    /// Counts what the thread did, to tell a slow device from a hung one.
+    steps: u64,
+    verifying: Option<Transfer>,
+    /// Set by the job to have the thread stop early with this progress.
+    stop: Option<Progress>,
+    /// Set by the thread once it is done, or by the job if it gave up on it.
+    result: Option<Progress>,
+}
+
+impl Fanout {
+    /// Opens all of `targets` through `backend`, each on the thread that
+    /// writes it. One that can't be opened, or is smaller than the `image` if
+    /// both sizes are known, fails on its own and is reported as such.
+    pub fn open(
+        targets: Vec<Target>,
+        backend: &Arc<dyn Backend>,
+        options: &WriteOptions,
+        image: Option<u64>,
+    ) -> Self {
+        Self {
+            devices: targets
+                .into_iter()
+                .map(|target| Device::open(target, backend, options, image))
+                .collect(),
+            position: 0,
+            last: (0, 0, 0),
+        }
+    }
+
+    /// Whether every device has ended and said so.
+    pub fn is_done(&self) -> bool {
+        self.devices.iter().all(|device| device.ended)
+    }
+
+    /// The progress of each device still busy, and the final progress of
+    /// those that ended since the last call. `done`, `total` and `source`
+    /// count the progress of the source as for `Meter::update`.
+    pub fn reports(&mut self, done: u64, total: u64, source: u64) -> Vec<Report> {
+        self.last = (done, total, source);
+
+        let mut reports = vec![];
+        for (i, device) in self.devices.iter_mut().enumerate() {
+            if device.ended {
+                continue;
+            }
+
+            let shared = lock(&device.shared);
+            let progress = match (&shared.result, shared.verifying) {
+                (Some(result), _) => {
+                    device.ended = true;
+                    result.clone()
+                }
+                (None, Some(transfer)) => Progress::Verifying(transfer),
+                (None, None) => {
+                    Progress::Advanced(device.meter.update(done, total, source, shared.written))
+                }
+            };
+
+            reports.push(Report::new(i, progress));
+        }
+
+        reports
+    }
+
+    /// Stops writing to `device` because it was unplugged. It ends with
+    /// `WriteError::Removed` unless it already ended.
+    pub fn remove(&mut self, device: usize) {
+        if let Some(device) = self.devices.get_mut(device) {
+            lock(&device.shared)
+                .stop
+                .get_or_insert(Progress::Errored(WriteError::Removed));
+            device.close();
+        }
+    }
+
+    /// Hands what didn't fit in their queues to the devices, waiting for
+    /// those that are behind. A device that hangs is given up on.
+    pub async fn drain(&mut self) {
+        loop {
+            let mut behind = false;
+            for device in &mut self.devices {
+                device.push();
+                behind |= !device.backlog.is_empty() && !device.check();
+            }
+
+            if !behind {
+                return;
+            }
+            time::sleep(TICK).await;
+        }
+    }
+
+    /// Lets the devices write what is left for them and read it back.
+    pub async fn finish(&mut self) {
+        self.drain().await;
+
+        for device in &mut self.devices {
+            device.queue = None;
+        }
+    }
+
+    /// Waits a little for the devices, once the whole image is handed out.
+    pub async fn wait(&mut self) -> Vec<Report> {
+        time::sleep(POLL).await;
+        for device in &mut self.devices {
+            device.check();
+        }
+
+        let (done, total, source) = self.last;
+        self.reports(done, total, source)
+    }
+
+    /// Stops the devices that are still busy, flushing what they wrote, and
+    /// returns how each of them ended. Those that were stopped end with
+    /// `progress`, unless they hang.
+    pub async fn stop(&mut self, progress: Progress) -> Vec<Report> {
+        for device in &mut self.devices {
+            lock(&device.shared).stop = Some(progress.clone());
+            device.close();
+        }
+
+        loop {
+            let mut ended = true;
+            for device in &mut self.devices {
+                ended &= device.check();
+            }
+
+            if ended {
+                break;
+            }
+            time::sleep(TICK).await;
+        }
+
+        let (done, total, source) = self.last;
+        self.reports(done, total, source)
+    }
+
+    fn send(&mut self, command: impl Fn() -> Command) {
+        for device in &mut self.devices {
+            if device.queue.is_none() {
+                continue;
+            }
+
+            let command = command();
+            if let Command::Write(data) = &command {
+                device.backlog_size += data.len();
+            }
+            device.backlog.push_back(command);
+            device.push();
+
+            // Data is handed out on a thread that may block, see
+            // `writer::blocking`. It only waits here if an image decompresses
+            // to a lot at once.
+            while device.backlog_size > BACKLOG && !device.check() {
+                thread::sleep(TICK);
+                device.push();
+            }
+        }
+    }
+}
+
+impl Write for Fanout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let data: Arc<[u8]> = buf.into();
+        self.send(|| Command::Write(data.clone()));
+        self.position += buf.len() as u64;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Fanout {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+            SeekFrom::End(_) => None,
+        };
+
+        let position = position.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid seek on the devices")
+        })?;
+        self.position = position;
+        self.send(|| Command::Seek(position));
+
+        Ok(self.position)
+    }
+}
+
+/// Stops the threads of a job that is dropped before it ended, instead of
+/// letting them read back a partly written image.
+impl Drop for Fanout {
+    fn drop(&mut self) {
+        for device in &mut self.devices {
+            lock(&device.shared).stop.get_or_insert(Progress::Cancelled);
+            device.close();
+        }
+    }
+}
+
+impl Device {
+    fn open(
+        target: Target,
+        backend: &Arc<dyn Backend>,
+        options: &WriteOptions,
+        image: Option<u64>,
+    ) -> Self {
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let (queue, commands) = mpsc::sync_channel(QUEUE);
+
+        // The device is attached and opened by the thread as well, as that
+        // goes through the backend. The thread is left to itself, it may
+        // hang for good in a write to a broken device.
+        {
+            let shared = shared.clone();
+            let backend = backend.clone();
+            let options = options.clone();
+            thread::spawn(move || {
+                let progress = match recorder(&target, &backend, &options, image, &shared) {
+                    Ok((attached, recorder)) => {
+                        let progress = work(&attached, recorder, commands, &shared);
+                        attached.finish(progress, &options)
+                    }
+                    Err(progress) => progress,
+                };
+                lock(&shared).result.get_or_insert(progress);
+            });
+        }
+
+        Self {
+            queue: Some(queue),
+            backlog: VecDeque::new(),
+            backlog_size: 0,
+            shared,
+            meter: Meter::new(),
+            watch: None,
+            ended: false,
+        }
+    }
+
+    /// Moves what it can from the backlog to the queue.
+    fn push(&mut self) {
+        while let Some(command) = self.backlog.pop_front() {
+            let queue = match &self.queue {
+                Some(queue) => queue,
+                None => return self.close(),
+            };
+
+            let size = match &command {
+                Command::Write(data) => data.len(),
+                Command::Seek(_) => 0,
+            };
+            match queue.try_send(command) {
+                Ok(()) => self.backlog_size -= size,
+                Err(TrySendError::Full(command)) => return self.backlog.push_front(command),
+                // The thread is gone if writing to its device failed.
+                Err(TrySendError::Disconnected(_)) => return self.close(),
+            }
+        }
+    }
+
+    /// Hands the device nothing more.
+    fn close(&mut self) {
+        self.queue = None;
+        self.backlog.clear();
+        self.backlog_size = 0;
+    }
+
+    /// Whether the device ended, or hung and was given up on. It hangs if its
+    /// thread did nothing for `STALL` while it had something to do, which is
+    /// when this is called.
+    fn check(&mut self) -> bool {
+        let mut shared = lock(&self.shared);
+        if shared.result.is_some() {
+            return true;
+        }
+
+        match self.watch {
+            Some((steps, since)) if steps == shared.steps && since.elapsed() > STALL => {
+                shared.stop = Some(Progress::Errored(WriteError::Stalled));
+                shared.result = Some(Progress::Errored(WriteError::Stalled));
+                drop(shared);
+                self.close();
+                return true;
+            }
+            Some((steps, _)) if steps == shared.steps => {}
+            _ => self.watch = Some((shared.steps, Instant::now())),
+        }
+
+        false
+    }
+}
+
+/// Gets the target ready to be written, unless it is smaller than the
+/// `image` or the device was stopped before. Returns how it ended otherwise.
+fn recorder(
+    target: &Target,
+    backend: &Arc<dyn Backend>,
+    options: &WriteOptions,
+    image: Option<u64>,
+    shared: &Mutex<Shared>,
+) -> Result<(Attached, Recorder), Progress> {
+    if let (Some(image), Some(size)) = (image, target.size()) {
+        if image > size {
+            return Err(Progress::Errored(WriteError::TooSmall {
+                image,
+                device: size,
+            }));
+        }
+    }
+
+    // Nothing is unmounted for a job that was cancelled in the meantime.
+    if let Some(progress) = lock(shared).stop.take() {
+        return Err(progress);
+    }
+
+    let attached = target.attach(backend, options).map_err(Progress::Errored)?;
+    let file = attached.open().map_err(Progress::Errored)?;
+
+    Ok((attached, Recorder::new(file, options)))
+}
+
+/// Writes the commands to the target until the queue is closed, then reads
+/// it back if asked to. Returns how the device ended.
+fn work(
+    target: &Attached,
+    mut recorder: Recorder,
+    commands: Receiver<Command>,
+    shared: &Mutex<Shared>,
+) -> Progress {
+    for command in commands {
+        if let Some(progress) = lock(shared).stop.take() {
+            return halt(&recorder, progress);
+        }
+
+        let result = match command {
+            Command::Write(data) => recorder.write_all(&data),
+            Command::Seek(offset) => recorder.seek(SeekFrom::Start(offset)).map(|_| ()),
+        };
+
+        if let Err(e) = result {
+            return Progress::Errored(WriteError::from_sink(e, recorder.written()));
+        }
+
+        let mut shared = lock(shared);
+        shared.written = recorder.written();
+        shared.steps += 1;
+    }
+
+    if let Some(progress) = lock(shared).stop.take() {
+        return halt(&recorder, progress);
+    }
+
+    let mut read_back = match recorder.finish(|| target.open()) {
+        Ok(None) => return Progress::Finished,
+        Ok(Some(read_back)) => read_back,
+        Err(e) => return Progress::Errored(e),
+    };
+
+    loop {
+        // Cut short, the image is left unchecked and the device isn't done.
+        if let Some(progress) = lock(shared).stop.take() {
+            return progress;
+        }
+
+        lock(shared).steps += 1;
+        match read_back.step() {
+            Ok(Step::Advanced(transfer)) => lock(shared).verifying = Some(transfer),
+            Ok(Step::Matched) => return Progress::Finished,
+            Ok(Step::Mismatched) => return Progress::Mismatch,
+            Err(e) => return Progress::Errored(e),
+        }
+    }
+}
+
+/// Flushes what was written so far before the device is closed. A device that
+/// is gone is left alone.
+fn halt(recorder: &Recorder, progress: Progress) -> Progress {
+    if let Progress::Errored(WriteError::Removed) = progress {
+        return progress;
+    }
+
+    match recorder.sync() {
+        Ok(()) => progress,
+        Err(e) => Progress::Errored(WriteError::Sync {
+            cause: e.to_string(),
+        }),
+    }
+}
+
+/// A thread that panicked leaves the rest of the shared state usable.
+fn lock(shared: &Mutex<Shared>) -> MutexGuard<'_, Shared> {
+    shared.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    use dbus_udisks2::DiskDevice;
+    use futures::StreamExt;
+    use reqwest::Client;
+    use tempfile::TempDir;
+
+    use crate::backend::{Backend, Mock, Mount};
+    use crate::target::Target;
+    use crate::testing::{image, run, stick};
+    use crate::writer::Job;
+    use crate::{Progress, Source, WriteError, WriteOptions};
+
+    fn options() -> WriteOptions {
+        WriteOptions {
+            verify: true,
+            eject: true,
+            ..Default::default()
+        }
+    }
+
+    /// Writes the image at `path` to `devs` through `mock`.
+    async fn write(
+        mock: &Arc<Mock>,
+        path: &Path,
+        devs: &[&DiskDevice],
+        options: WriteOptions,
+    ) -> Vec<Progress> {
+        let backend: Arc<dyn Backend> = mock.clone();
+        let targets = devs
+            .iter()
+            .map(|dev| Target::Device(Box::new((*dev).clone())))
+            .collect();
+        let source = Source::File(path.display().to_string());
+
+        let job = Job::new(source, targets, Client::new(), options).with_backend(backend);
+        run(job, devs.len()).await
+    }
+
+    /// Plugs in a stick of 4 MiB.
+    fn plug(mock: &Mock, dir: &Path, name: &str) -> (DiskDevice, PathBuf) {
+        let (dev, backing) = stick(dir, name, 4 << 20);
+        mock.plug(dev.clone(), &backing);
+        (dev, backing)
+    }
+
+    fn mount(mock: &Mock, dev: &DiskDevice) {
+        mock.mount(Mount {
+            path: dev.parent.path.clone(),
+            device: dev.parent.device.clone(),
+            mount_points: vec![PathBuf::from("/media/stick")],
+        });
+    }
+
+    /// Plugs in a stick backed by a pipe no one else opens, so opening it
+    /// to read or writing much to it blocks for good.
+    fn plug_hung(mock: &Mock, dir: &Path, name: &str) -> DiskDevice {
+        let (dev, backing) = stick(dir, name, 32 << 20);
+        fs::remove_file(&backing).unwrap();
+        let fifo = CString::new(backing.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+        mock.plug(dev.clone(), &backing);
+        dev
+    }
+
+    fn holds(backing: &Path, data: &[u8]) -> bool {
+        fs::read(backing).unwrap()[..data.len()] == *data
+    }
+
+    fn untouched(backing: &Path) -> bool {
+        fs::read(backing).unwrap().iter().all(|b| *b == 0xff)
+    }
+
+    #[tokio::test]
+    async fn writes_several_devices_and_ejects_them() {
+        let dir = TempDir::new().unwrap();
+        let (path, data) = image(dir.path());
+        let mock = Arc::new(Mock::new());
+        let (a, a_backing) = plug(&mock, dir.path(), "sdb");
+        let (b, b_backing) = plug(&mock, dir.path(), "sdc");
+
+        let results = write(&mock, &path, &[&a, &b], options()).await;
+
+        assert!(matches!(
+            results[..],
+            [Progress::Ejected, Progress::Ejected]
+        ));
+        assert!(holds(&a_backing, &data) && holds(&b_backing, &data));
+        let mut ejected = mock.ejected();
+        ejected.sort();
+        assert_eq!(ejected, [a.parent.path, b.parent.path]);
+        assert!(mock.list_devices().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn leaves_devices_attached_if_asked_to() {
+        let dir = TempDir::new().unwrap();
+        let (path, data) = image(dir.path());
+        let mock = Arc::new(Mock::new());
+        let (dev, backing) = plug(&mock, dir.path(), "sdb");
+
+        let options = WriteOptions {
+            eject: false,
+            ..options()
+        };
+        let results = write(&mock, &path, &[&dev], options).await;
+
+        assert!(matches!(results[..], [Progress::Finished]));
+        assert!(holds(&backing, &data));
+        assert!(mock.ejected().is_empty());
+    }
+
+    #[tokio::test]
+    async fn one_bad_device_does_not_stop_the_others() {
+        let dir = TempDir::new().unwrap();
+        let (path, data) = image(dir.path());
+        let mock = Arc::new(Mock::new());
+        let (good, good_backing) = plug(&mock, dir.path(), "sdb");
+
+        let (small, small_backing) = stick(dir.path(), "sdc", 1 << 20);
+        mock.plug(small.clone(), &small_backing);
+
+        // Pulled after it was chosen.
+        let (gone, _) = plug(&mock, dir.path(), "sdd");
+        mock.unplug(&gone.drive.path);
+
+        let results = write(&mock, &path, &[&good, &small, &gone], options()).await;
+
+        assert!(matches!(
+            results[..],
+            [
+                Progress::Ejected,
+                Progress::Errored(WriteError::TooSmall { .. }),
+                Progress::Errored(WriteError::Changed),
+            ]
+        ));
+        assert!(holds(&good_backing, &data));
+        assert!(untouched(&small_backing));
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_hung_device() {
+        let dir = TempDir::new().unwrap();
+        let mock = Arc::new(Mock::new());
+
+        // More than the queues hold, so the job has to wait for the devices.
+        let path = dir.path().join("image.img");
+        let data = vec![7; 24 << 20];
+        fs::write(&path, &data).unwrap();
+
+        let (good, good_backing) = stick(dir.path(), "sdb", 32 << 20);
+        mock.plug(good.clone(), &good_backing);
+
+        let hung = plug_hung(&mock, dir.path(), "sdc");
+
+        let results = write(&mock, &path, &[&good, &hung], options()).await;
+
+        assert!(matches!(
+            results[..],
+            [Progress::Ejected, Progress::Errored(WriteError::Stalled)]
+        ));
+        assert!(holds(&good_backing, &data));
+    }
+
+    #[tokio::test]
+    async fn opens_devices_apart_from_the_job() {
+        let dir = TempDir::new().unwrap();
+        let (path, data) = image(dir.path());
+        let mock = Arc::new(Mock::new());
+        let (good, good_backing) = plug(&mock, dir.path(), "sdb");
+        let mut written = fs::read(&good_backing).unwrap();
+        written[..data.len()].copy_from_slice(&data);
+        fs::write(&good_backing, written).unwrap();
+
+        // Comparing opens it read-only, which waits for a writer to the pipe.
+        let hung = plug_hung(&mock, dir.path(), "sdc");
+
+        let options = WriteOptions {
+            compare_only: true,
+            ..options()
+        };
+        let results = write(&mock, &path, &[&good, &hung], options).await;
+
+        assert!(matches!(
+            results[..],
+            [Progress::Finished, Progress::Errored(WriteError::Stalled)]
+        ));
+    }
+
+    #[tokio::test]
+    async fn leaves_a_device_cancelled_during_the_read_back_unverified() {
+        let dir = TempDir::new().unwrap();
+        let mock = Arc::new(Mock::new());
+
+        // Large enough that reading it back takes a while.
+        let path = dir.path().join("image.img");
+        let data: Vec<u8> = (0..48 << 20).map(|i: u32| (i % 251) as u8).collect();
+        fs::write(&path, &data).unwrap();
+        let (dev, backing) = stick(dir.path(), "sdb", 64 << 20);
+        mock.plug(dev.clone(), &backing);
+
+        let backend: Arc<dyn Backend> = mock.clone();
+        let source = Source::File(path.display().to_string());
+        let targets = vec![Target::Device(Box::new(dev))];
+        let job = Job::new(source, targets, Client::new(), options()).with_backend(backend);
+        let cancel = job.cancel_handle();
+        let mut reports = job.run();
+
+        let mut result = None;
+        while let Some(report) = reports.next().await {
+            match report.progress {
+                Progress::Verifying(_) => cancel.cancel(),
+                progress if progress.is_final() => result = Some(progress),
+                _ => {}
+            }
+        }
+
+        // Written in full, but not checked, so neither done nor ejected.
+        assert!(matches!(result, Some(Progress::Cancelled)));
+        assert!(mock.ejected().is_empty());
+        assert!(holds(&backing, &data));
+    }
+
+    #[tokio::test]
+    async fn reports_a_device_that_does_not_read_back_what_was_written() {
+        let dir = TempDir::new().unwrap();
+        let (path, data) = image(dir.path());
+        let mock = Arc::new(Mock::new());
+        let (fake, _) = plug(&mock, dir.path(), "sdb");
+        mock.fake(&fake, 1 << 20);
+        let (real, real_backing) = plug(&mock, dir.path(), "sdc");
+
+        let results = write(&mock, &path, &[&fake, &real], options()).await;
+
+        assert!(
+            matches!(results[..], [Progress::Mismatch, Progress::Ejected]),
+            "{results:?}"
+        );
+        assert_eq!(mock.ejected(), [real.parent.path]);
+        assert!(holds(&real_backing, &data));
+    }
+
+    #[tokio::test]
+    async fn refuses_protected_and_mounted_devices() {
+        let dir = TempDir::new().unwrap();
+        let (path, data) = image(dir.path());
+        let mock = Arc::new(Mock::new());
+        let (system, system_backing) = plug(&mock, dir.path(), "sdb");
+        mock.protect(&system, "/dev/sdb2 is mounted at /");
+        let (mounted, mounted_backing) = plug(&mock, dir.path(), "sdc");
+        mount(&mock, &mounted);
+
+        let results = write(&mock, &path, &[&system, &mounted], options()).await;
+
+        match &results[..] {
+            [Progress::Errored(WriteError::Protected { reason }), Progress::Errored(WriteError::Mounted { mount_points })] =>
+            {
+                assert_eq!(reason, "/dev/sdb2 is mounted at /");
+                assert_eq!(mount_points, &["/media/stick"]);
+            }
+            results => panic!("{results:?}"),
+        }
+        assert!(untouched(&system_backing) && untouched(&mounted_backing));
+
+        // Allowed to, it unmounts the stick, but a system disk stays refused.
+        let options = WriteOptions {
+            unmount: true,
+            ..options()
+        };
+        let results = write(&mock, &path, &[&system, &mounted], options).await;
+
+        assert!(matches!(
+            results[..],
+            [
+                Progress::Errored(WriteError::Protected { .. }),
+                Progress::Ejected
+            ]
+        ));
+        assert!(untouched(&system_backing) && holds(&mounted_backing, &data));
+    }
+
+    #[tokio::test]
+    async fn reports_backend_failures() {
+        let dir = TempDir::new().unwrap();
+        let (path, _) = image(dir.path());
+        let mock = Arc::new(Mock::new());
+        let (dev, backing) = plug(&mock, dir.path(), "sdb");
+
+        mock.fail("open", Some("Not authorized to perform operation"));
+        let results = write(&mock, &path, &[&dev], options()).await;
+        match &results[..] {
+            [Progress::Errored(WriteError::Open { cause })] => {
+                assert_eq!(cause, "Not authorized to perform operation")
+            }
+            results => panic!("{results:?}"),
+        }
+        mock.fail("open", None);
+
+        mount(&mock, &dev);
+        mock.fail("unmount", Some("Target is busy"));
+        let unmount = WriteOptions {
+            unmount: true,
+            ..options()
+        };
+        let results = write(&mock, &path, &[&dev], unmount).await;
+        match &results[..] {
+            [Progress::Errored(WriteError::Unmount { cause })] => {
+                assert_eq!(cause, "/dev/sdb: Target is busy")
+            }
+            results => panic!("{results:?}"),
+        }
+        mock.fail("unmount", None);
+
+        mock.fail("*", Some("UDisks is not running"));
+        let results = write(&mock, &path, &[&dev], options()).await;
+        assert!(matches!(
+            results[..],
+            [Progress::Errored(WriteError::Open { .. })]
+        ));
+        assert!(untouched(&backing));
+    }
+}
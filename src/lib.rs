@@ -1,32 +1,91 @@
+pub mod archive;
+pub mod backend;
+pub mod bmap;
+pub mod cache;
+pub mod checksum;
+pub mod decompress;
 pub mod download;
+pub mod fanout;
+pub mod metalink;
 pub mod read;
+pub mod signature;
+pub mod target;
+#[cfg(test)]
+mod testing;
+pub mod transfer;
+pub mod verify;
+pub mod writer;
 
+use std::fmt;
 use std::fs::File;
 use std::io;
 use std::io::Read;
+use std::path::PathBuf;
 
+use reqwest::header::CONTENT_LENGTH;
+use reqwest::Client;
 use serde::Deserialize;
 use serde::Serialize;
 
-#[cfg(target_os = "linux")]
-pub use crate::linux::list_devices;
+use crate::cache::Cache;
+use crate::checksum::Expected;
+use crate::decompress::Compression;
+use crate::metalink::Metalink;
+use crate::signature::Signature;
+use crate::transfer::Transfer;
+use crate::verify::DeviceError;
+
+pub use crate::backend::{device_label, Backend, Mount, Partition};
 
-#[cfg(target_os = "linux")]
-mod linux;
 pub mod ui;
 
 pub const DIRECTORY: &str = "/etc/linux_creation_tool/";
 
+const DEFAULT_CACHE_LIMIT_MB: u64 = 20 * 1024;
+const DEFAULT_LARGE_DEVICE_GB: u64 = 128;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct OperatingSystem {
     name: String,
     source: Source,
     pic: Source,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha512: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signing_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    keyring: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    member: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bmap: Option<Source>,
+    /// The size of the image once decompressed, in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
 }
 
 impl OperatingSystem {
     pub fn new(name: String, source: Source, pic: Source) -> Self {
-        Self { name, source, pic }
+        Self {
+            name,
+            source,
+            pic,
+            sha256: None,
+            sha512: None,
+            checksum_url: None,
+            signature_url: None,
+            signing_key: None,
+            keyring: None,
+            member: None,
+            bmap: None,
+            size: None,
+        }
     }
 
     pub fn name(&self) -> &String {
@@ -40,11 +99,96 @@ impl OperatingSystem {
     pub fn pic(&self) -> &Source {
         &self.pic
     }
+
+    /// The image to write if the source is a zip archive holding several.
+    pub fn member(&self) -> Option<&String> {
+        self.member.as_ref()
+    }
+
+    pub fn bmap(&self) -> Option<&Source> {
+        self.bmap.as_ref()
+    }
+
+    /// The size of the image as written, from the catalog or else from the
+    /// file or a HEAD request. Unknown for compressed images the catalog
+    /// doesn't give the size of.
+    pub async fn image_size(&self, client: &Client) -> Option<u64> {
+        if self.size.is_some() {
+            return self.size;
+        }
+
+        let raw = |name: &str| Compression::detect(&[], name) == Compression::None;
+
+        let url = match &self.source {
+            Source::File(path) => {
+                return match raw(path) {
+                    true => std::fs::metadata(path).ok().map(|m| m.len()),
+                    false => None,
+                }
+            }
+            Source::Url(url) => url.clone(),
+            Source::Mirrors(urls) => urls.first()?.clone(),
+            Source::Metalink(url) => {
+                let metalink = Metalink::load(url, client).await.ok()?;
+                return metalink.size.filter(|_| raw(&metalink.name));
+            }
+        };
+
+        if !raw(checksum::file_name(&url)) {
+            return None;
+        }
+
+        let response = client.head(&url).send().await.ok()?;
+        response
+            .error_for_status()
+            .ok()?
+            .headers()
+            .get(CONTENT_LENGTH)?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    pub fn options(&self, verify: bool) -> WriteOptions {
+        WriteOptions {
+            checksum: self.checksum(),
+            member: self.member.clone(),
+            bmap: self.bmap.clone(),
+            size: self.size,
+            unmount: false,
+            eject: true,
+            verify,
+            compare_only: false,
+        }
+    }
+
+    pub fn checksum(&self) -> Expected {
+        Expected {
+            sha256: self.sha256.clone(),
+            sha512: self.sha512.clone(),
+            url: self.checksum_url.clone(),
+            signature: self.signature_url.as_ref().map(|url| Signature {
+                url: url.clone(),
+                fingerprint: self.signing_key.clone(),
+                keyring: self.keyring.clone(),
+            }),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct OperatingSystemList {
     os: Vec<OperatingSystem>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cache_dir: Option<String>,
+    /// Size limit of the download cache in MiB, 0 turns it off.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cache_limit_mb: Option<u64>,
+    /// Devices larger than this many GiB are only written once the user
+    /// confirms it, as they are unlikely to be throwaway sticks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    large_device_gb: Option<u64>,
 }
 
 impl OperatingSystemList {
@@ -53,7 +197,29 @@ impl OperatingSystemList {
     }
 
     pub fn empty() -> Self {
-        Self { os: vec![] }
+        Self {
+            os: vec![],
+            cache_dir: None,
+            cache_limit_mb: None,
+            large_device_gb: None,
+        }
+    }
+
+    /// The download cache this catalog is configured with.
+    pub fn cache(&self) -> Cache {
+        let dir = match &self.cache_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => Cache::default_dir(),
+        };
+        let limit = self.cache_limit_mb.unwrap_or(DEFAULT_CACHE_LIMIT_MB);
+
+        Cache::new(dir, limit << 20)
+    }
+
+    /// Whether `size` in bytes makes for a large device that needs
+    /// confirming.
+    pub fn is_large(&self, size: u64) -> bool {
+        size > self.large_device_gb.unwrap_or(DEFAULT_LARGE_DEVICE_GB) << 30
     }
 
     pub fn as_vec(&self) -> &Vec<OperatingSystem> {
@@ -65,6 +231,10 @@ impl OperatingSystemList {
 pub enum Source {
     Url(String),
     File(String),
+    /// The same image on several servers, tried in order.
+    Mirrors(Vec<String>),
+    /// A Metalink (RFC 5854) file listing the mirrors of the image.
+    Metalink(String),
 }
 
 pub fn load_config(path: &str) -> io::Result<OperatingSystemList> {
@@ -78,10 +248,243 @@ pub fn load_config(path: &str) -> io::Result<OperatingSystemList> {
     Ok(json)
 }
 
-#[derive(Debug, Clone)]
+/// Everything about writing an image besides its source and target.
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    pub checksum: Expected,
+    pub member: Option<String>,
+    pub bmap: Option<Source>,
+    /// The size of the image once decompressed, if the catalog knows it.
+    pub size: Option<u64>,
+    /// Unmount filesystems of the devices that are mounted, instead of
+    /// refusing to write to them.
+    pub unmount: bool,
+    /// Eject and power off the devices once they are written.
+    pub eject: bool,
+    pub verify: bool,
+    /// Compare the device with the image without writing to it.
+    pub compare_only: bool,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event", content = "transfer", rename_all = "snake_case")]
 pub enum Progress {
     Started,
-    Advanced(f32),
+    Advanced(Transfer),
+    Verifying(Transfer),
     Finished,
-    Errored,
+    /// Finished, and the drive was ejected so it is safe to remove.
+    Ejected,
+    Mismatch,
+    ChecksumMismatch,
+    /// The job was cancelled, leaving the device partly written.
+    Cancelled,
+    Errored(WriteError),
+}
+
+impl Progress {
+    /// Whether nothing follows this for the device.
+    pub fn is_final(&self) -> bool {
+        !matches!(
+            self,
+            Progress::Started | Progress::Advanced(_) | Progress::Verifying(_)
+        )
+    }
+}
+
+/// Progress of a job on one of its devices, or on all of them at once.
+#[derive(Serialize, Debug, Clone)]
+pub struct Report {
+    /// Position of the device in the list the job was given, `None` for all
+    /// of them.
+    pub device: Option<usize>,
+    #[serde(flatten)]
+    pub progress: Progress,
+}
+
+impl Report {
+    pub fn new(device: usize, progress: Progress) -> Self {
+        Self {
+            device: Some(device),
+            progress,
+        }
+    }
+
+    pub fn all(progress: Progress) -> Self {
+        Self {
+            device: None,
+            progress,
+        }
+    }
+
+    pub fn concerns(&self, device: usize) -> bool {
+        self.device.is_none_or(|d| d == device)
+    }
+}
+
+/// Why a write failed. Offsets are bytes of the source read or downloaded so
+/// far, except for `Write` and `ReadBack` where they are bytes into the
+/// device.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum WriteError {
+    /// The checksum could not be fetched or its signature is bad.
+    Checksum {
+        cause: String,
+    },
+    Bmap {
+        cause: String,
+    },
+    /// The download could not be started.
+    Request {
+        cause: String,
+    },
+    NoContentLength,
+    Download {
+        cause: String,
+        offset: u64,
+    },
+    /// The local image could not be opened or inspected.
+    Source {
+        cause: String,
+    },
+    Read {
+        cause: String,
+        offset: u64,
+    },
+    /// UDisks did not hand out the device, e.g. because polkit denied it.
+    Open {
+        cause: String,
+    },
+    /// Another device took the place of the chosen one.
+    Changed,
+    /// A file target is a device node, symlink or anything else that isn't a
+    /// regular file.
+    NotAFile {
+        path: String,
+    },
+    /// The device holds the running system.
+    Protected {
+        reason: String,
+    },
+    /// The device has mounted filesystems and unmounting wasn't allowed.
+    Mounted {
+        mount_points: Vec<String>,
+    },
+    Unmount {
+        cause: String,
+    },
+    /// The image doesn't fit on the device.
+    TooSmall {
+        image: u64,
+        device: u64,
+    },
+    Seek {
+        cause: String,
+    },
+    /// The image is corrupt, truncated or not supported.
+    Image {
+        cause: String,
+        offset: u64,
+    },
+    Write {
+        cause: String,
+        offset: u64,
+    },
+    Sync {
+        cause: String,
+    },
+    ReadBack {
+        cause: String,
+        offset: u64,
+    },
+    /// The device was unplugged while it was being written.
+    Removed,
+    /// The device took no data for minutes and was given up on.
+    Stalled,
 }
+
+impl WriteError {
+    /// Tells a failed device write apart from a broken image in an error of
+    /// the write pipeline. `offset` is the source position.
+    pub(crate) fn from_sink(e: io::Error, offset: u64) -> Self {
+        match e.get_ref().and_then(|e| e.downcast_ref::<DeviceError>()) {
+            Some(device) => WriteError::Write {
+                cause: device.cause.to_string(),
+                offset: device.offset,
+            },
+            None => WriteError::Image {
+                cause: e.to_string(),
+                offset,
+            },
+        }
+    }
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::Checksum { cause } => write!(f, "Could not check the image: {cause}"),
+            WriteError::Bmap { cause } => write!(f, "Could not load the block map: {cause}"),
+            WriteError::Request { cause } => write!(f, "Could not start the download: {cause}"),
+            WriteError::NoContentLength => write!(f, "The server did not send the image size"),
+            WriteError::Download { cause, offset } => {
+                write!(f, "The download failed after {offset} bytes: {cause}")
+            }
+            WriteError::Source { cause } => write!(f, "Could not open the image: {cause}"),
+            WriteError::Read { cause, offset } => {
+                write!(f, "Reading the image failed after {offset} bytes: {cause}")
+            }
+            WriteError::Open { cause } => write!(f, "Could not open the device: {cause}"),
+            WriteError::TooSmall { image, device } => write!(
+                f,
+                "The image is {}, the device is {}",
+                transfer::bytes(*image),
+                transfer::bytes(*device)
+            ),
+            WriteError::Mounted { mount_points } => write!(
+                f,
+                "The device is mounted at {}, unmount it first",
+                mount_points.join(", ")
+            ),
+            WriteError::Unmount { cause } => write!(f, "Could not unmount the device: {cause}"),
+            WriteError::Protected { reason } => {
+                write!(f, "The device holds the running system: {reason}")
+            }
+            WriteError::Changed => write!(
+                f,
+                "The device is not the one that was chosen, choose it again"
+            ),
+            WriteError::NotAFile { path } => {
+                write!(
+                    f,
+                    "{path} is not a regular file, only those are written as files"
+                )
+            }
+            WriteError::Seek { cause } => write!(f, "Could not seek on the device: {cause}"),
+            WriteError::Image { cause, offset } => {
+                write!(f, "The image is broken after {offset} bytes: {cause}")
+            }
+            WriteError::Write { cause, offset } => {
+                write!(f, "Writing to the device failed at byte {offset}: {cause}")
+            }
+            WriteError::Sync { cause } => write!(f, "Could not flush the device: {cause}"),
+            WriteError::ReadBack { cause, offset } => {
+                write!(
+                    f,
+                    "Reading the device back failed at byte {offset}: {cause}"
+                )
+            }
+            WriteError::Removed => write!(
+                f,
+                "The device was removed while it was being written and must be written again"
+            ),
+            WriteError::Stalled => write!(
+                f,
+                "The device stopped responding and was given up on, it may be faulty"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WriteError {}
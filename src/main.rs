@@ -1,4 +1,6 @@
+use crate::cli::Cli;
 use crate::ui::App;
+use clap::Parser;
 use iced::window::Icon;
 use iced::{window::Settings as WindowSettings, Application, Settings};
 use image::io::Reader as ImageReader;
@@ -6,9 +8,20 @@ use linux_creation_tool::ui::Flags;
 use linux_creation_tool::*;
 use reqwest::Client;
 
+mod cli;
+
 const CONFIG: &str = "/etc/linux_creation_tool/config.json";
 
 fn main() {
+    let cli = Cli::parse();
+    if cli.command.is_some() {
+        if let Err(e) = cli::run(cli) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let client = Client::new();
 
     let img = ImageReader::open(format!("{}pictures/icon.png", DIRECTORY))
@@ -18,7 +31,7 @@ fn main() {
     let img = img.as_rgba8().unwrap().as_raw();
 
     let settings = Settings {
-        flags: Flags::new(client, CONFIG),
+        flags: Flags::new(client, cli.config.clone()).with_backend(cli.backend()),
         exit_on_close_request: true,
         window: WindowSettings {
             size: (512, 362),
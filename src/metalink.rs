@@ -0,0 +1,173 @@
+use std::io;
+
+use reqwest::Client;
+
+use crate::checksum::{fetch, Checksum};
+
+/// The image a Metalink (RFC 5854) file describes.
+#[derive(Debug, Clone)]
+pub struct Metalink {
+    pub name: String,
+    pub size: Option<u64>,
+    pub checksum: Option<Checksum>,
+    /// The HTTP(S) mirrors, most preferred first.
+    pub urls: Vec<String>,
+}
+
+impl Metalink {
+    /// Parses the first file of a Metalink document. Hashes other than
+    /// SHA-256 and SHA-512, and mirrors other than HTTP(S) ones, are ignored.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let doc = roxmltree::Document::parse(text).map_err(invalid)?;
+
+        let file = doc
+            .root_element()
+            .children()
+            .find(|n| n.has_tag_name("file"))
+            .ok_or_else(|| invalid("metalink lists no file"))?;
+
+        let name = file
+            .attribute("name")
+            .ok_or_else(|| invalid("metalink file has no name"))?;
+
+        let child = |tag: &'static str| {
+            file.children()
+                .filter(move |n| n.has_tag_name(tag))
+                .map(|n| (n, n.text().unwrap_or("").trim()))
+        };
+
+        let size = child("size").find_map(|(_, text)| text.parse().ok());
+
+        let hash = |algorithm: &str, parse: fn(&str) -> Option<Checksum>| {
+            child("hash")
+                .find(|(n, _)| n.attribute("type") == Some(algorithm))
+                .and_then(|(_, text)| parse(text))
+        };
+        let checksum =
+            hash("sha-512", Checksum::sha512).or_else(|| hash("sha-256", Checksum::sha256));
+
+        // Lower priorities are preferred, mirrors without one come last.
+        let mut urls: Vec<(u32, String)> = child("url")
+            .filter(|(_, text)| text.starts_with("http://") || text.starts_with("https://"))
+            .map(|(n, text)| {
+                let priority = n
+                    .attribute("priority")
+                    .and_then(|p| p.parse().ok())
+                    .unwrap_or(u32::MAX);
+                (priority, text.to_string())
+            })
+            .collect();
+        urls.sort_by_key(|(priority, _)| *priority);
+
+        if urls.is_empty() {
+            return Err(invalid(format!("metalink lists no HTTP mirror for {name}")));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            size,
+            checksum,
+            urls: urls.into_iter().map(|(_, url)| url).collect(),
+        })
+    }
+
+    pub async fn load(url: &str, client: &Client) -> io::Result<Self> {
+        let bytes = fetch(client, url).await.map_err(io::Error::other)?;
+
+        Self::parse(&String::from_utf8_lossy(&bytes))
+    }
+}
+
+fn invalid<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metalink;
+    use crate::checksum::Checksum;
+
+    const SHA256: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+    const SHA512: &str = "ee26b0dd4af7e749aa1a8ee3c10ae9923f618980772e473f8819a5d4940e0db2\
+                          7ac185f8a0e1d5f84f88bc887fd67b143732c304cc5fa9ad8e6f57f50028a8ff";
+
+    fn metalink(file: &str) -> String {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <metalink xmlns=\"urn:ietf:params:xml:ns:metalink\">\n\
+             <file name=\"os.img.xz\">{file}</file>\n</metalink>\n"
+        )
+    }
+
+    #[test]
+    fn orders_the_http_mirrors_by_priority() {
+        let parsed = Metalink::parse(&metalink(
+            "<url>https://last.example/os.img.xz</url>\
+             <url priority=\"2\">http://second.example/os.img.xz</url>\
+             <url priority=\"1\">ftp://ftp.example/os.img.xz</url>\
+             <url priority=\"1\">https://first.example/os.img.xz</url>\
+             <metaurl mediatype=\"torrent\">https://first.example/os.torrent</metaurl>",
+        ))
+        .unwrap();
+
+        assert_eq!(parsed.name, "os.img.xz");
+        assert_eq!(
+            parsed.urls,
+            [
+                "https://first.example/os.img.xz",
+                "http://second.example/os.img.xz",
+                "https://last.example/os.img.xz",
+            ]
+        );
+    }
+
+    #[test]
+    fn prefers_sha512_over_sha256() {
+        let parsed = Metalink::parse(&metalink(&format!(
+            "<size>1024</size>\
+             <hash type=\"sha-256\">{SHA256}</hash>\
+             <hash type=\"sha-512\">{SHA512}</hash>\
+             <url>https://example.org/os.img.xz</url>"
+        )))
+        .unwrap();
+
+        assert_eq!(parsed.size, Some(1024));
+        assert_eq!(parsed.checksum, Checksum::sha512(SHA512));
+
+        let parsed = Metalink::parse(&metalink(&format!(
+            "<hash type=\"md5\">098f6bcd4621d373cade4e832627b4f6</hash>\
+             <hash type=\"sha-256\">{SHA256}</hash>\
+             <url>https://example.org/os.img.xz</url>"
+        )))
+        .unwrap();
+
+        assert_eq!(parsed.checksum, Checksum::sha256(SHA256));
+    }
+
+    #[test]
+    fn leaves_out_a_missing_size_or_hash() {
+        let parsed = Metalink::parse(&metalink(
+            "<size>unknown</size>\
+             <hash type=\"md5\">098f6bcd4621d373cade4e832627b4f6</hash>\
+             <hash type=\"sha-256\">not hex</hash>\
+             <url>https://example.org/os.img.xz</url>",
+        ))
+        .unwrap();
+
+        assert_eq!(parsed.size, None);
+        assert_eq!(parsed.checksum, None);
+    }
+
+    #[test]
+    fn refuses_a_metalink_without_http_mirrors() {
+        for text in [
+            metalink("<url>ftp://ftp.example/os.img.xz</url>"),
+            "<metalink xmlns=\"urn:ietf:params:xml:ns:metalink\"/>".into(),
+            metalink("").replace(" name=\"os.img.xz\"", ""),
+            "not xml".into(),
+        ] {
+            let e = Metalink::parse(&text).unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidData, "{text}");
+        }
+    }
+}
@@ -1,112 +1,289 @@
 use std::{
-    cmp::min,
-    fs::{self, File},
-    hash::Hash,
+    fs::File,
     io::{self, BufReader, Read, Seek, Write},
+    sync::Arc,
 };
 
-use dbus_udisks2::DiskDevice;
-use iced::subscription;
-
-#[cfg(target_os = "linux")]
-use crate::linux::udisks_open;
-use crate::Progress;
-
-pub fn file<I: 'static + Hash + Copy + Send + Sync, T: ToString>(
-    id: I,
-    path: T,
-    dev: DiskDevice,
-) -> iced::Subscription<(I, Progress)> {
-    subscription::unfold(
-        id,
-        State::Ready(path.to_string(), Box::new(dev)),
-        move |state| read(id, state),
-    )
-}
+use reqwest::Client;
 
-async fn read<I: Copy>(id: I, state: State) -> (Option<(I, Progress)>, State) {
-    match state {
-        State::Ready(path, dev) => {
-            let content = match File::open(path) {
-                Ok(f) => f,
-                Err(_) => return (Some((id, Progress::Errored)), State::Finished),
-            };
+use crate::archive;
+use crate::backend::Backend;
+use crate::bmap::{self, Bmap, BmapWriter};
+use crate::checksum::{self, Checksum, Hasher};
+use crate::decompress::{self, Compression, Decoder};
+use crate::fanout::Fanout;
+use crate::target::Target;
+use crate::writer::{self, Cancel};
+use crate::{Progress, Report, WriteError, WriteOptions};
 
-            let total = match content.metadata() {
-                Ok(m) => m.len(),
-                Err(_) => return (Some((id, Progress::Errored)), State::Finished),
+/// Advances the write by one step. Returns the next state, or `None` once
+/// the write has ended on every device or it was cancelled.
+pub async fn read(state: State, cancel: &Cancel) -> (Vec<Report>, Option<State>) {
+    match state {
+        State::Ready {
+            path,
+            targets,
+            backend,
+            client,
+            options,
+        } => {
+            let checksum = match options
+                .checksum
+                .resolve(&client, checksum::file_name(&path))
+                .await
+            {
+                Ok(checksum) => checksum.map(|c| {
+                    let hasher = c.hasher();
+                    (c, hasher)
+                }),
+                Err(e) => {
+                    return failed(WriteError::Checksum {
+                        cause: e.to_string(),
+                    })
+                }
             };
 
-            let reader = BufReader::new(content);
-
-            let mut file = match udisks_open(&dev.parent.path) {
-                Ok(f) => f,
-                Err(_) => return (Some((id, Progress::Errored)), State::Finished),
+            let bmap = match options.bmap.clone().or_else(|| bmap::beside(&path)) {
+                Some(source) => match Bmap::load(&source, &client).await {
+                    Ok(bmap) => Some(bmap),
+                    Err(e) => {
+                        return failed(WriteError::Bmap {
+                            cause: e.to_string(),
+                        })
+                    }
+                },
+                None => None,
             };
 
-            let file_size = match fs::metadata(&dev.parent.device) {
-                Ok(x) => x.len(),
-                Err(_) => return (Some((id, Progress::Errored)), State::Finished),
+            let member = options.member.is_some();
+            let opened = writer::blocking(move || open(&path, member)).await;
+            let (reader, total, compression, uncompressed) = match opened {
+                Ok(opened) => opened,
+                Err(e) => return failed(e),
             };
 
-            if file.seek(io::SeekFrom::Start(file_size)).is_err() {
-                return (Some((id, Progress::Errored)), State::Finished);
+            let image = match compression {
+                Compression::None => Some(total),
+                _ => uncompressed,
             }
+            .or(bmap.as_ref().map(Bmap::image_size))
+            .or(options.size);
+
+            let sink = match Decoder::new(
+                compression,
+                BmapWriter::new(Fanout::open(targets, &backend, &options, image), bmap),
+                options.member,
+            ) {
+                Ok(sink) => Box::new(sink),
+                Err(e) => return failed(WriteError::from_sink(e, 0)),
+            };
 
             (
-                Some((id, Progress::Started)),
-                State::Reading {
+                vec![Report::all(Progress::Started)],
+                Some(State::Reading {
                     reader,
-                    file,
-                    total,
+                    sink,
+                    checksum,
+                    total: uncompressed.unwrap_or(total),
+                    uncompressed: uncompressed.is_some(),
                     read: 0,
-                },
+                }),
             )
         }
         State::Reading {
             mut reader,
-            mut file,
+            mut sink,
+            mut checksum,
             total,
+            uncompressed,
             read,
         } => {
-            let mut buffer = [0; 1048576];
-            let size = match reader.read(&mut buffer) {
-                Ok(size) => size,
-                Err(_) => return (Some((id, Progress::Errored)), State::Finished),
+            let fed = writer::blocking(move || {
+                let fed = feed(&mut reader, &mut sink, &mut checksum, read);
+                (reader, sink, checksum, fed)
+            });
+            let (reader, mut sink, checksum, size) = match fed.await {
+                (reader, sink, checksum, Ok(size)) => (reader, sink, checksum, size),
+                (.., Err(e)) => return failed(e),
             };
 
             if size == 0 {
-                return (Some((id, Progress::Finished)), State::Finished);
-            }
+                if let Some((checksum, source_hasher)) = checksum {
+                    if !checksum.matches(source_hasher) {
+                        let reports = sink
+                            .get_mut()
+                            .get_mut()
+                            .stop(Progress::ChecksumMismatch)
+                            .await;
+                        return (reports, None);
+                    }
+                }
+
+                let finished = writer::blocking(move || {
+                    sink.finish().and_then(|bmap_writer| bmap_writer.finish())
+                });
+                let mut fanout = match finished.await {
+                    Ok(fanout) => fanout,
+                    Err(e) => return failed(WriteError::from_sink(e, read)),
+                };
 
-            if file.write_all(&buffer).is_err() {
-                return (Some((id, Progress::Errored)), State::Finished);
+                fanout.finish().await;
+                return (vec![], Some(State::Finishing(fanout)));
             }
 
-            let new = min(read + buffer.len() as u64, total);
-            let percentage = (new as f32 / total as f32) * 100.0;
+            let new = read + size as u64;
+            let done = match uncompressed {
+                true => sink.get_ref().position(),
+                false => new,
+            };
+
+            let fanout = sink.get_mut().get_mut();
+            if cancel.until(fanout.drain()).await.is_none() {
+                return (fanout.stop(Progress::Cancelled).await, None);
+            }
+            let reports = fanout.reports(done, total, new);
+            if fanout.is_done() {
+                return (reports, None);
+            }
 
             (
-                Some((id, Progress::Advanced(percentage))),
-                State::Reading {
+                reports,
+                Some(State::Reading {
                     reader,
-                    file,
+                    sink,
+                    checksum,
                     total,
+                    uncompressed,
                     read: new,
-                },
+                }),
             )
         }
-        State::Finished => iced::futures::future::pending().await,
+        State::Finishing(fanout) => finishing(fanout).await,
+    }
+}
+
+/// Stops the write, flushing what was written so far to the devices.
+pub async fn cancel(state: State) -> Vec<Report> {
+    match state {
+        State::Ready { .. } => vec![Report::all(Progress::Cancelled)],
+        State::Reading { mut sink, .. } => sink.get_mut().get_mut().stop(Progress::Cancelled).await,
+        State::Finishing(mut fanout) => fanout.stop(Progress::Cancelled).await,
+    }
+}
+
+/// Stops writing to `device`, which was unplugged.
+pub fn remove(state: &mut State, device: usize) {
+    match state {
+        State::Ready { .. } => {}
+        State::Reading { sink, .. } => sink.get_mut().get_mut().remove(device),
+        State::Finishing(fanout) => fanout.remove(device),
     }
 }
 
 pub enum State {
-    Ready(String, Box<DiskDevice>),
+    Ready {
+        path: String,
+        targets: Vec<Target>,
+        backend: Arc<dyn Backend>,
+        client: Client,
+        options: WriteOptions,
+    },
     Reading {
         reader: BufReader<File>,
-        file: File,
+        sink: Box<Decoder<BmapWriter<Fanout>>>,
+        checksum: Option<(Checksum, Hasher)>,
         total: u64,
+        uncompressed: bool,
         read: u64,
     },
-    Finished,
+    /// The whole image is handed to the devices, which finish writing and
+    /// read it back.
+    Finishing(Fanout),
+}
+
+async fn finishing(mut fanout: Fanout) -> (Vec<Report>, Option<State>) {
+    let reports = fanout.wait().await;
+
+    match fanout.is_done() {
+        true => (reports, None),
+        false => (reports, Some(State::Finishing(fanout))),
+    }
+}
+
+/// Opens the image at `path` and finds out how it is compressed. Returns it
+/// with its size, its compression and the size it decompresses to, where
+/// the container records that. A zip archive has to hold a single image,
+/// unless the `member` to write is named.
+fn open(
+    path: &str,
+    member: bool,
+) -> Result<(BufReader<File>, u64, Compression, Option<u64>), WriteError> {
+    let source = |e: io::Error| WriteError::Source {
+        cause: e.to_string(),
+    };
+
+    let mut content = File::open(path).map_err(source)?;
+    let total = content.metadata().map_err(source)?.len();
+
+    let mut head = Vec::new();
+    if let Err(e) = (&mut content).take(6).read_to_end(&mut head) {
+        return Err(WriteError::Read {
+            cause: e.to_string(),
+            offset: 0,
+        });
+    }
+    let compression = Compression::detect(&head, path);
+
+    // A zip archive can only be written unambiguously if it holds a single
+    // image or the entry names the one to use.
+    if compression == Compression::Zip && !member {
+        let images = archive::images(&mut content).map_err(source)?;
+        if images.len() != 1 {
+            return Err(WriteError::Source {
+                cause: format!(
+                    "the zip archive holds {} disk images, name the member to use",
+                    images.len()
+                ),
+            });
+        }
+    }
+
+    // Count progress in uncompressed bytes where the container records the
+    // size, otherwise in bytes read from the file.
+    let uncompressed = match compression {
+        Compression::Xz => decompress::xz_uncompressed_size(&mut content),
+        _ => None,
+    };
+
+    content.seek(io::SeekFrom::Start(0)).map_err(source)?;
+
+    Ok((BufReader::new(content), total, compression, uncompressed))
+}
+
+/// Reads the next chunk of the image, at `offset`, and hands it to the
+/// devices. Returns its size, 0 at the end of the image.
+fn feed(
+    reader: &mut BufReader<File>,
+    sink: &mut Decoder<BmapWriter<Fanout>>,
+    checksum: &mut Option<(Checksum, Hasher)>,
+    offset: u64,
+) -> Result<usize, WriteError> {
+    let mut buffer = vec![0; 1048576];
+    let size = reader.read(&mut buffer).map_err(|e| WriteError::Read {
+        cause: e.to_string(),
+        offset,
+    })?;
+
+    if let Some((_, source_hasher)) = checksum {
+        source_hasher.update(&buffer[..size]);
+    }
+
+    sink.write_all(&buffer[..size])
+        .map_err(|e| WriteError::from_sink(e, offset))?;
+
+    Ok(size)
+}
+
+fn failed(e: WriteError) -> (Vec<Report>, Option<State>) {
+    (vec![Report::all(Progress::Errored(e))], None)
 }
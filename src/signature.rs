@@ -0,0 +1,334 @@
+use std::{fs, io, path::PathBuf, process::Command};
+
+use reqwest::Client;
+use tempfile::TempDir;
+
+use crate::checksum::fetch;
+use crate::writer;
+use crate::DIRECTORY;
+
+/// Keyring used when an entry only names a fingerprint.
+const DEFAULT_KEYRING: &str = "trusted.gpg";
+
+/// A detached OpenPGP signature over a checksum file and the key it has to be
+/// made with.
+#[derive(Debug, Clone)]
+pub struct Signature {
+    pub url: String,
+    pub fingerprint: Option<String>,
+    pub keyring: Option<String>,
+}
+
+impl Signature {
+    /// Fetches the signature and checks it over `data` with `gpgv`.
+    pub async fn verify(&self, client: &Client, data: &[u8]) -> io::Result<()> {
+        let signature = fetch(client, &self.url).await.map_err(io::Error::other)?;
+
+        // A fresh directory only the user can enter, so nobody else can
+        // swap the files gpgv checks.
+        let dir = tempfile::Builder::new()
+            .prefix("linux_creation_tool-")
+            .tempdir()?;
+
+        let (this, data) = (self.clone(), data.to_vec());
+        writer::blocking(move || this.gpgv(&dir, &signature, &data)).await
+    }
+
+    fn gpgv(&self, dir: &TempDir, signature: &[u8], data: &[u8]) -> io::Result<()> {
+        let sig_path = dir.path().join("checksums.sig");
+        let data_path = dir.path().join("checksums");
+        fs::write(&sig_path, signature)?;
+        fs::write(&data_path, data)?;
+
+        let output = Command::new("gpgv")
+            .arg("--status-fd")
+            .arg("1")
+            .arg("--keyring")
+            .arg(self.keyring())
+            .arg(&sig_path)
+            .arg(&data_path)
+            .output()?;
+
+        if !output.status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad signature on {}", self.url),
+            ));
+        }
+
+        let fingerprint = match &self.fingerprint {
+            None => return Ok(()),
+            Some(f) => normalize(f),
+        };
+
+        let status = String::from_utf8_lossy(&output.stdout);
+        let trusted = status
+            .lines()
+            .filter_map(|l| l.strip_prefix("[GNUPG:] VALIDSIG "))
+            .any(|l| {
+                let fields: Vec<&str> = l.split_whitespace().collect();
+
+                // The signing (sub)key comes first, the primary key last.
+                fields.first() == Some(&fingerprint.as_str())
+                    || fields.last() == Some(&fingerprint.as_str())
+            });
+
+        match trusted {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is not signed by {}", self.url, fingerprint),
+            )),
+        }
+    }
+
+    fn keyring(&self) -> PathBuf {
+        let keyring = self.keyring.as_deref().unwrap_or(DEFAULT_KEYRING);
+
+        match keyring.starts_with('/') {
+            true => PathBuf::from(keyring),
+            false => PathBuf::from(format!("{}{}", DIRECTORY, keyring)),
+        }
+    }
+}
+
+fn normalize(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::path::Path;
+    use std::process::Command;
+
+    use reqwest::Client;
+    use tempfile::TempDir;
+
+    use super::Signature;
+    use crate::checksum::{Checksum, Expected};
+    use crate::testing::Server;
+
+    const DIGEST: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    /// A throwaway key in a home of its own, and the public keyring gpgv
+    /// checks against.
+    struct Key {
+        home: TempDir,
+        fingerprint: String,
+    }
+
+    impl Key {
+        /// Needs gpg, the tests fail without it rather than pass unchecked.
+        fn generate() -> Self {
+            let home = TempDir::new().unwrap();
+            let gpg = |args: &[&str]| {
+                Command::new("gpg")
+                    .arg("--batch")
+                    .arg("--homedir")
+                    .arg(home.path())
+                    .args(args)
+                    .output()
+            };
+
+            let generated = gpg(&[
+                "--passphrase",
+                "",
+                "--quick-gen-key",
+                "Test <test@example.org>",
+                "ed25519",
+                "sign",
+                "never",
+            ])
+            .expect("the signature tests need gpg");
+            assert!(
+                generated.status.success(),
+                "gpg could not generate a key: {}",
+                String::from_utf8_lossy(&generated.stderr)
+            );
+
+            let listed = gpg(&["--with-colons", "--list-keys"]).unwrap();
+            let fingerprint = String::from_utf8_lossy(&listed.stdout)
+                .lines()
+                .find_map(|l| l.strip_prefix("fpr:"))
+                .map(|l| l.trim_matches(':').to_string())
+                .expect("gpg lists no key");
+
+            let exported = gpg(&["--export"]).unwrap();
+            std::fs::write(home.path().join("keyring.gpg"), exported.stdout).unwrap();
+
+            Self { home, fingerprint }
+        }
+
+        fn sign(&self, data: &[u8]) -> Vec<u8> {
+            let path = self.home.path().join("data");
+            std::fs::write(&path, data).unwrap();
+
+            let signed = Command::new("gpg")
+                .arg("--batch")
+                .arg("--homedir")
+                .arg(self.home.path())
+                .args(["--detach-sign", "--output", "-"])
+                .arg(&path)
+                .output()
+                .unwrap();
+            assert!(signed.status.success());
+
+            signed.stdout
+        }
+
+        fn keyring(&self) -> String {
+            self.home.path().join("keyring.gpg").display().to_string()
+        }
+    }
+
+    impl Drop for Key {
+        fn drop(&mut self) {
+            let _ = Command::new("gpgconf")
+                .arg("--homedir")
+                .arg(self.home.path())
+                .args(["--kill", "gpg-agent"])
+                .output();
+        }
+    }
+
+    /// Serves `sums` signed by `key` and resolves the checksum of
+    /// "image.iso" through them, pinned to `fingerprint`.
+    async fn resolve(
+        key: &Key,
+        sums: &[u8],
+        signed: &[u8],
+        fingerprint: Option<&str>,
+    ) -> io::Result<Option<Checksum>> {
+        resolve_with(key, sums, signed, fingerprint, None).await
+    }
+
+    /// As `resolve`, with the `sha256` of the entry set as well.
+    async fn resolve_with(
+        key: &Key,
+        sums: &[u8],
+        signed: &[u8],
+        fingerprint: Option<&str>,
+        sha256: Option<&str>,
+    ) -> io::Result<Option<Checksum>> {
+        let server = Server::start();
+        server.serve("/SHA256SUMS", sums);
+        server.serve("/SHA256SUMS.gpg", key.sign(signed).as_slice());
+
+        let expected = Expected {
+            sha256: sha256.map(String::from),
+            url: Some(server.url("/SHA256SUMS")),
+            signature: Some(Signature {
+                url: server.url("/SHA256SUMS.gpg"),
+                fingerprint: fingerprint.map(String::from),
+                keyring: Some(key.keyring()),
+            }),
+            ..Default::default()
+        };
+
+        expected.resolve(&Client::new(), "image.iso").await
+    }
+
+    #[tokio::test]
+    async fn accepts_a_good_signature() {
+        let key = Key::generate();
+        let sums = format!("{DIGEST}  image.iso\n");
+
+        let checksum = resolve(&key, sums.as_bytes(), sums.as_bytes(), None).await;
+        assert_eq!(checksum.unwrap(), Checksum::sha256(DIGEST));
+
+        // Fingerprints are compared without spaces and case.
+        let pinned = key.fingerprint.to_lowercase();
+        let checksum = resolve(&key, sums.as_bytes(), sums.as_bytes(), Some(&pinned)).await;
+        assert_eq!(checksum.unwrap(), Checksum::sha256(DIGEST));
+    }
+
+    #[tokio::test]
+    async fn rejects_a_changed_checksum_file() {
+        let key = Key::generate();
+        let sums = format!("{DIGEST}  image.iso\n");
+        let forged = format!("{}  image.iso\n", "0".repeat(64));
+
+        let e = resolve(&key, forged.as_bytes(), sums.as_bytes(), None)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rejects_another_key() {
+        let key = Key::generate();
+        let sums = format!("{DIGEST}  image.iso\n");
+
+        let e = resolve(
+            &key,
+            sums.as_bytes(),
+            sums.as_bytes(),
+            Some(&"A".repeat(40)),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn needs_a_checksum_file() {
+        let expected = Expected {
+            sha256: Some(DIGEST.into()),
+            signature: Some(Signature {
+                url: "http://127.0.0.1:9/SHA256SUMS.gpg".into(),
+                fingerprint: None,
+                keyring: None,
+            }),
+            ..Default::default()
+        };
+
+        assert!(expected.is_set());
+        let e = expected
+            .resolve(&Client::new(), "image.iso")
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn checks_the_signature_of_a_configured_digest() {
+        let key = Key::generate();
+        let sums = format!("{DIGEST}  image.iso\n");
+        let forged = format!("{}  image.iso\n", "0".repeat(64));
+
+        // The digest of the entry doesn't stand in for the signed file.
+        let e = resolve_with(&key, sums.as_bytes(), forged.as_bytes(), None, Some(DIGEST))
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        let other = "1".repeat(64);
+        let e = resolve_with(&key, sums.as_bytes(), sums.as_bytes(), None, Some(&other))
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        let checksum = resolve_with(&key, sums.as_bytes(), sums.as_bytes(), None, Some(DIGEST))
+            .await
+            .unwrap();
+        assert_eq!(checksum, Checksum::sha256(DIGEST));
+    }
+
+    #[test]
+    fn keyring_is_relative_to_the_config() {
+        let signature = Signature {
+            url: String::new(),
+            fingerprint: None,
+            keyring: None,
+        };
+        assert_eq!(
+            signature.keyring(),
+            Path::new("/etc/linux_creation_tool/trusted.gpg")
+        );
+    }
+}
@@ -0,0 +1,369 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use dbus_udisks2::DiskDevice;
+
+use crate::backend::Backend;
+use crate::{Progress, WriteError, WriteOptions};
+
+/// What an image is written to.
+#[derive(Debug, Clone)]
+pub enum Target {
+    /// A block device of the backend.
+    Device(Box<DiskDevice>),
+    /// A regular file, which is created or emptied and grows to the size of
+    /// the image. It can be flashed later on. Anything but a regular file,
+    /// such as a device node or a symlink, is refused.
+    File(PathBuf),
+    /// An existing regular file, attached as a loop device by the backend for
+    /// the write so it is written like a device of its size.
+    Loop(PathBuf),
+}
+
+impl Target {
+    /// The device node or path of the target.
+    pub fn name(&self) -> String {
+        match self {
+            Target::Device(dev) => dev.parent.device.display().to_string(),
+            Target::File(path) | Target::Loop(path) => path.display().to_string(),
+        }
+    }
+
+    /// How much the target holds, `None` if it grows with the image.
+    pub fn size(&self) -> Option<u64> {
+        match self {
+            Target::Device(dev) => Some(dev.parent.size),
+            Target::File(_) => None,
+            Target::Loop(path) => fs::metadata(path).ok().map(|m| m.len()),
+        }
+    }
+
+    /// Refuses the path of a file or loop target unless it is a regular file,
+    /// so a device node is never written without the checks a device gets.
+    /// A file target that doesn't exist yet is created.
+    pub fn check(&self) -> Result<(), WriteError> {
+        let (path, required) = match self {
+            Target::Device(_) => return Ok(()),
+            Target::File(path) => (path, false),
+            Target::Loop(path) => (path, true),
+        };
+
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_file() => Ok(()),
+            Ok(_) => Err(WriteError::NotAFile {
+                path: path.display().to_string(),
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => Ok(()),
+            Err(e) => Err(WriteError::Open {
+                cause: format!("{}: {e}", path.display()),
+            }),
+        }
+    }
+
+    /// The path of a file or loop target that exists and is written over.
+    pub fn existing_file(&self) -> Option<&Path> {
+        match self {
+            Target::File(path) | Target::Loop(path) if path.exists() => Some(path.as_path()),
+            _ => None,
+        }
+    }
+
+    /// Gets the target ready to be opened. A device must still be the one
+    /// that was chosen, must not hold the running system and has its
+    /// filesystems unmounted first.
+    pub(crate) fn attach(
+        &self,
+        backend: &Arc<dyn Backend>,
+        options: &WriteOptions,
+    ) -> Result<Attached, WriteError> {
+        self.check()?;
+
+        let kind = match self {
+            Target::Device(dev) => {
+                let unchanged = backend.is_unchanged(dev).map_err(|e| WriteError::Open {
+                    cause: e.to_string(),
+                })?;
+                if !unchanged {
+                    return Err(WriteError::Changed);
+                }
+
+                if !options.compare_only {
+                    let protected = backend.protection(dev).map_err(|e| WriteError::Open {
+                        cause: e.to_string(),
+                    })?;
+                    if let Some(reason) = protected {
+                        return Err(WriteError::Protected { reason });
+                    }
+
+                    release(backend.as_ref(), dev, options.unmount)?;
+                }
+
+                Kind::Device(dev.clone())
+            }
+            Target::File(path) => {
+                if !options.compare_only {
+                    open_file(path, true, true)?
+                        .set_len(0)
+                        .map_err(|e| WriteError::Open {
+                            cause: format!("{}: {e}", path.display()),
+                        })?;
+                }
+
+                Kind::File(path.clone())
+            }
+            Target::Loop(path) => {
+                let dev = backend.loop_setup(path).map_err(|e| WriteError::Open {
+                    cause: format!("{}: {e}", path.display()),
+                })?;
+
+                Kind::Loop(Box::new(dev))
+            }
+        };
+
+        Ok(Attached {
+            backend: backend.clone(),
+            kind,
+            writable: !options.compare_only,
+        })
+    }
+}
+
+/// A target that is ready to be opened, for writing and again for the
+/// read-back. A loop device is detached once this is dropped.
+pub(crate) struct Attached {
+    backend: Arc<dyn Backend>,
+    kind: Kind,
+    /// Whether it is opened for writing, which comparing doesn't need.
+    writable: bool,
+}
+
+enum Kind {
+    Device(Box<DiskDevice>),
+    File(PathBuf),
+    /// The loop device the file is attached as.
+    Loop(Box<DiskDevice>),
+}
+
+impl Attached {
+    pub(crate) fn open(&self) -> Result<File, WriteError> {
+        match &self.kind {
+            Kind::Device(dev) | Kind::Loop(dev) => {
+                self.backend
+                    .open(dev, self.writable)
+                    .map_err(|e| WriteError::Open {
+                        cause: e.to_string(),
+                    })
+            }
+            Kind::File(path) => open_file(path, false, self.writable),
+        }
+    }
+
+    /// Ejects a device that was written successfully if asked to. If that
+    /// fails the write still finished, the device just can't be pulled right
+    /// away.
+    pub(crate) fn finish(&self, progress: Progress, options: &WriteOptions) -> Progress {
+        let dev = match &self.kind {
+            Kind::Device(dev) if !options.compare_only => dev,
+            _ => return progress,
+        };
+
+        if options.eject && matches!(progress, Progress::Finished) {
+            if let Ok(true) = self.backend.eject(dev) {
+                return Progress::Ejected;
+            }
+        }
+
+        // The new partitions show up without replugging. It doesn't matter
+        // to the write if that fails.
+        let _ = self.backend.rescan(dev);
+        progress
+    }
+}
+
+impl Drop for Attached {
+    fn drop(&mut self) {
+        if let Kind::Loop(dev) = &self.kind {
+            let _ = self.backend.loop_delete(dev);
+        }
+    }
+}
+
+/// Opens the regular file at `path` without following a symlink, so it can't
+/// be swapped for a device after it was checked. It is only created if
+/// `create`, and only opened for writing if `writable`.
+fn open_file(path: &Path, create: bool, writable: bool) -> Result<File, WriteError> {
+    let open = |path: &Path| {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .create(create)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)?;
+        Ok::<_, io::Error>((file.metadata()?.file_type().is_file(), file))
+    };
+
+    match open(path) {
+        Ok((true, file)) => Ok(file),
+        Ok((false, _)) => Err(WriteError::NotAFile {
+            path: path.display().to_string(),
+        }),
+        Err(e) => Err(WriteError::Open {
+            cause: format!("{}: {e}", path.display()),
+        }),
+    }
+}
+
+/// Makes sure nothing on `dev` is mounted, which would write over the image
+/// later on. Only unmounts it if `allowed`.
+pub fn release(backend: &dyn Backend, dev: &DiskDevice, allowed: bool) -> Result<(), WriteError> {
+    let mounted = backend.mounts(dev).map_err(|e| WriteError::Unmount {
+        cause: e.to_string(),
+    })?;
+
+    if !mounted.is_empty() && !allowed {
+        return Err(WriteError::Mounted {
+            mount_points: mounted
+                .iter()
+                .flat_map(|m| &m.mount_points)
+                .map(|p| p.display().to_string())
+                .collect(),
+        });
+    }
+
+    for mount in &mounted {
+        backend.unmount(mount).map_err(|e| WriteError::Unmount {
+            cause: format!("{}: {e}", mount.device.display()),
+        })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use reqwest::Client;
+    use tempfile::TempDir;
+
+    use super::Target;
+    use crate::backend::{Backend, Mock};
+    use crate::testing::{image, run};
+    use crate::writer::Job;
+    use crate::{Progress, Source, WriteError, WriteOptions};
+
+    /// Writes the image at `path` to `targets` and returns how each ended.
+    async fn write(path: &Path, targets: Vec<Target>) -> Vec<Progress> {
+        let backend: Arc<dyn Backend> = Arc::new(Mock::new());
+        let options = WriteOptions {
+            verify: true,
+            ..Default::default()
+        };
+        let source = Source::File(path.display().to_string());
+        let devices = targets.len();
+
+        let job = Job::new(source, targets, Client::new(), options).with_backend(backend);
+        run(job, devices).await
+    }
+
+    #[tokio::test]
+    async fn writes_files_and_reads_them_back() {
+        let dir = TempDir::new().unwrap();
+        let (path, data) = image(dir.path());
+
+        let new = dir.path().join("new.img");
+        let existing = dir.path().join("existing.img");
+        fs::write(&existing, vec![0xff; 5 << 20]).unwrap();
+
+        let results = write(
+            &path,
+            vec![Target::File(new.clone()), Target::File(existing.clone())],
+        )
+        .await;
+
+        assert!(matches!(
+            results[..],
+            [Progress::Finished, Progress::Finished]
+        ));
+        assert!(fs::read(new).unwrap() == data);
+        assert!(fs::read(existing).unwrap() == data);
+    }
+
+    #[tokio::test]
+    async fn writes_loop_devices_like_devices() {
+        let dir = TempDir::new().unwrap();
+        let (path, data) = image(dir.path());
+
+        let backing = dir.path().join("stick.img");
+        fs::write(&backing, vec![0xff; 4 << 20]).unwrap();
+
+        let results = write(&path, vec![Target::Loop(backing.clone())]).await;
+        assert!(matches!(results[..], [Progress::Finished]));
+
+        let written = fs::read(backing).unwrap();
+        assert_eq!(written.len(), 4 << 20);
+        assert!(written[..data.len()] == data);
+        assert!(written[data.len()..].iter().all(|b| *b == 0xff));
+    }
+
+    #[tokio::test]
+    async fn refuses_anything_but_regular_files() {
+        let dir = TempDir::new().unwrap();
+        let (path, _) = image(dir.path());
+
+        let victim = dir.path().join("victim");
+        fs::write(&victim, b"keep").unwrap();
+        let link = dir.path().join("link.img");
+        symlink(&victim, &link).unwrap();
+
+        for target in [
+            Target::File("/dev/null".into()),
+            Target::File(link.clone()),
+            Target::File(dir.path().to_path_buf()),
+            Target::Loop(link.clone()),
+        ] {
+            assert!(matches!(target.check(), Err(WriteError::NotAFile { .. })));
+        }
+        assert!(Target::File(dir.path().join("new.img")).check().is_ok());
+        assert!(Target::Loop(dir.path().join("new.img")).check().is_err());
+
+        let results = write(&path, vec![Target::File(link)]).await;
+        assert!(matches!(
+            results[..],
+            [Progress::Errored(WriteError::NotAFile { .. })]
+        ));
+        assert_eq!(fs::read(victim).unwrap(), b"keep");
+    }
+
+    #[test]
+    fn opens_targets_read_only_to_compare() {
+        let dir = TempDir::new().unwrap();
+        let backend: Arc<dyn Backend> = Arc::new(Mock::new());
+        let file = dir.path().join("stick.img");
+
+        for compare_only in [false, true] {
+            fs::write(&file, vec![0xff; 1 << 20]).unwrap();
+            let options = WriteOptions {
+                compare_only,
+                ..Default::default()
+            };
+
+            for target in [Target::File(file.clone()), Target::Loop(file.clone())] {
+                let opened = target.attach(&backend, &options).unwrap().open().unwrap();
+                let flags = unsafe { libc::fcntl(opened.as_raw_fd(), libc::F_GETFL) };
+                let read_only = flags & libc::O_ACCMODE == libc::O_RDONLY;
+                assert_eq!(read_only, compare_only);
+            }
+        }
+        assert!(fs::read(file).unwrap() == vec![0xff; 1 << 20]);
+    }
+}
@@ -0,0 +1,265 @@
+//! Stand-ins for servers, images and sticks in tests.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use dbus_udisks2::{Block, DiskDevice, Drive};
+use futures::StreamExt;
+
+use crate::writer::Job;
+use crate::Progress;
+
+/// A file served by `Server`.
+#[derive(Debug, Clone, Default)]
+pub struct Served {
+    pub body: Vec<u8>,
+    pub etag: Option<String>,
+    /// Every connection is dropped after sending this many bytes of the body.
+    pub cut: Option<usize>,
+    /// Whether the connection is kept open without sending anything more
+    /// after `cut`, instead of dropped.
+    pub stall: bool,
+    /// Whether it is only sent once, later requests find nothing.
+    pub once: bool,
+    /// The status requests are answered with instead of the file, like 503.
+    pub status: Option<u16>,
+}
+
+impl From<&[u8]> for Served {
+    fn from(body: &[u8]) -> Self {
+        Self {
+            body: body.to_vec(),
+            ..Default::default()
+        }
+    }
+}
+
+/// An HTTP server on localhost that answers GET and HEAD requests for the
+/// files it was given, ranges included, one request per connection.
+pub struct Server {
+    base: String,
+    files: Arc<Mutex<HashMap<String, Served>>>,
+    /// The path and first byte asked for of every GET request.
+    requests: Arc<Mutex<Vec<(String, u64)>>>,
+}
+
+impl Server {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let files: Arc<Mutex<HashMap<String, Served>>> = Arc::default();
+        let requests: Arc<Mutex<Vec<(String, u64)>>> = Arc::default();
+
+        {
+            let files = files.clone();
+            let requests = requests.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let files = files.clone();
+                    let requests = requests.clone();
+                    thread::spawn(move || answer(stream, &files, &requests));
+                }
+            });
+        }
+
+        Self {
+            base,
+            files,
+            requests,
+        }
+    }
+
+    pub fn serve(&self, path: &str, served: impl Into<Served>) {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.into(), served.into());
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base)
+    }
+
+    pub fn requests(&self) -> Vec<(String, u64)> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn answer(
+    stream: TcpStream,
+    files: &Mutex<HashMap<String, Served>>,
+    requests: &Mutex<Vec<(String, u64)>>,
+) {
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    if reader.read_line(&mut line).is_err() {
+        return;
+    }
+    let mut words = line.split_whitespace();
+    let (method, path) = match (words.next(), words.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return,
+    };
+
+    let mut start = 0;
+    let mut if_range = None;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("range") {
+                start = value
+                    .trim()
+                    .trim_start_matches("bytes=")
+                    .trim_end_matches('-')
+                    .parse()
+                    .unwrap_or(0);
+            }
+            if name.eq_ignore_ascii_case("if-range") {
+                if_range = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    if method == "GET" {
+        requests.lock().unwrap().push((path.clone(), start));
+    }
+
+    let served = {
+        let mut files = files.lock().unwrap();
+        match files.get(&path) {
+            Some(served) if served.once => files.remove(&path),
+            served => served.cloned(),
+        }
+    };
+
+    // A range of another version of the file is answered with all of it.
+    if let (Some(served), Some(if_range)) = (&served, &if_range) {
+        if served.etag.as_ref() != Some(if_range) {
+            start = 0;
+        }
+    }
+    let mut stream = &stream;
+    let served = match served {
+        Some(Served {
+            status: Some(status),
+            ..
+        }) => {
+            let head = format!(
+                "HTTP/1.1 {status} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            );
+            let _ = stream.write_all(head.as_bytes());
+            return;
+        }
+        Some(served) if start as usize <= served.body.len() => served,
+        Some(_) => {
+            let _ = stream.write_all(b"HTTP/1.1 416 Range Not Satisfiable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+            return;
+        }
+        None => {
+            let _ = stream.write_all(
+                b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            );
+            return;
+        }
+    };
+
+    let len = served.body.len() as u64;
+    let mut head = match start {
+        0 => "HTTP/1.1 200 OK\r\n".to_string(),
+        _ => format!(
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {start}-{}/{len}\r\n",
+            len - 1
+        ),
+    };
+    head += &format!(
+        "Content-Length: {}\r\nAccept-Ranges: bytes\r\nConnection: close\r\n",
+        len - start
+    );
+    if let Some(etag) = &served.etag {
+        head += &format!("ETag: {etag}\r\n");
+    }
+    head += "\r\n";
+
+    if stream.write_all(head.as_bytes()).is_err() || method == "HEAD" {
+        return;
+    }
+
+    let body = &served.body[start as usize..];
+    let body = &body[..served.cut.unwrap_or(body.len()).min(body.len())];
+    let _ = stream.write_all(body);
+    let _ = stream.flush();
+
+    if served.stall {
+        thread::sleep(Duration::from_secs(600));
+    }
+}
+
+/// An image of a few MiB that doesn't repeat, so misplaced chunks show, in
+/// `dir`.
+pub fn image(dir: &Path) -> (PathBuf, Vec<u8>) {
+    let mut state = 1u32;
+    let data: Vec<u8> = (0..3 << 20 | 123)
+        .map(|_| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state >> 24) as u8
+        })
+        .collect();
+
+    let path = dir.join("image.img");
+    fs::write(&path, &data).unwrap();
+    (path, data)
+}
+
+/// A USB stick of `size` bytes for the `Mock` backend, backed by a file in
+/// `dir` that is filled with 0xff.
+pub fn stick(dir: &Path, name: &str, size: u64) -> (DiskDevice, PathBuf) {
+    let backing = dir.join(format!("{name}.img"));
+    fs::write(&backing, vec![0xff; size as usize]).unwrap();
+
+    let dev = DiskDevice {
+        drive: Drive {
+            path: format!("/mock/drives/{name}"),
+            id: name.into(),
+            connection_bus: "usb".into(),
+            ejectable: true,
+            serial: format!("{name}-serial"),
+            ..Default::default()
+        },
+        parent: Block {
+            path: format!("/mock/blocks/{name}"),
+            device: PathBuf::from(format!("/dev/{name}")),
+            size,
+            ..Default::default()
+        },
+        partitions: vec![],
+    };
+
+    (dev, backing)
+}
+
+/// Runs `job` to the end and returns how each of its `devices` ended.
+pub async fn run(job: Job, devices: usize) -> Vec<Progress> {
+    let mut results = vec![None; devices];
+    let mut reports = job.run();
+
+    while let Some(report) = reports.next().await {
+        for (i, result) in results.iter_mut().enumerate() {
+            if report.concerns(i) && report.progress.is_final() {
+                result.get_or_insert(report.progress.clone());
+            }
+        }
+    }
+
+    results.into_iter().map(Option::unwrap).collect()
+}
@@ -0,0 +1,259 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+/// Rates are recomputed at most this often so they don't jitter from chunk to
+/// chunk.
+const INTERVAL: Duration = Duration::from_millis(500);
+
+/// Weight of the newest sample in the smoothed rates.
+const SMOOTHING: f64 = 0.3;
+
+/// How far along a write or read-back is. Rates are in bytes per second.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Transfer {
+    pub percentage: f32,
+    pub done: u64,
+    pub total: u64,
+    /// Bytes written to the device, which differs from `done` for compressed
+    /// or sparse images.
+    pub written: u64,
+    /// Rate the source is downloaded or read at.
+    pub source_rate: f64,
+    pub write_rate: f64,
+    /// Estimated seconds left, once there is a rate to go by and the total is
+    /// known.
+    pub eta: Option<u64>,
+}
+
+impl fmt::Display for Transfer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of {}", bytes(self.done), bytes(self.total))?;
+
+        // Only show both rates when decompression or a bmap makes them
+        // differ noticeably.
+        let (low, high) = match self.source_rate < self.write_rate {
+            true => (self.source_rate, self.write_rate),
+            false => (self.write_rate, self.source_rate),
+        };
+        if self.write_rate > 0.0 && low < high * 0.9 {
+            write!(
+                f,
+                ", source {}/s, device {}/s",
+                bytes(self.source_rate as u64),
+                bytes(self.write_rate as u64)
+            )?;
+        } else if self.source_rate > 0.0 {
+            write!(f, ", {}/s", bytes(self.source_rate as u64))?;
+        }
+
+        if let Some(eta) = self.eta {
+            match eta {
+                s if s >= 3600 => {
+                    write!(f, ", {}:{:02}:{:02} left", s / 3600, s / 60 % 60, s % 60)?
+                }
+                s => write!(f, ", {}:{:02} left", s / 60, s % 60)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Turns running byte counts into `Transfer`s with smoothed rates.
+#[derive(Debug)]
+pub struct Meter {
+    last: Instant,
+    done: u64,
+    source: u64,
+    written: u64,
+    done_rate: f64,
+    source_rate: f64,
+    write_rate: f64,
+}
+
+impl Meter {
+    pub fn new() -> Self {
+        Self {
+            last: Instant::now(),
+            done: 0,
+            source: 0,
+            written: 0,
+            done_rate: 0.0,
+            source_rate: 0.0,
+            write_rate: 0.0,
+        }
+    }
+
+    /// `done` and `total` count progress, `source` bytes read or downloaded
+    /// and `written` bytes written to the device, all since the start.
+    pub fn update(&mut self, done: u64, total: u64, source: u64, written: u64) -> Transfer {
+        let elapsed = self.last.elapsed();
+
+        if elapsed >= INTERVAL {
+            let seconds = elapsed.as_secs_f64();
+            let first = self.done_rate == 0.0;
+
+            let smooth = |old: f64, new: u64, last: u64| {
+                let rate = new.saturating_sub(last) as f64 / seconds;
+                match first {
+                    true => rate,
+                    false => old + SMOOTHING * (rate - old),
+                }
+            };
+
+            self.done_rate = smooth(self.done_rate, done, self.done);
+            self.source_rate = smooth(self.source_rate, source, self.source);
+            self.write_rate = smooth(self.write_rate, written, self.written);
+
+            self.last = Instant::now();
+            self.done = done;
+            self.source = source;
+            self.written = written;
+        }
+
+        let eta = match self.done_rate > 0.0 && total > 0 {
+            true => Some((total.saturating_sub(done) as f64 / self.done_rate) as u64),
+            false => None,
+        };
+
+        Transfer {
+            percentage: match total {
+                0 => 0.0,
+                total => (done.min(total) as f32 / total as f32) * 100.0,
+            },
+            done,
+            total,
+            written,
+            source_rate: self.source_rate,
+            write_rate: self.write_rate,
+            eta,
+        }
+    }
+}
+
+impl Default for Meter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Formats a byte count for people.
+pub fn bytes(n: u64) -> String {
+    match n {
+        n if n >= 1 << 30 => format!("{:.1} GB", n as f64 / (1u64 << 30) as f64),
+        n if n >= 1 << 20 => format!("{:.1} MB", n as f64 / (1u64 << 20) as f64),
+        n if n >= 1 << 10 => format!("{:.1} KB", n as f64 / (1u64 << 10) as f64),
+        n => format!("{n} B"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{bytes, Meter, Transfer};
+
+    /// Has a second pass on `meter` before its next update.
+    fn second(meter: &mut Meter) {
+        meter.last = Instant::now() - Duration::from_secs(1);
+    }
+
+    fn close(rate: f64, expected: f64) -> bool {
+        (rate - expected).abs() <= expected * 0.02
+    }
+
+    #[test]
+    fn smooths_the_rates() {
+        let mut meter = Meter::new();
+
+        second(&mut meter);
+        let transfer = meter.update(1000, 100_000, 1000, 2000);
+        assert!(close(transfer.source_rate, 1000.0), "{transfer:?}");
+        assert!(close(transfer.write_rate, 2000.0), "{transfer:?}");
+
+        // Updates in between don't count as samples.
+        let transfer = meter.update(50_000, 100_000, 50_000, 50_000);
+        assert!(close(transfer.source_rate, 1000.0), "{transfer:?}");
+        assert_eq!((transfer.done, transfer.written), (50_000, 50_000));
+
+        second(&mut meter);
+        let transfer = meter.update(4000, 100_000, 4000, 2000);
+        assert!(
+            close(transfer.source_rate, 1000.0 + 0.3 * 2000.0),
+            "{transfer:?}"
+        );
+        assert!(
+            close(transfer.write_rate, 2000.0 - 0.3 * 2000.0),
+            "{transfer:?}"
+        );
+        assert_eq!(transfer.percentage, 4.0);
+    }
+
+    #[test]
+    fn estimates_the_time_left() {
+        let mut meter = Meter::new();
+        assert_eq!(meter.update(0, 100_000, 0, 0).eta, None);
+
+        second(&mut meter);
+        assert_eq!(meter.update(0, 100_000, 0, 0).eta, None);
+
+        second(&mut meter);
+        let eta = meter.update(10_000, 100_000, 10_000, 10_000).eta.unwrap();
+        assert!((8..=9).contains(&eta), "{eta}");
+
+        // Without a total there is nothing to estimate.
+        let mut meter = Meter::new();
+        second(&mut meter);
+        let transfer = meter.update(10_000, 0, 10_000, 10_000);
+        assert_eq!((transfer.eta, transfer.percentage), (None, 0.0));
+    }
+
+    #[test]
+    fn shows_the_progress_for_people() {
+        let transfer = Transfer {
+            done: 512 << 20,
+            total: 1 << 30,
+            source_rate: (10 << 20) as f64,
+            write_rate: (10 << 20) as f64,
+            eta: Some(75),
+            ..Default::default()
+        };
+        assert_eq!(
+            transfer.to_string(),
+            "512.0 MB of 1.0 GB, 10.0 MB/s, 1:15 left"
+        );
+
+        let transfer = Transfer {
+            source_rate: (2 << 20) as f64,
+            eta: Some(3725),
+            ..transfer
+        };
+        assert_eq!(
+            transfer.to_string(),
+            "512.0 MB of 1.0 GB, source 2.0 MB/s, device 10.0 MB/s, 1:02:05 left"
+        );
+
+        let transfer = Transfer {
+            write_rate: 0.0,
+            eta: None,
+            ..transfer
+        };
+        assert_eq!(transfer.to_string(), "512.0 MB of 1.0 GB, 2.0 MB/s");
+
+        assert_eq!(Transfer::default().to_string(), "0 B of 0 B");
+    }
+
+    #[test]
+    fn formats_byte_counts() {
+        assert_eq!(bytes(0), "0 B");
+        assert_eq!(bytes(1023), "1023 B");
+        assert_eq!(bytes(1536), "1.5 KB");
+        assert_eq!(bytes(1 << 20), "1.0 MB");
+        assert_eq!(bytes((1 << 30) - 1), "1024.0 MB");
+        assert_eq!(bytes(15_518_924_800), "14.5 GB");
+    }
+}
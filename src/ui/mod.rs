@@ -1,18 +1,27 @@
 mod snapping_scrollbar;
 
 use std::collections::HashMap;
+use std::sync::Arc;
 
+use crate::backend;
+use crate::cache::{Cache, Cached};
+use crate::target::Target;
+use crate::transfer::{self, Transfer};
 use crate::ui::snapping_scrollbar::SnappingScrollable;
+use crate::writer::{Cancel, Job};
 use crate::{
-    download, list_devices, load_config, read, OperatingSystemList, Progress, Source, DIRECTORY,
+    device_label, load_config, Backend, OperatingSystemList, Partition, Progress, Report, Source,
+    WriteError, DIRECTORY,
 };
 use dbus_udisks2::DiskDevice;
+use iced::futures::channel::mpsc::UnboundedReceiver;
+use iced::futures::StreamExt;
 use iced::Theme;
 use iced::{
     alignment::Horizontal,
-    executor,
-    widget::{Button, Column, Image, PickList, Row, Space, Text},
-    Application, Command, ContentFit, Element, Length, Padding, Subscription,
+    executor, subscription,
+    widget::{Button, Checkbox, Column, Image, Row, Space, Text},
+    Application, Color, Command, ContentFit, Element, Length, Padding, Subscription,
 };
 use iced_native::widget::ProgressBar;
 use image::io::Reader as ImageReader;
@@ -20,56 +29,99 @@ use reqwest::Client;
 
 pub struct App {
     client: Client,
+    backend: Arc<dyn Backend>,
     os_list: Option<OperatingSystemList>,
     disks: HashMap<String, DiskDevice>,
-    disk_labels: Vec<String>,
-    downloads: Option<Download>,
-    reads: Option<Read>,
+    /// The keys of `disks`, in the order they are shown.
+    disk_ids: Vec<String>,
+    /// Why devices holding the running system can't be chosen, by id.
+    protected: HashMap<String, String>,
+    running: Option<Running>,
     last_id: usize,
     states: AppStates,
     images: Vec<String>,
+    cache: Cache,
+    cached: Vec<Cached>,
+    /// The size of the selected image, once known.
+    image_size: Option<u64>,
 }
 
 #[derive(Debug, Clone)]
 pub enum Message {
     StartWriting,
-    SelectDevice(String),
+    CancelWriting,
+    ClearCache,
+    ToggleDevice(String, bool),
+    ToggleVerify(bool),
+    ToggleEject(bool),
+    /// Whether the user agreed to overwrite the devices.
+    Confirm(bool),
+    /// Whether the user agreed to unmount what is mounted on them.
+    ToggleUnmount(bool),
+    /// Whether the user understood that the large device is overwritten.
+    ConfirmLarge(bool),
+    DevicesChanged,
+    ImageSize(usize, Option<u64>),
     Scrolled(usize),
-    Download(DownloadMessage),
-    Read(DownloadMessage),
+    /// A report of the job with the id.
+    Progressed((usize, Report)),
     None,
 }
 
-#[derive(Debug, Clone)]
-pub enum DownloadMessage {
-    Download(usize),
-    DownloadProgressed((usize, Progress)),
-}
-
 #[derive(Default, Debug)]
 struct AppStates {
     error_message: Vec<String>,
     selected_region: usize,
-    selected_device: Option<String>,
+    selected_devices: Vec<String>,
+    verify: bool,
+    eject: bool,
+    /// While asking whether to destroy what is on the selected devices.
+    confirm: Option<Confirm>,
+    /// What the user agreed to, for the next start only.
+    confirmed: Option<Confirm>,
+    /// A large device picked, while asking whether it really is to be
+    /// overwritten.
+    large: Option<String>,
+}
+
+/// Asking the user whether to destroy what is on some devices.
+#[derive(Debug)]
+struct Confirm {
+    /// The ids of the devices, the answer holds for exactly these.
+    ids: Vec<String>,
+    /// Their labels and what is on them.
+    devices: Vec<(String, Vec<Partition>)>,
+    /// Whether mounted filesystems on them may be unmounted, which is asked
+    /// for separately.
+    unmount: bool,
 }
 
 pub struct Flags {
     client: Client,
-    config: &'static str,
+    config: String,
+    backend: Arc<dyn Backend>,
 }
 
 impl Flags {
-    pub fn new(client: Client, config: &'static str) -> Self {
-        Flags { client, config }
+    pub fn new(client: Client, config: impl Into<String>) -> Self {
+        Flags {
+            client,
+            config: config.into(),
+            backend: backend::detect(),
+        }
+    }
+
+    /// Finds and handles the devices through `backend` instead of the one
+    /// detected.
+    pub fn with_backend(mut self, backend: Arc<dyn Backend>) -> Self {
+        self.backend = backend;
+        self
     }
 }
 
 impl Default for Flags {
     fn default() -> Self {
-        Self {
-            client: Client::new(),
-            config: "config.json",
-        }
+        Self::new(Client::new(), "config.json")
     }
 }
 
@@ -80,18 +132,23 @@ impl Application for App {
     type Theme = Theme;
 
     fn new(flags: Self::Flags) -> (Self, Command<Self::Message>) {
-        let dev = list_devices().unwrap();
-        let mut labels = Vec::new();
-
-        dev.iter().for_each(|(l, _)| labels.push(l.clone()));
-
-        let (os_list, images) = match load_config(flags.config) {
+        let mut error_message = vec![];
+        let dev = flags.backend.list_devices().unwrap_or_else(|e| {
+            error_message.push(format!("Failed to list devices: {e}"));
+            HashMap::new()
+        });
+        let ids = disk_ids(&dev);
+        let protected = protections(flags.backend.as_ref(), &dev);
+
+        let (os_list, images) = match load_config(&flags.config) {
             Ok(c) => {
                 let len = c.as_vec().len();
                 let mut images = Vec::with_capacity(len);
                 c.as_vec().iter().for_each(|os| {
                     images.push(match os.pic() {
-                        Source::Url(_) => format!("{}{}", DIRECTORY, "pictures/missing.png"),
+                        Source::Url(_) | Source::Mirrors(_) | Source::Metalink(_) => {
+                            format!("{}{}", DIRECTORY, "pictures/missing.png")
+                        }
                         Source::File(path) => {
                             let path = format!("{}{}", DIRECTORY, &path);
                             match ImageReader::open(&path) {
@@ -115,19 +172,35 @@ impl Application for App {
             }
         };
 
+        let cache = os_list
+            .as_ref()
+            .unwrap_or(&OperatingSystemList::empty())
+            .cache();
+        let cached = cache.list().unwrap_or_default();
+
         let app = Self {
             client: flags.client,
+            backend: flags.backend,
             os_list,
             disks: dev,
-            disk_labels: labels,
-            downloads: None,
-            reads: None,
+            disk_ids: ids,
+            protected,
+            running: None,
             last_id: 0,
-            states: AppStates::default(),
+            states: AppStates {
+                verify: true,
+                eject: true,
+                error_message,
+                ..Default::default()
+            },
             images,
+            cache,
+            cached,
+            image_size: None,
         };
 
-        (app, Command::none())
+        let command = app.look_up_size();
+        (app, command)
     }
 
     fn title(&self) -> String {
@@ -138,6 +211,8 @@ impl Application for App {
         return match message {
             Message::StartWriting => {
                 self.states.error_message = vec![];
+                // Taken right away, so no way out of here leaves it behind.
+                let confirmed = self.states.confirmed.take();
 
                 let os_list = match &self.os_list {
                     None => {
@@ -154,60 +229,185 @@ impl Application for App {
                     Some(os) => os,
                 };
 
-                let device = match self
-                    .disks
-                    .get(&self.states.selected_device.clone().unwrap_or("".into()))
-                {
-                    None => {
+                let devs: Option<Vec<DiskDevice>> = self
+                    .states
+                    .selected_devices
+                    .iter()
+                    .map(|id| self.disks.get(id).cloned())
+                    .collect();
+
+                let devs = match devs {
+                    Some(devs) if !devs.is_empty() => devs,
+                    _ => {
                         self.states
                             .error_message
                             .push("Failed to get device".into());
                         return Command::none();
                     }
-                    Some(dev) => dev,
+                };
+                let labels = devs.iter().map(device_label).collect();
+
+                let confirmed = match confirmed {
+                    Some(confirmed) if confirmed.ids == self.states.selected_devices => confirmed,
+                    _ => {
+                        let mut devices = vec![];
+                        for dev in &devs {
+                            match self.backend.partitions(dev) {
+                                Ok(partitions) => devices.push((device_label(dev), partitions)),
+                                Err(e) => {
+                                    self.states
+                                        .error_message
+                                        .push(format!("Failed to look at the device: {e}"));
+                                    return Command::none();
+                                }
+                            }
+                        }
+
+                        self.states.confirm = Some(Confirm {
+                            ids: self.states.selected_devices.clone(),
+                            devices,
+                            unmount: false,
+                        });
+                        return Command::none();
+                    }
                 };
 
-                self.last_id += 1;
-                return match os.source.clone() {
-                    Source::Url(url) => {
-                        let mut download =
-                            Download::new(self.last_id, url, device.clone(), self.client.clone());
-                        download.start();
+                let mut options = os.options(self.states.verify);
+                options.unmount = confirmed.unmount;
+                options.eject = self.states.eject;
+
+                let job = Job::new(
+                    os.source.clone(),
+                    devs.iter()
+                        .map(|dev| Target::Device(Box::new(dev.clone())))
+                        .collect(),
+                    self.client.clone(),
+                    options,
+                )
+                .with_backend(self.backend.clone())
+                .with_cache(self.cache.clone());
 
-                        self.downloads = Some(download);
+                self.last_id += 1;
+                let mut running = Running::new(self.last_id, labels, devs, job);
+                running.start();
+                self.running = Some(running);
 
-                        Command::none()
-                    }
-                    Source::File(path) => {
-                        let mut read = Read::new(self.last_id, path, device.clone());
-                        read.start();
+                Command::none()
+            }
+            Message::CancelWriting => {
+                if let Some(running) = &self.running {
+                    running.cancel();
+                }
 
-                        self.reads = Some(read);
+                Command::none()
+            }
+            Message::ClearCache => {
+                if let Err(e) = self.cache.clear() {
+                    self.states
+                        .error_message
+                        .push(format!("Failed to clear the cache: {e}"));
+                }
+                self.cached = self.cache.list().unwrap_or_default();
 
-                        Command::none()
+                Command::none()
+            }
+            Message::ToggleDevice(id, selected) => {
+                self.states.selected_devices.retain(|i| *i != id);
+                if selected && self.protected.contains_key(&id) {
+                    return Command::none();
+                }
+                if selected && self.disks.get(&id).is_some_and(|dev| self.is_large(dev)) {
+                    self.states.large = Some(id);
+                    return Command::none();
+                }
+                if selected {
+                    self.states.selected_devices.push(id);
+                }
+                Command::none()
+            }
+            Message::ConfirmLarge(confirmed) => {
+                if let Some(id) = self.states.large.take() {
+                    if confirmed {
+                        self.states.selected_devices.push(id);
                     }
-                };
+                }
+                Command::none()
             }
-            Message::SelectDevice(label) => {
-                self.states.selected_device = Some(label);
+            Message::ToggleVerify(verify) => {
+                self.states.verify = verify;
+                Command::none()
+            }
+            Message::ToggleEject(eject) => {
+                self.states.eject = eject;
+                Command::none()
+            }
+            Message::Confirm(confirmed) => {
+                let confirm = self.states.confirm.take();
+                if confirmed {
+                    self.states.confirmed = confirm;
+                    return self.update(Message::StartWriting);
+                }
+
+                Command::none()
+            }
+            Message::ToggleUnmount(unmount) => {
+                if let Some(confirm) = &mut self.states.confirm {
+                    confirm.unmount = unmount;
+                }
+                Command::none()
+            }
+            Message::DevicesChanged => {
+                match self.backend.list_devices() {
+                    Ok(disks) => {
+                        self.disk_ids = disk_ids(&disks);
+                        self.protected = protections(self.backend.as_ref(), &disks);
+                        prune(&mut self.states.selected_devices, &disks, &self.protected);
+                        self.disks = disks;
+                    }
+                    Err(e) => self
+                        .states
+                        .error_message
+                        .push(format!("Failed to list devices: {e}")),
+                }
+
+                if let Some(running) = &self.running {
+                    running.unplugged(&self.disks);
+                }
+
                 Command::none()
             }
             Message::Scrolled(region) => {
+                if region == self.states.selected_region {
+                    return Command::none();
+                }
+
                 self.states.selected_region = region;
+                self.image_size = None;
 
-                Command::none()
+                self.look_up_size()
             }
-            Message::Download(DownloadMessage::DownloadProgressed((id, progress))) => {
-                if let Some(download) = self.downloads.iter_mut().find(|download| download.id == id)
-                {
-                    download.progress(progress);
+            Message::ImageSize(region, size) => {
+                if region == self.states.selected_region {
+                    self.image_size = size;
+
+                    let disks = &self.disks;
+                    let keep = |id: &String| disks.get(id).is_some_and(|dev| fits(size, dev));
+                    self.states.selected_devices.retain(keep);
                 }
 
                 Command::none()
             }
-            Message::Read(DownloadMessage::DownloadProgressed((id, progress))) => {
-                if let Some(read) = self.reads.iter_mut().find(|read| read.id == id) {
-                    read.progress(progress);
+            Message::Progressed((id, report)) => {
+                if let Some(running) = self.running.iter_mut().find(|running| running.id == id) {
+                    let ended = report.progress.is_final();
+
+                    let errors = running.progress(report);
+                    self.states.error_message.extend(errors);
+
+                    // A download may have added to the cache.
+                    if ended {
+                        self.cached = self.cache.list().unwrap_or_default();
+                    }
                 }
 
                 Command::none()
@@ -217,13 +417,10 @@ impl Application for App {
     }
 
     fn subscription(&self) -> Subscription<Message> {
-        let mut subs: Vec<Subscription<Message>> = vec![];
+        let mut subs: Vec<Subscription<Message>> = vec![devices(self.backend.clone())];
 
-        if let Some(download) = &self.downloads {
-            subs.push(download.subscription());
-        }
-        if let Some(read) = &self.reads {
-            subs.push(read.subscription());
+        if let Some(running) = &self.running {
+            subs.push(running.subscription());
         }
 
         Subscription::batch(subs)
@@ -261,41 +458,79 @@ impl Application for App {
                 .height(Length::FillPortion(50))
                 .on_scroll(|region| Message::Scrolled(region.1));
 
-        let dev_list = PickList::new(
-            &self.disk_labels,
-            self.states.selected_device.clone(),
-            Message::SelectDevice,
-        )
-        .placeholder("Choose a device ...");
+        let mut dev_list = Column::new();
+        for id in &self.disk_ids {
+            let dev = &self.disks[id];
+            let label = device_label(dev);
+
+            let unusable = match self.protected.get(id) {
+                Some(reason) => Some(format!("protected, {reason}")),
+                None if !fits(self.image_size, dev) => Some("too small".to_string()),
+                None => None,
+            };
+
+            if let Some(why) = unusable {
+                let grey = Color::from_rgb(0.5, 0.5, 0.5);
+                dev_list = dev_list.push(Text::new(format!("{label} ({why})")).style(grey));
+                continue;
+            }
+
+            let selected = self.states.selected_devices.contains(id);
+            let id = id.clone();
+            dev_list = dev_list.push(Checkbox::new(selected, label, move |selected| {
+                Message::ToggleDevice(id.clone(), selected)
+            }));
+        }
 
         let start_button =
             Button::new(Text::new("Write ISO to drive...")).on_press(Message::StartWriting);
 
-        let mut row = Row::new().push(dev_list);
+        let verify_checkbox = Checkbox::new(self.states.verify, "Verify", Message::ToggleVerify);
+
+        let eject_checkbox = Checkbox::new(self.states.eject, "Eject", Message::ToggleEject);
 
-        let state = match &self.downloads {
-            None => &State::Idle,
-            Some(d) => d.state(),
+        let cancel_button = Button::new(Text::new("Cancel")).on_press(Message::CancelWriting);
+
+        let running = match &self.running {
+            Some(running) if running.is_active() => Some((running.labels(), running.states())),
+            _ => None,
         };
 
-        match state {
-            State::Progressing { progress } => {
-                row = row.push(ProgressBar::new(0.0..=100.0, *progress));
-            }
-            _ => {
-                let state = match &self.reads {
-                    None => &State::Idle,
-                    Some(d) => d.state(),
+        let row = match (running, &self.states.confirm) {
+            (Some((labels, states)), _) => Row::new()
+                .push(devices_view(labels, states))
+                .push(cancel_button),
+            (None, _) if self.states.large.is_some() => {
+                let dev = self.states.large.as_ref().and_then(|id| self.disks.get(id));
+                let warning = match dev {
+                    Some(dev) => format!(
+                        "{} is {}, more than a USB stick usually holds. Everything on it will be \
+                         destroyed.",
+                        device_label(dev),
+                        transfer::bytes(dev.parent.size)
+                    ),
+                    None => "The device is gone.".to_string(),
                 };
 
-                match state {
-                    State::Progressing { progress } => {
-                        row = row.push(ProgressBar::new(0.0..=100.0, *progress))
-                    }
-                    _ => row = row.push(Space::with_width(Length::Fill)).push(start_button),
-                }
+                Row::new()
+                    .push(Text::new(warning).width(Length::Fill))
+                    .push(Button::new(Text::new("Cancel")).on_press(Message::ConfirmLarge(false)))
+                    .push(
+                        Button::new(Text::new("I understand"))
+                            .on_press(Message::ConfirmLarge(dev.is_some())),
+                    )
             }
-        }
+            (None, Some(confirm)) => Row::new()
+                .push(confirm_view(confirm))
+                .push(Button::new(Text::new("Cancel")).on_press(Message::Confirm(false)))
+                .push(Button::new(Text::new("Erase and write")).on_press(Message::Confirm(true))),
+            (None, None) => Row::new()
+                .push(dev_list)
+                .push(Space::with_width(Length::Fill))
+                .push(verify_checkbox)
+                .push(eject_checkbox)
+                .push(start_button),
+        };
 
         let mut col = Column::new()
             .width(Length::Fill)
@@ -305,6 +540,20 @@ impl Application for App {
             .push(scrolled_image)
             .push(row);
 
+        if !self.cached.is_empty() {
+            let names: Vec<String> = self
+                .cached
+                .iter()
+                .map(|image| format!("{} ({})", image.name, transfer::bytes(image.size)))
+                .collect();
+
+            let cache = Row::new()
+                .push(Text::new(format!("Cached: {}", names.join(", "))).width(Length::Fill))
+                .push(Button::new(Text::new("Clear cache")).on_press(Message::ClearCache));
+
+            col = col.push(cache);
+        }
+
         if !self.states.error_message.is_empty() {
             let mut errors = Column::new();
 
@@ -322,113 +571,409 @@ impl Application for App {
 #[derive(Debug)]
 enum State {
     Idle,
-    Progressing { progress: f32 },
+    Progressing { transfer: Transfer },
+    Verifying { transfer: Transfer },
     Finished,
-    Errored,
+    Ejected,
+    Mismatch,
+    ChecksumMismatch,
+    Cancelled,
+    Errored(WriteError),
+}
+
+impl State {
+    fn is_active(&self) -> bool {
+        matches!(self, State::Progressing { .. } | State::Verifying { .. })
+    }
+
+    fn progress(&mut self, new_progress: Progress) {
+        if let State::Progressing { transfer } | State::Verifying { transfer } = self {
+            match new_progress {
+                Progress::Started => *transfer = Transfer::default(),
+                Progress::Advanced(new) => *transfer = new,
+                Progress::Verifying(new) => *self = State::Verifying { transfer: new },
+                Progress::Finished => *self = State::Finished,
+                Progress::Ejected => *self = State::Ejected,
+                Progress::Mismatch => *self = State::Mismatch,
+                Progress::ChecksumMismatch => *self = State::ChecksumMismatch,
+                Progress::Cancelled => *self = State::Cancelled,
+                Progress::Errored(e) => *self = State::Errored(e),
+            }
+        }
+    }
+
+    fn error(&self) -> Option<String> {
+        match self {
+            State::Mismatch => {
+                Some("Verification failed: the data on the device does not match the image".into())
+            }
+            State::ChecksumMismatch => {
+                Some("The image does not match its published checksum".into())
+            }
+            State::Cancelled => Some(
+                "Writing was cancelled. The device is now in an undefined state and must be \
+                 written again before use."
+                    .into(),
+            ),
+            State::Errored(e) => Some(e.to_string()),
+            _ => None,
+        }
+    }
 }
 
+/// Writing the image to the chosen devices, whether it is read from a file
+/// or downloaded.
 #[derive(Debug)]
-struct Read {
+struct Running {
     id: usize,
-    dev: DiskDevice,
-    path: String,
-    state: State,
+    labels: Vec<String>,
+    devs: Vec<DiskDevice>,
+    /// Writes the image to `devs`, each run with a cancel handle of its own.
+    job: Job,
+    states: Vec<State>,
+    cancel: Cancel,
 }
 
-impl Read {
-    pub fn new(id: usize, path: String, dev: DiskDevice) -> Self {
-        Read {
+impl Running {
+    pub fn new(id: usize, labels: Vec<String>, devs: Vec<DiskDevice>, job: Job) -> Self {
+        Running {
             id,
-            dev,
-            path,
-            state: State::Idle,
+            states: devs.iter().map(|_| State::Idle).collect(),
+            labels,
+            devs,
+            job,
+            cancel: Cancel::default(),
         }
     }
 
     pub fn start(&mut self) {
-        match self.state {
-            State::Idle | State::Finished { .. } | State::Errored { .. } => {
-                self.state = State::Progressing { progress: 0.0 };
+        if !self.is_active() {
+            self.cancel = Cancel::default();
+            for state in &mut self.states {
+                *state = State::Progressing {
+                    transfer: Transfer::default(),
+                };
             }
-            _ => {}
         }
     }
 
-    pub fn progress(&mut self, new_progress: Progress) {
-        if let State::Progressing { progress } = &mut self.state {
-            match new_progress {
-                Progress::Started => *progress = 0.0,
-                Progress::Advanced(percentage) => *progress = percentage,
-                Progress::Finished => self.state = State::Finished,
-                Progress::Errored => self.state = State::Errored,
+    /// Applies `report` to the devices it concerns, and returns the errors of
+    /// those it ended.
+    pub fn progress(&mut self, report: Report) -> Vec<String> {
+        let mut errors = vec![];
+        for (i, state) in self.states.iter_mut().enumerate() {
+            if report.concerns(i) && state.is_active() {
+                state.progress(report.progress.clone());
+                if let Some(err) = state.error() {
+                    errors.push(format!("{}: {err}", self.labels[i]));
+                }
             }
         }
+
+        errors
     }
 
     pub fn subscription(&self) -> Subscription<Message> {
-        match self.state {
-            State::Progressing { .. } => read::file(self.id, &self.path, self.dev.clone())
-                .map(|p| Message::Read(DownloadMessage::DownloadProgressed(p))),
-            _ => Subscription::none(),
+        match self.is_active() {
+            true => job(self.id, self.job.clone().with_cancel(self.cancel.clone()))
+                .map(Message::Progressed),
+            false => Subscription::none(),
+        }
+    }
+
+    /// Asks the running job to stop. It reports `Cancelled` for each device
+    /// once it is flushed and closed, also for one that was written in full
+    /// but not read back yet, which is never ejected.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Stops writing to the devices that are no longer plugged in.
+    pub fn unplugged(&self, disks: &HashMap<String, DiskDevice>) {
+        for (i, (dev, state)) in self.devs.iter().zip(&self.states).enumerate() {
+            if state.is_active() && !disks.values().any(|d| d.parent.path == dev.parent.path) {
+                self.cancel.remove(i);
+            }
         }
     }
 
-    pub fn state(&self) -> &State {
-        &self.state
+    /// Whether any of the devices is still being written.
+    pub fn is_active(&self) -> bool {
+        self.states.iter().any(State::is_active)
+    }
+
+    pub fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    pub fn states(&self) -> &[State] {
+        &self.states
     }
 }
 
-#[derive(Debug)]
-struct Download {
-    id: usize,
-    dev: DiskDevice,
-    url: String,
-    state: State,
-    client: Client,
+/// Runs `job` as a subscription. iced keeps it alive for as long as a
+/// subscription with the same `id` is returned.
+fn job(id: usize, job: Job) -> Subscription<(usize, Report)> {
+    subscription::unfold(id, job.run(), move |mut reports| async move {
+        match reports.next().await {
+            Some(r) => (Some((id, r)), reports),
+            None => iced::futures::future::pending().await,
+        }
+    })
 }
 
-impl Download {
-    pub fn new(id: usize, url: String, dev: DiskDevice, client: Client) -> Self {
-        Download {
-            id,
-            url,
-            dev,
-            state: State::Idle,
-            client,
+impl App {
+    /// Whether `dev` is large enough to be something else than a throwaway
+    /// stick.
+    fn is_large(&self, dev: &DiskDevice) -> bool {
+        match &self.os_list {
+            Some(ls) => ls.is_large(dev.parent.size),
+            None => OperatingSystemList::empty().is_large(dev.parent.size),
         }
     }
+    /// Looks up the size of the selected image, to tell which devices are too
+    /// small for it.
+    fn look_up_size(&self) -> Command<Message> {
+        let region = self.states.selected_region;
+        let os = match self.os_list.as_ref().and_then(|ls| ls.get(region)) {
+            Some(os) => os.clone(),
+            None => return Command::none(),
+        };
+        let client = self.client.clone();
 
-    pub fn start(&mut self) {
-        match self.state {
-            State::Idle | State::Finished { .. } | State::Errored { .. } => {
-                self.state = State::Progressing { progress: 0.0 };
+        Command::perform(async move { os.image_size(&client).await }, move |size| {
+            Message::ImageSize(region, size)
+        })
+    }
+}
+
+/// Whether an image of `size` fits on `dev`. Any device fits an image of
+/// unknown size.
+fn fits(size: Option<u64>, dev: &DiskDevice) -> bool {
+    size.is_none_or(|size| size <= dev.parent.size)
+}
+
+/// Why each device of `disks` that holds the running system can't be chosen.
+/// One that can't be checked is not offered either.
+fn protections(
+    backend: &dyn Backend,
+    disks: &HashMap<String, DiskDevice>,
+) -> HashMap<String, String> {
+    disks
+        .iter()
+        .filter_map(|(id, dev)| match backend.protection(dev) {
+            Ok(reason) => reason.map(|reason| (id.clone(), reason)),
+            Err(e) => Some((id.clone(), format!("could not be checked: {e}"))),
+        })
+        .collect()
+}
+
+/// Drops the devices from `selected` that are no longer plugged in, or that
+/// hold the running system now.
+fn prune(
+    selected: &mut Vec<String>,
+    disks: &HashMap<String, DiskDevice>,
+    protected: &HashMap<String, String>,
+) {
+    selected.retain(|id| disks.contains_key(id) && !protected.contains_key(id));
+}
+
+/// The ids of `disks` ordered by device node.
+fn disk_ids(disks: &HashMap<String, DiskDevice>) -> Vec<String> {
+    let mut ids: Vec<String> = disks.keys().cloned().collect();
+    ids.sort_by_key(|id| disks[id].parent.device.clone());
+    ids
+}
+
+/// Yields whenever `backend` sees a device plugged in or removed. If it
+/// can't watch for that, the list stays as it was at start.
+fn devices(backend: Arc<dyn Backend>) -> Subscription<Message> {
+    subscription::unfold(
+        "devices",
+        (backend, None),
+        |(backend, watch): (Arc<dyn Backend>, Option<UnboundedReceiver<()>>)| async move {
+            let mut watch = match watch {
+                Some(watch) => watch,
+                None => match backend.watch_devices() {
+                    Ok(watch) => watch,
+                    Err(e) => {
+                        eprintln!("Not watching for devices: {e}");
+                        iced::futures::future::pending().await
+                    }
+                },
+            };
+
+            match watch.next().await {
+                Some(()) => (Some(Message::DevicesChanged), (backend, Some(watch))),
+                None => iced::futures::future::pending().await,
             }
-            _ => {}
+        },
+    )
+}
+
+/// What will be destroyed on each device, for the user to confirm. If
+/// something is mounted, whether to unmount it is asked as well.
+fn confirm_view(confirm: &Confirm) -> Element<'static, Message> {
+    let mut col = Column::new()
+        .width(Length::Fill)
+        .push(Text::new("Everything on these devices will be destroyed."));
+
+    let mounted = confirm
+        .devices
+        .iter()
+        .flat_map(|(_, partitions)| partitions)
+        .any(|p| !p.mount_points.is_empty());
+    if mounted {
+        col = col.push(Checkbox::new(
+            confirm.unmount,
+            "Unmount the mounted filesystems, devices with any left mounted are not written",
+            Message::ToggleUnmount,
+        ));
+    }
+
+    for (label, partitions) in &confirm.devices {
+        col = col.push(Text::new(label.clone()));
+
+        if partitions.is_empty() {
+            col = col.push(Text::new("    No partitions").size(14));
+        }
+        for partition in partitions {
+            col = col.push(Text::new(format!("    {partition}")).size(14));
         }
     }
 
-    pub fn progress(&mut self, new_progress: Progress) {
-        if let State::Progressing { progress } = &mut self.state {
-            match new_progress {
-                Progress::Started => *progress = 0.0,
-                Progress::Advanced(percentage) => *progress = percentage,
-                Progress::Finished => self.state = State::Finished,
-                Progress::Errored => self.state = State::Errored,
+    col.into()
+}
+
+/// A row per device being written, with its progress or how it ended.
+fn devices_view(labels: &[String], states: &[State]) -> Element<'static, Message> {
+    let mut col = Column::new().width(Length::Fill);
+
+    for (label, state) in labels.iter().zip(states) {
+        let status: Element<'static, Message> = match state {
+            State::Progressing { transfer } | State::Verifying { transfer } => {
+                progress_view(transfer)
             }
+            State::Idle => Space::with_width(Length::Fill).into(),
+            State::Finished => Text::new("Done").width(Length::Fill).into(),
+            State::Ejected => Text::new("Done, safe to remove").width(Length::Fill).into(),
+            State::Cancelled => Text::new("Cancelled").width(Length::Fill).into(),
+            _ => Text::new("Failed").width(Length::Fill).into(),
+        };
+
+        col = col.push(
+            Row::new()
+                .push(Text::new(label.clone()).width(Length::FillPortion(30)))
+                .push(status),
+        );
+    }
+
+    col.into()
+}
+
+/// A progress bar with the bytes done, rates and time left below it.
+fn progress_view(transfer: &Transfer) -> Element<'static, Message> {
+    Column::new()
+        .width(Length::Fill)
+        .push(ProgressBar::new(0.0..=100.0, transfer.percentage))
+        .push(Text::new(transfer.to_string()).size(14))
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+
+    use futures::StreamExt;
+    use reqwest::Client;
+    use tempfile::TempDir;
+
+    use super::{disk_ids, protections, prune, Running, State};
+    use crate::backend::{Backend, Mock};
+    use crate::target::Target;
+    use crate::testing::{image, stick};
+    use crate::writer::Job;
+    use crate::{Progress, Source, WriteError, WriteOptions};
+
+    #[test]
+    fn forgets_devices_that_were_unplugged_or_hold_the_system() {
+        let dir = TempDir::new().unwrap();
+        let mock = Mock::new();
+        let mut devs = vec![];
+        for name in ["sdb", "sdc", "sdd"] {
+            let (dev, backing) = stick(dir.path(), name, 4 << 20);
+            mock.plug(dev.clone(), backing);
+            devs.push(dev);
         }
+        let mut selected: Vec<String> = devs.iter().map(|d| d.drive.path.clone()).collect();
+
+        mock.unplug(&devs[1].drive.path);
+        mock.protect(&devs[2], "/dev/sdd2 is mounted at /");
+        let disks = mock.list_devices().unwrap();
+        prune(&mut selected, &disks, &protections(&mock, &disks));
+
+        assert_eq!(selected, [devs[0].drive.path.clone()]);
+        assert_eq!(
+            disk_ids(&disks),
+            [devs[0].drive.path.clone(), devs[2].drive.path.clone()]
+        );
     }
 
-    pub fn subscription(&self) -> Subscription<Message> {
-        match self.state {
-            State::Progressing { .. } => {
-                download::file(self.id, &self.url, self.dev.clone(), self.client.clone())
-                    .map(|p| Message::Download(DownloadMessage::DownloadProgressed(p)))
+    #[tokio::test]
+    async fn stops_writing_to_a_device_that_was_unplugged() {
+        let dir = TempDir::new().unwrap();
+        let (path, data) = image(dir.path());
+        let mock = Arc::new(Mock::new());
+        let (pulled, _) = stick(dir.path(), "sdb", 4 << 20);
+        mock.plug(pulled.clone(), dir.path().join("sdb.img"));
+        let (kept, kept_backing) = stick(dir.path(), "sdc", 4 << 20);
+        mock.plug(kept.clone(), &kept_backing);
+
+        let backend: Arc<dyn Backend> = mock.clone();
+        let devs = vec![pulled.clone(), kept];
+        let targets = devs
+            .iter()
+            .map(|dev| Target::Device(Box::new(dev.clone())))
+            .collect();
+        let options = WriteOptions {
+            verify: true,
+            eject: true,
+            ..Default::default()
+        };
+        let source = Source::File(path.display().to_string());
+        let job = Job::new(source, targets, Client::new(), options).with_backend(backend);
+        let labels = vec!["sdb".into(), "sdc".into()];
+        let mut running = Running::new(0, labels, devs, job);
+        running.start();
+
+        // The stick is pulled once the write got going.
+        let mut reports = running
+            .job
+            .clone()
+            .with_cancel(running.cancel.clone())
+            .run();
+        let mut pulled_out = false;
+        while let Some(report) = reports.next().await {
+            let advanced = matches!(report.progress, Progress::Advanced(_));
+            running.progress(report);
+
+            if advanced && !pulled_out {
+                mock.unplug(&pulled.drive.path);
+                running.unplugged(&mock.list_devices().unwrap());
+                pulled_out = true;
             }
-            _ => Subscription::none(),
         }
-    }
 
-    pub fn state(&self) -> &State {
-        &self.state
+        assert!(
+            matches!(
+                running.states(),
+                [State::Errored(WriteError::Removed), State::Ejected]
+            ),
+            "{:?}",
+            running.states()
+        );
+        assert!(!running.is_active());
+        assert!(fs::read(kept_backing).unwrap()[..data.len()] == data);
     }
 }
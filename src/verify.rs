@@ -0,0 +1,217 @@
+use std::{
+    cmp::min,
+    fmt,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::io::AsRawFd,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::transfer::{Meter, Transfer};
+use crate::{WriteError, WriteOptions};
+
+const CHUNK_SIZE: u64 = 1048576;
+
+/// Reads back what was written to a device and compares it with the digest
+/// computed while streaming the image.
+pub struct ReadBack {
+    file: File,
+    hasher: Sha256,
+    expected: Vec<u8>,
+    extents: Vec<(u64, u64)>,
+    extent: usize,
+    position: u64,
+    total: u64,
+    read: u64,
+    meter: Meter,
+}
+
+pub enum Step {
+    Advanced(Transfer),
+    Matched,
+    Mismatched,
+}
+
+impl ReadBack {
+    /// Prepares to read back the written `extents` of the reopened `file`,
+    /// which together should hash to `expected`.
+    pub fn new(file: File, expected: Vec<u8>, extents: Vec<(u64, u64)>) -> Self {
+        // Drop cached pages so the comparison reads from the stick itself.
+        unsafe {
+            libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+        }
+
+        Self {
+            file,
+            hasher: Sha256::new(),
+            expected,
+            total: extents.iter().map(|(start, end)| end - start).sum(),
+            extents,
+            extent: 0,
+            position: 0,
+            read: 0,
+            meter: Meter::new(),
+        }
+    }
+
+    pub fn transfer(&mut self) -> Transfer {
+        self.meter.update(self.read, self.total, self.read, 0)
+    }
+
+    pub fn step(&mut self) -> Result<Step, WriteError> {
+        self.next().map_err(|e| WriteError::ReadBack {
+            cause: e.to_string(),
+            offset: self.position,
+        })
+    }
+
+    fn next(&mut self) -> io::Result<Step> {
+        let (start, end) = match self.extents.get(self.extent) {
+            Some(extent) => *extent,
+            None => {
+                let digest = std::mem::take(&mut self.hasher).finalize();
+
+                return Ok(match digest.as_slice() == self.expected.as_slice() {
+                    true => Step::Matched,
+                    false => Step::Mismatched,
+                });
+            }
+        };
+
+        if self.position < start || self.position >= end {
+            self.position = self.file.seek(SeekFrom::Start(start))?;
+        }
+
+        let mut buffer = vec![0; min(CHUNK_SIZE, end - self.position) as usize];
+        self.file.read_exact(&mut buffer)?;
+        self.hasher.update(&buffer);
+
+        self.position += buffer.len() as u64;
+        self.read += buffer.len() as u64;
+        if self.position >= end {
+            self.extent += 1;
+        }
+
+        Ok(Step::Advanced(self.transfer()))
+    }
+}
+
+/// Writes the image to the device, hashing what goes out so it can be read
+/// back afterwards.
+pub struct Recorder {
+    file: File,
+    hasher: Option<Sha256>,
+    extents: Vec<(u64, u64)>,
+    position: u64,
+    written: u64,
+    dry_run: bool,
+}
+
+impl Recorder {
+    /// With `compare_only` set nothing is written, the read-back then compares
+    /// what is already on the device with the image.
+    pub fn new(mut file: File, options: &WriteOptions) -> Self {
+        let position = file.stream_position().unwrap_or(0);
+
+        Self {
+            file,
+            hasher: (options.verify || options.compare_only).then(Sha256::new),
+            extents: vec![],
+            position,
+            written: 0,
+            dry_run: options.compare_only,
+        }
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    /// Syncs the device and, if verification was requested, has `reopen` open
+    /// it again for the read-back.
+    pub fn finish(
+        self,
+        reopen: impl FnOnce() -> Result<File, WriteError>,
+    ) -> Result<Option<ReadBack>, WriteError> {
+        self.file.sync_all().map_err(|e| WriteError::Sync {
+            cause: e.to_string(),
+        })?;
+        drop(self.file);
+
+        match self.hasher {
+            None => Ok(None),
+            Some(hasher) => Ok(Some(ReadBack::new(
+                reopen()?,
+                hasher.finalize().to_vec(),
+                self.extents,
+            ))),
+        }
+    }
+
+    fn device_error(&self, e: io::Error) -> io::Error {
+        io::Error::new(
+            e.kind(),
+            DeviceError {
+                cause: e,
+                offset: self.position,
+            },
+        )
+    }
+}
+
+impl Write for Recorder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = match self.dry_run {
+            true => buf.len(),
+            false => self.file.write(buf).map_err(|e| self.device_error(e))?,
+        };
+
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..size]);
+        }
+
+        let end = self.position + size as u64;
+        match self.extents.last_mut() {
+            Some((_, last)) if *last == self.position => *last = end,
+            _ => self.extents.push((self.position, end)),
+        }
+
+        self.position = end;
+        self.written += size as u64;
+
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush().map_err(|e| self.device_error(e))
+    }
+}
+
+impl Seek for Recorder {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = self.file.seek(pos).map_err(|e| self.device_error(e))?;
+
+        Ok(self.position)
+    }
+}
+
+/// An error of the device itself, as opposed to the image, passed up through
+/// the write pipeline.
+#[derive(Debug)]
+pub(crate) struct DeviceError {
+    pub cause: io::Error,
+    pub offset: u64,
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.cause, self.offset)
+    }
+}
+
+impl std::error::Error for DeviceError {}
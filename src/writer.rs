@@ -0,0 +1,398 @@
+use std::future::Future;
+use std::mem;
+use std::panic;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+use futures::stream::{self, BoxStream, StreamExt};
+use reqwest::Client;
+use tokio::sync::Notify;
+use tokio::task;
+
+use crate::backend::{self, Backend};
+use crate::cache::Cache;
+use crate::checksum;
+use crate::metalink::Metalink;
+use crate::target::Target;
+use crate::{download, read, Progress, Report, Source, WriteError, WriteOptions};
+
+/// Writing one image to one or more devices, independent of any user
+/// interface. The source is read or downloaded once for all of them.
+#[derive(Debug, Clone)]
+pub struct Job {
+    source: Source,
+    targets: Vec<Target>,
+    /// `None` to pick UDisks or sysfs, whichever works, once it runs.
+    backend: Option<Arc<dyn Backend>>,
+    client: Client,
+    options: WriteOptions,
+    cancel: Cancel,
+    cache: Option<Cache>,
+}
+
+/// Stops a running job, or the writes to devices that were unplugged. A job
+/// is stopped right away, even while it waits for a server or a device, and
+/// unplugged devices before the next chunk.
+#[derive(Debug, Clone, Default)]
+pub struct Cancel {
+    cancelled: Arc<AtomicBool>,
+    notify: Arc<Notify>,
+    removed: Arc<Mutex<Vec<usize>>>,
+}
+
+impl Cancel {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Runs `future` unless the job is cancelled first, which drops it.
+    pub(crate) async fn until<T>(&self, future: impl Future<Output = T>) -> Option<T> {
+        // Set up before looking at the flag, so a cancel in between wakes it.
+        let notified = self.notify.notified();
+        if self.is_cancelled() {
+            return None;
+        }
+
+        tokio::select! {
+            biased;
+            _ = notified => None,
+            output = future => Some(output),
+        }
+    }
+
+    /// Stops writing to the `device`th device of the job, which is gone. The
+    /// others carry on.
+    pub fn remove(&self, device: usize) {
+        let mut removed = self.removed.lock().unwrap_or_else(|e| e.into_inner());
+        if !removed.contains(&device) {
+            removed.push(device);
+        }
+    }
+
+    fn removed(&self) -> Vec<usize> {
+        self.removed
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+enum Stage {
+    Lookup(Lookup),
+    Download(Box<download::State>),
+    Read(read::State),
+}
+
+/// Works out the mirrors of a download and looks for a cached copy before
+/// starting it.
+struct Lookup {
+    source: Source,
+    targets: Vec<Target>,
+    backend: Arc<dyn Backend>,
+    client: Client,
+    options: WriteOptions,
+    cache: Option<Cache>,
+}
+
+impl Lookup {
+    /// The cache is keyed by the checksum, so it is resolved here and then
+    /// handed on as a fixed digest instead of being fetched again.
+    async fn resolve(self) -> Result<Stage, WriteError> {
+        let Lookup {
+            source,
+            targets,
+            backend,
+            client,
+            mut options,
+            cache,
+        } = self;
+
+        // A metalink names the image and often its size and checksum too.
+        // `key` stands for the image in checksum files and the cache.
+        let (key, urls, size, listed) = match source {
+            Source::Url(url) => (url.clone(), vec![url], None, None),
+            Source::Mirrors(urls) => (urls.first().cloned().unwrap_or_default(), urls, None, None),
+            Source::Metalink(url) => {
+                let metalink =
+                    Metalink::load(&url, &client)
+                        .await
+                        .map_err(|e| WriteError::Request {
+                            cause: format!("{url}: {e}"),
+                        })?;
+
+                let key = format!("{url}/{}", metalink.name);
+                (key, metalink.urls, metalink.size, metalink.checksum)
+            }
+            Source::File(path) => {
+                return Ok(Stage::Read(read::State::Ready {
+                    path,
+                    targets,
+                    backend,
+                    client,
+                    options,
+                }))
+            }
+        };
+
+        let checksum = match options.checksum.is_set() {
+            true => options
+                .checksum
+                .resolve(&client, checksum::file_name(&key))
+                .await
+                .map_err(|e| WriteError::Checksum {
+                    cause: e.to_string(),
+                })?,
+            false => listed.clone(),
+        };
+
+        if let (Some(checksum), Some(listed)) = (&checksum, &listed) {
+            if mem::discriminant(checksum) == mem::discriminant(listed) && checksum != listed {
+                return Err(WriteError::Checksum {
+                    cause: "the metalink lists a different checksum".into(),
+                });
+            }
+        }
+
+        if let Some(checksum) = &checksum {
+            options.checksum = checksum.expected();
+        }
+
+        if let Some(path) = cache.as_ref().and_then(|c| c.get(&key, checksum.as_ref())) {
+            return Ok(Stage::Read(read::State::Ready {
+                path: path.to_string_lossy().into_owned(),
+                targets,
+                backend,
+                client,
+                options,
+            }));
+        }
+
+        Ok(Stage::Download(Box::new(download::State::Ready {
+            cache: cache.and_then(|c| c.entry(&key, checksum.as_ref()).ok()),
+            urls,
+            size,
+            targets,
+            backend,
+            client,
+            options,
+        })))
+    }
+}
+
+impl Job {
+    pub fn new(
+        source: Source,
+        targets: Vec<Target>,
+        client: Client,
+        options: WriteOptions,
+    ) -> Self {
+        Self {
+            source,
+            targets,
+            backend: None,
+            client,
+            options,
+            cancel: Cancel::default(),
+            cache: None,
+        }
+    }
+
+    /// Serves downloads from `cache` and adds them to it.
+    pub fn with_cache(mut self, cache: Cache) -> Self {
+        self.cache = Some(cache).filter(Cache::enabled);
+        self
+    }
+
+    /// Handles the devices through `backend` instead of the one detected.
+    pub fn with_backend(mut self, backend: Arc<dyn Backend>) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Makes the job stop when `cancel` is triggered, instead of the handle it
+    /// was created with.
+    pub fn with_cancel(mut self, cancel: Cancel) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    pub fn cancel_handle(&self) -> Cancel {
+        self.cancel.clone()
+    }
+
+    /// Starts the job. The stream yields the progress on each device and ends
+    /// once each got `Finished`, `Ejected`, `Mismatch`, `ChecksumMismatch`,
+    /// `Cancelled` or `Errored`. Nothing happens until it is polled.
+    pub fn run(self) -> BoxStream<'static, Report> {
+        let targets = self.targets;
+        let backend = self.backend.unwrap_or_else(backend::detect);
+
+        let stage = match self.source {
+            Source::File(path) => Stage::Read(read::State::Ready {
+                path,
+                targets,
+                backend,
+                client: self.client,
+                options: self.options,
+            }),
+            source => Stage::Lookup(Lookup {
+                source,
+                targets,
+                backend,
+                client: self.client,
+                options: self.options,
+                cache: self.cache,
+            }),
+        };
+
+        let cancel = self.cancel;
+
+        stream::unfold(Some(stage), move |stage| {
+            let cancel = cancel.clone();
+
+            async move {
+                let mut stage = stage?;
+
+                if cancel.is_cancelled() {
+                    let reports = match stage {
+                        Stage::Lookup(_) => vec![Report::all(Progress::Cancelled)],
+                        Stage::Download(state) => download::cancel(*state).await,
+                        Stage::Read(state) => read::cancel(state).await,
+                    };
+
+                    return Some((reports, None));
+                }
+
+                for device in cancel.removed() {
+                    match &mut stage {
+                        Stage::Lookup(_) => {}
+                        Stage::Download(state) => download::remove(state, device),
+                        Stage::Read(state) => read::remove(state, device),
+                    }
+                }
+
+                // Nothing is written before the devices are opened at the
+                // end of the first step, so up to then a cancelled job simply
+                // drops it, e.g. while it fetches the checksum.
+                let starting = matches!(stage, Stage::Lookup(_) | Stage::Read(read::State::Ready { .. }))
+                    || matches!(&stage, Stage::Download(state) if matches!(**state, download::State::Ready { .. }));
+
+                let step = step(stage, &cancel);
+                let (reports, next) = match starting {
+                    true => match cancel.until(step).await {
+                        Some(step) => step,
+                        None => (vec![Report::all(Progress::Cancelled)], None),
+                    },
+                    false => step.await,
+                };
+
+                Some((reports, next))
+            }
+        })
+        .flat_map(stream::iter)
+        .boxed()
+    }
+}
+
+/// Advances `stage` by one step, which the later stages end early if the job
+/// is cancelled.
+async fn step(mut stage: Stage, cancel: &Cancel) -> (Vec<Report>, Option<Stage>) {
+    if let Stage::Lookup(lookup) = stage {
+        stage = match lookup.resolve().await {
+            Ok(stage) => stage,
+            Err(e) => return (vec![Report::all(Progress::Errored(e))], None),
+        };
+    }
+
+    match stage {
+        Stage::Lookup(_) => unreachable!(),
+        Stage::Download(state) => {
+            let (reports, next) = download::download(*state, cancel).await;
+            (reports, next.map(|s| Stage::Download(Box::new(s))))
+        }
+        Stage::Read(state) => {
+            let (reports, next) = read::read(state, cancel).await;
+            (reports, next.map(Stage::Read))
+        }
+    }
+}
+
+/// Runs `work`, which blocks on files or processes or keeps the CPU busy, on
+/// a thread of the blocking pool. That way whatever else the runtime drives,
+/// e.g. in a daemon, goes on meanwhile.
+pub(crate) async fn blocking<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> T {
+    match task::spawn_blocking(work).await {
+        Ok(output) => output,
+        Err(e) => panic::resume_unwind(e.into_panic()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use reqwest::Client;
+    use tempfile::TempDir;
+    use tokio::time;
+
+    use super::Job;
+    use crate::backend::{Backend, Mock};
+    use crate::target::Target;
+    use crate::testing::{image, stick, Served, Server};
+    use crate::{Progress, Source, WriteOptions};
+
+    #[tokio::test]
+    async fn cancels_while_the_server_stalls() {
+        let dir = TempDir::new().unwrap();
+        let (_, data) = image(dir.path());
+        let server = Server::start();
+        server.serve(
+            "/image.img",
+            Served {
+                body: data,
+                cut: Some(1 << 20),
+                stall: true,
+                ..Default::default()
+            },
+        );
+
+        let mock = Arc::new(Mock::new());
+        let (dev, backing) = stick(dir.path(), "sdb", 4 << 20);
+        mock.plug(dev.clone(), backing);
+        let backend: Arc<dyn Backend> = mock;
+
+        let source = Source::Url(server.url("/image.img"));
+        let targets = vec![Target::Device(Box::new(dev))];
+        let job =
+            Job::new(source, targets, Client::new(), WriteOptions::default()).with_backend(backend);
+        let cancel = job.cancel_handle();
+        let mut reports = job.run();
+
+        // Once the server stopped sending, the next chunk never comes.
+        while let Some(report) = reports.next().await {
+            if let Progress::Advanced(transfer) = report.progress {
+                if transfer.done >= 1 << 20 {
+                    break;
+                }
+            }
+        }
+        cancel.cancel();
+
+        let rest = time::timeout(Duration::from_secs(5), reports.collect::<Vec<_>>())
+            .await
+            .expect("the job didn't stop");
+        assert!(matches!(
+            rest.last().map(|report| &report.progress),
+            Some(Progress::Cancelled)
+        ));
+    }
+}